@@ -40,4 +40,61 @@ fn main() {
     for (name, val) in values.as_table().unwrap_or(&map_dummy) {
         println!("cargo:rustc-cfg={}={}", name, val.to_string());
     }
+
+    // Declaring any rerun-if line replaces the default of rerunning on every
+    // change in the package, so all inputs are listed.
+    for file in ["build.rs", "kernel.toml", ".cargo/config.toml"] {
+        println!("cargo:rerun-if-changed={}", file);
+    }
+    println!("cargo:rerun-if-env-changed=BOARD");
+    println!("cargo:rerun-if-env-changed=ARCH");
+
+    // Log settings are read as strings by src/logger.rs. LOG and LOG_FILE
+    // override kernel.toml when they are not empty.
+    for (name, key, default) in [("LOG", "log", "info"), ("LOG_FILE", "log_file", "")] {
+        println!("cargo:rerun-if-env-changed={}", name);
+        let value = match env::var(name) {
+            Ok(value) if !value.is_empty() => value,
+            _ => values
+                .get(key)
+                .and_then(|val| val.as_str())
+                .unwrap_or(default)
+                .to_string(),
+        };
+        println!("cargo:rustc-env=KERNEL_{}={}", name, value);
+    }
+
+    // The boot image is built into the kernel from OUT_DIR/initrd, which is
+    // left empty when there is none. INITRD overrides kernel.toml's `initrd`.
+    println!("cargo:rerun-if-env-changed=INITRD");
+    let initrd = match env::var("INITRD") {
+        Ok(value) if !value.is_empty() => value,
+        _ => values
+            .get("initrd")
+            .and_then(|val| val.as_str())
+            .unwrap_or("")
+            .to_string(),
+    };
+    let out = std::path::Path::new(&env::var("OUT_DIR").unwrap()).join("initrd");
+    if initrd.is_empty() {
+        std::fs::write(&out, []).unwrap();
+    } else {
+        println!("cargo:rerun-if-changed={}", initrd);
+        std::fs::copy(&initrd, &out).unwrap_or_else(|e| panic!("initrd {}: {}", initrd, e));
+    }
+
+    // Space for the symbol table of src/backtrace.rs. tools/ksyms writes the
+    // size the last image needed to target/ksyms-size; until there is one,
+    // 256 KiB are reserved.
+    println!("cargo:rerun-if-changed=target/ksyms-size");
+    let ksyms_size = std::fs::read_to_string("target/ksyms-size")
+        .ok()
+        .and_then(|size| size.trim().parse::<usize>().ok())
+        .unwrap_or(0x40000);
+    let out = std::path::Path::new(&env::var("OUT_DIR").unwrap()).join("ksyms_size.rs");
+    std::fs::write(
+        &out,
+        format!("const KSYMS_SIZE: usize = {:#x};\n", ksyms_size),
+    )
+    .unwrap();
 }
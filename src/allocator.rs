@@ -1,6 +1,9 @@
 #![allow(unused_imports)]
 
+pub mod debug;
 pub mod dlmalloc;
+pub mod frame;
+pub mod slab;
 pub mod watermark;
 
 use core::alloc::Layout;
@@ -12,6 +15,12 @@ use watermark::WaterMarkAllocator;
 #[cfg(allocator = "Dlmalloc")]
 use self::dlmalloc::GlobalDlmalloc;
 
+#[cfg(allocator = "Slab")]
+use slab::SlabAllocator;
+
+#[cfg(allocator = "Debug")]
+use debug::DebugAllocator;
+
 #[global_allocator]
 #[cfg(allocator = "WaterMark")]
 static mut ALLOCATOR: WaterMarkAllocator = WaterMarkAllocator::empty();
@@ -20,6 +29,14 @@ static mut ALLOCATOR: WaterMarkAllocator = WaterMarkAllocator::empty();
 #[cfg(allocator = "Dlmalloc")]
 static mut ALLOCATOR: GlobalDlmalloc = GlobalDlmalloc;
 
+#[global_allocator]
+#[cfg(allocator = "Slab")]
+static mut ALLOCATOR: SlabAllocator = SlabAllocator::new();
+
+#[global_allocator]
+#[cfg(allocator = "Debug")]
+static mut ALLOCATOR: DebugAllocator = DebugAllocator::new();
+
 #[cfg(allocator = "WaterMark")]
 pub fn init_allocator() {
     #[cfg(target_arch = "aarch64")]
@@ -34,11 +51,59 @@ pub fn init_allocator() {
     unsafe {
         ALLOCATOR = WaterMarkAllocator::new(heap_start, heap_end);
     }
+    init_frame_allocator();
 }
 
-#[cfg(allocator = "Dlmalloc")]
+#[cfg(any(allocator = "Dlmalloc", allocator = "Debug"))]
 pub fn init_allocator() {
+    #[cfg(target_arch = "aarch64")]
+    use crate::arch::aarch64::address;
+    #[cfg(target_arch = "riscv64")]
+    use crate::arch::riscv64::address;
+
+    #[cfg(allocator = "Dlmalloc")]
     info!("Initialize Dlmalloc allocator");
+    #[cfg(allocator = "Debug")]
+    info!("Initialize Debug allocator");
+
+    // x86_64 has no heap yet
+    #[cfg(not(target_arch = "x86_64"))]
+    dlmalloc::init_heap(address::_heap_start as usize, address::_heap_end as usize);
+    init_frame_allocator();
+}
+
+// Everything comes from the frame allocator, including the heap.
+#[cfg(allocator = "Slab")]
+pub fn init_allocator() {
+    #[cfg(target_arch = "aarch64")]
+    use crate::arch::aarch64::address;
+    #[cfg(target_arch = "riscv64")]
+    use crate::arch::riscv64::address;
+
+    info!("Initialize Slab allocator");
+
+    #[cfg(not(target_arch = "x86_64"))]
+    unsafe {
+        frame::FRAME_ALLOCATOR.init(address::_heap_start as usize, address::_ram_end as usize);
+    }
+}
+
+#[cfg(allocator = "Slab")]
+pub fn dump_stats() {
+    unsafe { ALLOCATOR.dump_stats() };
+}
+
+#[cfg(not(allocator = "Slab"))]
+fn init_frame_allocator() {
+    #[cfg(target_arch = "aarch64")]
+    use crate::arch::aarch64::address;
+    #[cfg(target_arch = "riscv64")]
+    use crate::arch::riscv64::address;
+
+    #[cfg(not(target_arch = "x86_64"))]
+    unsafe {
+        frame::FRAME_ALLOCATOR.init(address::_heap_end as usize, address::_ram_end as usize);
+    }
 }
 
 #[alloc_error_handler]
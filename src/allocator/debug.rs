@@ -0,0 +1,281 @@
+use crate::allocator::dlmalloc::GlobalDlmalloc;
+use crate::arch;
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::{align_of, size_of};
+use core::ptr;
+
+const REDZONE: usize = 32;
+pub const REDZONE_BYTE: u8 = 0xfd;
+// fresh blocks, to make reads of uninitialized memory stand out
+const ALLOC_BYTE: u8 = 0xcd;
+// freed blocks
+pub const POISON_BYTE: u8 = 0xdd;
+
+const MAGIC_LIVE: usize = 0x11fe_a110_c0de_0001;
+const MAGIC_FREED: usize = 0xdead_f4ee_c0de_0002;
+
+// return addresses kept per allocation
+const CALLERS: usize = 4;
+
+// Every block looks like this. `front` is a multiple of the requested
+// alignment.
+//
+// +--------+---------+------------------+---------+
+// | Header | redzone |   user memory    | redzone |
+// +--------+---------+------------------+---------+
+// ^ base             ^ base + front
+#[repr(C)]
+struct Header {
+    next: *mut Header,
+    prev: *mut Header,
+    size: usize,
+    align: usize,
+    // allocation number, see mark()
+    serial: usize,
+    callers: [usize; CALLERS],
+    magic: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DebugStats {
+    pub live: usize,
+    pub live_bytes: usize,
+    pub serial: usize,
+}
+
+// Live allocations, linked through their headers.
+static mut LIVE: *mut Header = ptr::null_mut();
+static mut STATS: DebugStats = DebugStats {
+    live: 0,
+    live_bytes: 0,
+    serial: 0,
+};
+
+fn front(align: usize) -> usize {
+    let align = align.max(align_of::<Header>());
+    (size_of::<Header>() + REDZONE + align - 1) & !(align - 1)
+}
+
+fn inner_layout(layout: &Layout) -> Layout {
+    Layout::from_size_align(
+        front(layout.align()) + layout.size() + REDZONE,
+        layout.align().max(align_of::<Header>()),
+    )
+    .unwrap()
+}
+
+// Return addresses of the frames above the allocator.
+#[inline(always)]
+fn callers() -> [usize; CALLERS] {
+    let mut callers = [0; CALLERS];
+    let mut fp = arch::frame_pointer();
+    // skip the allocator entry points
+    let mut skip = 2;
+    let mut i = 0;
+    while i < CALLERS {
+        match unsafe { arch::unwind_frame(fp) } {
+            Some((ra, prev)) if prev > fp => {
+                if skip > 0 {
+                    skip -= 1;
+                } else {
+                    callers[i] = ra;
+                    i += 1;
+                }
+                fp = prev;
+            }
+            _ => break,
+        }
+    }
+    callers
+}
+
+unsafe fn redzones(header: *mut Header) -> [(*mut u8, usize); 2] {
+    let base = header as *mut u8;
+    let user = base.add(front((*header).align));
+    let front_zone = base.add(size_of::<Header>());
+    [
+        (front_zone, user as usize - front_zone as usize),
+        (user.add((*header).size), REDZONE),
+    ]
+}
+
+// The first overwritten redzone byte, as an offset from `user`.
+unsafe fn overwritten(header: *mut Header, user: *mut u8) -> Option<isize> {
+    for (zone, len) in redzones(header) {
+        let bytes = core::slice::from_raw_parts(zone, len);
+        if let Some(i) = bytes.iter().position(|b| *b != REDZONE_BYTE) {
+            return Some(zone.add(i) as isize - user as isize);
+        }
+    }
+    None
+}
+
+unsafe fn check_redzones(header: *mut Header, user: *mut u8) {
+    if let Some(offset) = overwritten(header, user) {
+        dump_block(header);
+        panic!(
+            "heap corruption: redzone of {:p} overwritten at offset {}",
+            user, offset
+        );
+    }
+}
+
+unsafe fn dump_block(header: *mut Header) {
+    let user = (header as *mut u8).add(front((*header).align));
+    println!(
+        "  {:p}: {} bytes (#{}), allocated from {:#x?}",
+        user,
+        (*header).size,
+        (*header).serial,
+        (*header).callers
+    );
+}
+
+// Wraps dlmalloc with redzones, poisoning and a list of live allocations.
+pub struct DebugAllocator {
+    inner: GlobalDlmalloc,
+}
+
+impl DebugAllocator {
+    pub const fn new() -> Self {
+        Self {
+            inner: GlobalDlmalloc,
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for DebugAllocator {
+    #[inline(never)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let base = self.inner.alloc(inner_layout(&layout));
+        if base.is_null() {
+            return base;
+        }
+        let header = base as *mut Header;
+        STATS.serial += 1;
+        header.write(Header {
+            next: LIVE,
+            prev: ptr::null_mut(),
+            size: layout.size(),
+            align: layout.align(),
+            serial: STATS.serial,
+            callers: callers(),
+            magic: MAGIC_LIVE,
+        });
+        if !LIVE.is_null() {
+            (*LIVE).prev = header;
+        }
+        LIVE = header;
+        STATS.live += 1;
+        STATS.live_bytes += layout.size();
+
+        for (zone, len) in redzones(header) {
+            ptr::write_bytes(zone, REDZONE_BYTE, len);
+        }
+        let user = base.add(front(layout.align()));
+        ptr::write_bytes(user, ALLOC_BYTE, layout.size());
+        user
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = ptr.sub(front(layout.align())) as *mut Header;
+        match (*header).magic {
+            MAGIC_LIVE => {}
+            MAGIC_FREED => panic!("double free of {:p}", ptr),
+            _ => panic!("free of {:p}, which is not a heap block", ptr),
+        }
+        if (*header).size != layout.size() || (*header).align != layout.align() {
+            dump_block(header);
+            panic!(
+                "free of {:p} with {:?}, allocated with size {} align {}",
+                ptr,
+                layout,
+                (*header).size,
+                (*header).align
+            );
+        }
+        check_redzones(header, ptr);
+
+        let next = (*header).next;
+        let prev = (*header).prev;
+        if prev.is_null() {
+            LIVE = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        STATS.live -= 1;
+        STATS.live_bytes -= layout.size();
+
+        (*header).magic = MAGIC_FREED;
+        ptr::write_bytes(ptr, POISON_BYTE, layout.size());
+        self.inner.dealloc(header as *mut u8, inner_layout(&layout));
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() {
+            ptr::write_bytes(ptr, 0, layout.size());
+        }
+        ptr
+    }
+}
+
+pub fn stats() -> DebugStats {
+    unsafe { STATS }
+}
+
+// Allocations made after this call count as leaks for dump_leaks.
+pub fn mark() -> usize {
+    unsafe { STATS.serial }
+}
+
+// The first live allocation with an overwritten redzone.
+unsafe fn corrupted_block() -> Option<(*mut Header, *mut u8, isize)> {
+    let mut header = LIVE;
+    while !header.is_null() {
+        let user = (header as *mut u8).add(front((*header).align));
+        if let Some(offset) = overwritten(header, user) {
+            return Some((header, user, offset));
+        }
+        header = (*header).next;
+    }
+    None
+}
+
+// Returns an allocation with an overwritten redzone, and the offset of the
+// damage from its start.
+pub fn find_corruption() -> Option<(*mut u8, isize)> {
+    unsafe { corrupted_block().map(|(_, user, offset)| (user, offset)) }
+}
+
+// Checks the redzones of every live allocation.
+pub fn check_heap() {
+    unsafe {
+        if let Some((header, user, _)) = corrupted_block() {
+            check_redzones(header, user);
+        }
+    }
+}
+
+// Prints the allocations made after `mark` that are still live and returns
+// how many there are.
+pub fn dump_leaks(mark: usize) -> usize {
+    let mut leaks = 0;
+    unsafe {
+        let mut header = LIVE;
+        while !header.is_null() {
+            if (*header).serial > mark {
+                if leaks == 0 {
+                    println!("live allocations since #{}:", mark);
+                }
+                dump_block(header);
+                leaks += 1;
+            }
+            header = (*header).next;
+        }
+    }
+    leaks
+}
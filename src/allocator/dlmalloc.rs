@@ -1,44 +1,108 @@
+use crate::arch::PAGE_SIZE;
 use core::alloc::{GlobalAlloc, Layout};
-use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
 use core::ptr;
 use dlmalloc::Allocator;
 use dlmalloc::Dlmalloc;
 
-pub struct System {
-    pos: UnsafeCell<usize>,
+// Bounds of the memory dlmalloc grows into. Until init_heap is called the
+// heap is empty and every request fails.
+struct Heap {
+    start: usize,
+    pos: usize,
+    end: usize,
 }
 
+static mut HEAP: Heap = Heap {
+    start: 0,
+    pos: 0,
+    end: 0,
+};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    // bytes handed out and not yet freed
+    pub current: usize,
+    pub peak: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub failures: usize,
+    // bytes dlmalloc has taken from the heap
+    pub heap_used: usize,
+    pub heap_size: usize,
+}
+
+static mut STATS: HeapStats = HeapStats {
+    current: 0,
+    peak: 0,
+    allocations: 0,
+    frees: 0,
+    failures: 0,
+    heap_used: 0,
+    heap_size: 0,
+};
+
+pub fn init_heap(start: usize, end: usize) {
+    let start = (start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    assert!(start <= end);
+    unsafe {
+        HEAP = Heap {
+            start,
+            pos: start,
+            end,
+        };
+        STATS.heap_size = end - start;
+    }
+}
+
+pub fn heap_stats() -> HeapStats {
+    unsafe {
+        let mut stats = STATS;
+        stats.heap_used = HEAP.pos - HEAP.start;
+        stats
+    }
+}
+
+fn record_alloc(ptr: *mut u8, size: usize) {
+    unsafe {
+        if ptr.is_null() {
+            STATS.failures += 1;
+        } else {
+            STATS.allocations += 1;
+            STATS.current += size;
+            STATS.peak = STATS.peak.max(STATS.current);
+        }
+    }
+}
+
+fn record_free(size: usize) {
+    unsafe {
+        STATS.frees += 1;
+        STATS.current -= size;
+    }
+}
+
+pub struct System;
+
 impl System {
     pub const fn new() -> Self {
-        Self {
-            pos: UnsafeCell::new(0),
-        }
+        Self
     }
 }
 
 unsafe impl Send for System {}
 unsafe impl Sync for System {}
 
+// Memory comes from the top of the heap, so only the most recent segment can
+// shrink or go back.
 unsafe impl Allocator for System {
     fn alloc(&self, size: usize) -> (*mut u8, usize, u32) {
-        #[cfg(target_arch = "aarch64")]
-        use crate::arch::aarch64::address::_heap_start;
-        #[cfg(target_arch = "riscv64")]
-        use crate::arch::riscv64::address::_heap_start;
-        #[cfg(target_arch = "x86_64")]
-        let _heap_start = 0;
-        let prev = unsafe {
-            if *self.pos.get() == 0 {
-                *self.pos.get() = _heap_start as usize;
-            }
-            let prev = *self.pos.get();
-            *self.pos.get() += size;
-            prev
-        };
-        if prev == usize::max_value() {
+        let heap = unsafe { &mut HEAP };
+        if heap.end - heap.pos < size {
             return (ptr::null_mut(), 0, 0);
         }
+        let prev = heap.pos;
+        heap.pos += size;
         (prev as *mut u8, size, 0)
     }
 
@@ -46,24 +110,30 @@ unsafe impl Allocator for System {
         ptr::null_mut()
     }
 
-    fn free_part(&self, _ptr: *mut u8, _oldsize: usize, _newsize: usize) -> bool {
-        false
+    fn free_part(&self, ptr: *mut u8, oldsize: usize, newsize: usize) -> bool {
+        let heap = unsafe { &mut HEAP };
+        if ptr as usize + oldsize != heap.pos {
+            return false;
+        }
+        heap.pos = ptr as usize + newsize;
+        true
     }
 
-    fn free(&self, _ptr: *mut u8, _size: usize) -> bool {
-        false
+    fn free(&self, ptr: *mut u8, size: usize) -> bool {
+        self.free_part(ptr, size, 0)
     }
 
     fn can_release_part(&self, _flags: u32) -> bool {
-        false
+        true
     }
 
     fn allocates_zeros(&self) -> bool {
-        true
+        // released memory may come back dirty
+        false
     }
 
     fn page_size(&self) -> usize {
-        64 * 1024
+        PAGE_SIZE
     }
 }
 
@@ -76,22 +146,35 @@ pub struct GlobalDlmalloc;
 unsafe impl GlobalAlloc for GlobalDlmalloc {
     #[inline]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        <Dlmalloc<System>>::malloc(&mut get(), layout.size(), layout.align())
+        let ptr = <Dlmalloc<System>>::malloc(&mut get(), layout.size(), layout.align());
+        record_alloc(ptr, layout.size());
+        ptr
     }
 
     #[inline]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        <Dlmalloc<System>>::free(&mut get(), ptr, layout.size(), layout.align())
+        <Dlmalloc<System>>::free(&mut get(), ptr, layout.size(), layout.align());
+        record_free(layout.size());
     }
 
     #[inline]
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-        <Dlmalloc<System>>::calloc(&mut get(), layout.size(), layout.align())
+        let ptr = <Dlmalloc<System>>::calloc(&mut get(), layout.size(), layout.align());
+        record_alloc(ptr, layout.size());
+        ptr
     }
 
     #[inline]
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        <Dlmalloc<System>>::realloc(&mut get(), ptr, layout.size(), layout.align(), new_size)
+        let new_ptr =
+            <Dlmalloc<System>>::realloc(&mut get(), ptr, layout.size(), layout.align(), new_size);
+        if new_ptr.is_null() {
+            STATS.failures += 1;
+        } else {
+            STATS.current = STATS.current - layout.size() + new_size;
+            STATS.peak = STATS.peak.max(STATS.current);
+        }
+        new_ptr
     }
 }
 
@@ -0,0 +1,286 @@
+use crate::arch::PAGE_SIZE;
+use core::ptr;
+use log::info;
+
+// Blocks are 2^0 to 2^(MAX_ORDER - 1) frames large.
+pub const MAX_ORDER: usize = 11;
+
+// Set in the metadata byte of the first frame of each free block.
+const FREE: u8 = 0x80;
+// Set in the metadata byte of the first frame of each allocated block, so
+// that frees of other frames are caught.
+const ALLOCATED: u8 = 0x40;
+
+pub static mut FRAME_ALLOCATOR: FrameAllocator = FrameAllocator::empty();
+
+#[derive(Clone, Copy, Debug)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    pub free_blocks: [usize; MAX_ORDER],
+    pub allocations: usize,
+    pub frees: usize,
+    pub failures: usize,
+}
+
+// Free blocks are linked through their first bytes.
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+// Buddy allocator over the physical memory that is not used by the kernel
+// image or the heap. Addresses are kernel addresses, so callers that need the
+// physical address go through arch::virt_to_phys. Blocks are aligned to their
+// size.
+pub struct FrameAllocator {
+    // first frame
+    base: usize,
+    frames: usize,
+    // one byte per frame: FREE | order for the head of a free block,
+    // ALLOCATED | order for the head of an allocated block, 0 otherwise
+    meta: *mut u8,
+    free_lists: [*mut FreeBlock; MAX_ORDER],
+    stats: FrameStats,
+}
+
+unsafe impl Sync for FrameAllocator {}
+unsafe impl Send for FrameAllocator {}
+
+impl FrameAllocator {
+    pub const fn empty() -> Self {
+        Self {
+            base: 0,
+            frames: 0,
+            meta: ptr::null_mut(),
+            free_lists: [ptr::null_mut(); MAX_ORDER],
+            stats: FrameStats {
+                total: 0,
+                free: 0,
+                free_blocks: [0; MAX_ORDER],
+                allocations: 0,
+                frees: 0,
+                failures: 0,
+            },
+        }
+    }
+
+    // Manages [start, end). The metadata is kept at the beginning of the range.
+    pub fn init(&mut self, start: usize, end: usize) {
+        let start = (start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let end = end & !(PAGE_SIZE - 1);
+        assert!(start < end);
+        let pages = (end - start) / PAGE_SIZE;
+        let meta_pages = (pages + PAGE_SIZE - 1) / PAGE_SIZE;
+
+        self.meta = start as *mut u8;
+        self.base = start + meta_pages * PAGE_SIZE;
+        self.frames = pages - meta_pages;
+        unsafe {
+            ptr::write_bytes(self.meta, 0, self.frames);
+        }
+
+        let mut index = 0;
+        while index < self.frames {
+            let mut order = MAX_ORDER - 1;
+            while self.frame_number(index) % (1 << order) != 0 || index + (1 << order) > self.frames
+            {
+                order -= 1;
+            }
+            self.push(index, order);
+            index += 1 << order;
+        }
+        self.stats.total = self.frames;
+        self.stats.free = self.frames;
+
+        info!(
+            "Frames: {} ({:#x} - {:#x})",
+            self.frames,
+            self.base,
+            self.base + self.frames * PAGE_SIZE
+        );
+    }
+
+    fn frame_number(&self, index: usize) -> usize {
+        self.base / PAGE_SIZE + index
+    }
+
+    fn buddy(&self, index: usize, order: usize) -> Option<usize> {
+        let buddy = (self.frame_number(index) ^ (1 << order)).checked_sub(self.base / PAGE_SIZE)?;
+        if buddy + (1 << order) > self.frames {
+            None
+        } else {
+            Some(buddy)
+        }
+    }
+
+    fn address(&self, index: usize) -> usize {
+        self.base + index * PAGE_SIZE
+    }
+
+    fn index(&self, addr: usize) -> usize {
+        assert!(addr >= self.base && (addr - self.base) % PAGE_SIZE == 0);
+        let index = (addr - self.base) / PAGE_SIZE;
+        assert!(index < self.frames);
+        index
+    }
+
+    fn meta(&self, index: usize) -> u8 {
+        unsafe { *self.meta.add(index) }
+    }
+
+    fn set_meta(&mut self, index: usize, meta: u8) {
+        unsafe {
+            *self.meta.add(index) = meta;
+        }
+    }
+
+    fn push(&mut self, index: usize, order: usize) {
+        let block = self.address(index) as *mut FreeBlock;
+        let head = self.free_lists[order];
+        unsafe {
+            (*block).next = head;
+            (*block).prev = ptr::null_mut();
+            if !head.is_null() {
+                (*head).prev = block;
+            }
+        }
+        self.free_lists[order] = block;
+        self.set_meta(index, FREE | order as u8);
+        self.stats.free_blocks[order] += 1;
+    }
+
+    fn remove(&mut self, index: usize, order: usize) {
+        let block = self.address(index) as *mut FreeBlock;
+        unsafe {
+            let next = (*block).next;
+            let prev = (*block).prev;
+            if prev.is_null() {
+                self.free_lists[order] = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+        self.set_meta(index, 0);
+        self.stats.free_blocks[order] -= 1;
+    }
+
+    pub fn alloc(&mut self, order: usize) -> Option<usize> {
+        assert!(order < MAX_ORDER);
+        let found = match (order..MAX_ORDER).find(|o| !self.free_lists[*o].is_null()) {
+            Some(found) => found,
+            None => {
+                self.stats.failures += 1;
+                return None;
+            }
+        };
+        let index = self.index(self.free_lists[found] as usize);
+        self.remove(index, found);
+        // split, giving back the upper halves
+        for o in (order..found).rev() {
+            self.push(index + (1 << o), o);
+        }
+        self.set_meta(index, ALLOCATED | order as u8);
+        self.stats.free -= 1 << order;
+        self.stats.allocations += 1;
+        Some(self.address(index))
+    }
+
+    // Whether `addr` is the start of an allocated block of `order`, which is
+    // what free() takes.
+    pub fn is_allocated(&self, addr: usize, order: usize) -> bool {
+        addr >= self.base
+            && (addr - self.base) % PAGE_SIZE == 0
+            && (addr - self.base) / PAGE_SIZE < self.frames
+            && self.meta((addr - self.base) / PAGE_SIZE) == ALLOCATED | order as u8
+    }
+
+    pub fn free(&mut self, addr: usize, order: usize) {
+        if !self.is_allocated(addr, order) {
+            panic!(
+                "free_frames: {:#x} is not an allocated block of order {}",
+                addr, order
+            );
+        }
+        let mut index = self.index(addr);
+        self.set_meta(index, 0);
+        self.stats.free += 1 << order;
+        self.stats.frees += 1;
+
+        let mut order = order;
+        while order < MAX_ORDER - 1 {
+            let buddy = match self.buddy(index, order) {
+                Some(buddy) if self.meta(buddy) == FREE | order as u8 => buddy,
+                _ => break,
+            };
+            self.remove(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push(index, order);
+    }
+
+    // Turns an allocated block into its two halves, which are then freed
+    // separately.
+    pub fn split(&mut self, addr: usize, order: usize) {
+        let index = self.index(addr);
+        assert!(order > 0 && self.meta(index) == ALLOCATED | order as u8);
+        self.set_meta(index, ALLOCATED | (order as u8 - 1));
+        self.set_meta(index + (1 << (order - 1)), ALLOCATED | (order as u8 - 1));
+        self.stats.allocations += 1;
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+}
+
+// Smallest order whose blocks hold `size` bytes.
+pub fn order_for(size: usize) -> usize {
+    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    pages.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+pub fn alloc_frames(order: usize) -> Option<usize> {
+    unsafe { FRAME_ALLOCATOR.alloc(order) }
+}
+
+pub fn alloc_frames_zeroed(order: usize) -> Option<usize> {
+    let addr = alloc_frames(order)?;
+    unsafe {
+        ptr::write_bytes(addr as *mut u8, 0, PAGE_SIZE << order);
+    }
+    Some(addr)
+}
+
+pub fn free_frames(addr: usize, order: usize) {
+    unsafe { FRAME_ALLOCATOR.free(addr, order) }
+}
+
+pub fn frames_allocated(addr: usize, order: usize) -> bool {
+    unsafe { FRAME_ALLOCATOR.is_allocated(addr, order) }
+}
+
+pub fn split_frames(addr: usize, order: usize) {
+    unsafe { FRAME_ALLOCATOR.split(addr, order) }
+}
+
+pub fn frame_stats() -> FrameStats {
+    unsafe { FRAME_ALLOCATOR.stats() }
+}
+
+pub fn dump_frame_stats() {
+    let stats = frame_stats();
+    info!(
+        "Frames: {}/{} free, {} allocations, {} frees, {} failures",
+        stats.free, stats.total, stats.allocations, stats.frees, stats.failures
+    );
+    for (order, blocks) in stats.free_blocks.iter().enumerate() {
+        if *blocks != 0 {
+            info!("  order {:2}: {} free blocks", order, blocks);
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use crate::allocator::frame::{self, alloc_frames, free_frames, MAX_ORDER};
+use crate::arch::PAGE_SIZE;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr;
+use log::info;
+
+// A slab holds at least this many objects.
+const MIN_OBJECTS: usize = 8;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub slabs: usize,
+    pub objects: usize,
+    pub in_use: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub failures: usize,
+}
+
+// Each slab starts with this header, followed by the objects.
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    // first free object
+    free: *mut u8,
+    in_use: usize,
+}
+
+fn list_push(head: &mut *mut Slab, slab: *mut Slab) {
+    unsafe {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = *head;
+        if !head.is_null() {
+            (**head).prev = slab;
+        }
+    }
+    *head = slab;
+}
+
+fn list_remove(head: &mut *mut Slab, slab: *mut Slab) {
+    unsafe {
+        let next = (*slab).next;
+        let prev = (*slab).prev;
+        if prev.is_null() {
+            *head = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
+}
+
+// Untyped object cache. Slabs come from the frame allocator and are aligned
+// to their size, so the slab of an object is found by masking its address.
+// Slabs are kept on three lists depending on how many of their objects are in
+// use. One empty slab is kept around, further ones go back to the frame
+// allocator.
+pub struct RawCache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    // computed when the first slab is created
+    order: usize,
+    offset: usize,
+    objects: usize,
+    // Free objects are linked through the word at this offset. It is past the
+    // object if there is a constructor, whose work must not be overwritten.
+    link: usize,
+    partial: *mut Slab,
+    full: *mut Slab,
+    empty: *mut Slab,
+    // run on each object when its slab is created or released
+    ctor: Option<fn(*mut u8)>,
+    dtor: Option<fn(*mut u8)>,
+    stats: CacheStats,
+}
+
+unsafe impl Sync for RawCache {}
+unsafe impl Send for RawCache {}
+
+impl RawCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        Self {
+            name,
+            size,
+            align,
+            order: 0,
+            offset: 0,
+            objects: 0,
+            link: 0,
+            partial: ptr::null_mut(),
+            full: ptr::null_mut(),
+            empty: ptr::null_mut(),
+            ctor: None,
+            dtor: None,
+            stats: CacheStats {
+                slabs: 0,
+                objects: 0,
+                in_use: 0,
+                allocations: 0,
+                frees: 0,
+                failures: 0,
+            },
+        }
+    }
+
+    fn compute_layout(&mut self) {
+        let word = size_of::<usize>();
+        let align = self.align.max(word);
+        let link = if self.ctor.is_some() {
+            (self.size + word - 1) & !(word - 1)
+        } else {
+            0
+        };
+        let size = self.size.max(link + word);
+        let size = (size + align - 1) & !(align - 1);
+        let offset = (size_of::<Slab>() + align - 1) & !(align - 1);
+        let mut order = 0;
+        while order < MAX_ORDER - 1 && ((PAGE_SIZE << order) - offset) / size < MIN_OBJECTS {
+            order += 1;
+        }
+        assert!((PAGE_SIZE << order) - offset >= size);
+        self.size = size;
+        self.align = align;
+        self.order = order;
+        self.offset = offset;
+        self.link = link;
+        self.objects = ((PAGE_SIZE << order) - offset) / size;
+    }
+
+    fn slab_size(&self) -> usize {
+        PAGE_SIZE << self.order
+    }
+
+    fn object(&self, slab: *mut Slab, index: usize) -> *mut u8 {
+        (slab as usize + self.offset + index * self.size) as *mut u8
+    }
+
+    fn next_free(&self, object: *mut u8) -> *mut *mut u8 {
+        (object as usize + self.link) as *mut *mut u8
+    }
+
+    fn grow(&mut self) -> Option<*mut Slab> {
+        if self.objects == 0 {
+            self.compute_layout();
+        }
+        let slab = alloc_frames(self.order)? as *mut Slab;
+        let mut free = ptr::null_mut();
+        for i in (0..self.objects).rev() {
+            let object = self.object(slab, i);
+            if let Some(ctor) = self.ctor {
+                ctor(object);
+            }
+            unsafe {
+                *self.next_free(object) = free;
+            }
+            free = object;
+        }
+        unsafe {
+            (*slab).free = free;
+            (*slab).in_use = 0;
+        }
+        self.stats.slabs += 1;
+        self.stats.objects += self.objects;
+        Some(slab)
+    }
+
+    fn release(&mut self, slab: *mut Slab) {
+        if let Some(dtor) = self.dtor {
+            for i in 0..self.objects {
+                dtor(self.object(slab, i));
+            }
+        }
+        free_frames(slab as usize, self.order);
+        self.stats.slabs -= 1;
+        self.stats.objects -= self.objects;
+    }
+
+    pub fn alloc(&mut self) -> *mut u8 {
+        if self.partial.is_null() {
+            let slab = if !self.empty.is_null() {
+                let slab = self.empty;
+                list_remove(&mut self.empty, slab);
+                slab
+            } else {
+                match self.grow() {
+                    Some(slab) => slab,
+                    None => {
+                        self.stats.failures += 1;
+                        return ptr::null_mut();
+                    }
+                }
+            };
+            list_push(&mut self.partial, slab);
+        }
+
+        let slab = self.partial;
+        let object = unsafe {
+            let object = (*slab).free;
+            (*slab).free = *self.next_free(object);
+            (*slab).in_use += 1;
+            object
+        };
+        if unsafe { (*slab).in_use } == self.objects {
+            list_remove(&mut self.partial, slab);
+            list_push(&mut self.full, slab);
+        }
+        self.stats.in_use += 1;
+        self.stats.allocations += 1;
+        object
+    }
+
+    pub fn free(&mut self, ptr: *mut u8) {
+        let slab = (ptr as usize & !(self.slab_size() - 1)) as *mut Slab;
+        assert!(
+            ptr as usize >= self.object(slab, 0) as usize,
+            "{}: {:p} is not an object of this cache",
+            self.name,
+            ptr
+        );
+        let in_use = unsafe {
+            *self.next_free(ptr) = (*slab).free;
+            (*slab).free = ptr;
+            (*slab).in_use -= 1;
+            (*slab).in_use
+        };
+        if in_use == self.objects - 1 {
+            list_remove(&mut self.full, slab);
+            list_push(&mut self.partial, slab);
+        }
+        if in_use == 0 {
+            list_remove(&mut self.partial, slab);
+            if self.empty.is_null() {
+                list_push(&mut self.empty, slab);
+            } else {
+                self.release(slab);
+            }
+        }
+        self.stats.in_use -= 1;
+        self.stats.frees += 1;
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn dump_stats(&self) {
+        info!(
+            "{}: {} slabs, {}/{} objects in use, {} allocations, {} frees, {} failures",
+            self.name,
+            self.stats.slabs,
+            self.stats.in_use,
+            self.stats.objects,
+            self.stats.allocations,
+            self.stats.frees,
+            self.stats.failures
+        );
+    }
+}
+
+// Cache of objects of type T.
+//
+// With a constructor, objects are constructed once when their slab is created
+// and alloc() hands them out in that state; callers have to return them in
+// the same state. The destructor runs when the slab is given back to the frame
+// allocator. Without a constructor the memory returned by alloc() is
+// uninitialized.
+pub struct KmemCache<T> {
+    raw: RawCache,
+    _marker: PhantomData<T>,
+}
+
+impl<T> KmemCache<T> {
+    pub fn new() -> Self {
+        Self {
+            raw: RawCache::new(core::any::type_name::<T>(), size_of::<T>(), align_of::<T>()),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn with_ctor(mut self, ctor: fn(*mut T)) -> Self {
+        self.raw.ctor = Some(unsafe { core::mem::transmute::<fn(*mut T), fn(*mut u8)>(ctor) });
+        self
+    }
+
+    pub fn with_dtor(mut self, dtor: fn(*mut T)) -> Self {
+        self.raw.dtor = Some(unsafe { core::mem::transmute::<fn(*mut T), fn(*mut u8)>(dtor) });
+        self
+    }
+
+    pub fn alloc(&mut self) -> Option<*mut T> {
+        let object = self.raw.alloc() as *mut T;
+        if object.is_null() {
+            None
+        } else {
+            Some(object)
+        }
+    }
+
+    pub fn free(&mut self, object: *mut T) {
+        self.raw.free(object as *mut u8);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.raw.stats()
+    }
+
+    pub fn dump_stats(&self) {
+        self.raw.dump_stats();
+    }
+}
+
+// Size classes of SlabAllocator. Larger requests get whole frame blocks.
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+pub struct SlabAllocator {
+    caches: UnsafeCell<[RawCache; SIZE_CLASSES.len()]>,
+}
+
+unsafe impl Sync for SlabAllocator {}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        Self {
+            caches: UnsafeCell::new([
+                RawCache::new("slab-16", 16, 16),
+                RawCache::new("slab-32", 32, 32),
+                RawCache::new("slab-64", 64, 64),
+                RawCache::new("slab-128", 128, 128),
+                RawCache::new("slab-256", 256, 256),
+                RawCache::new("slab-512", 512, 512),
+                RawCache::new("slab-1024", 1024, 1024),
+                RawCache::new("slab-2048", 2048, 2048),
+            ]),
+        }
+    }
+
+    // Objects are aligned to their size class.
+    fn cache(&self, layout: &Layout) -> Option<&mut RawCache> {
+        let size = layout.size().max(layout.align());
+        let class = SIZE_CLASSES.iter().position(|class| size <= *class)?;
+        unsafe { Some(&mut (*self.caches.get())[class]) }
+    }
+
+    pub fn dump_stats(&self) {
+        for cache in unsafe { (*self.caches.get()).iter() } {
+            cache.dump_stats();
+        }
+    }
+}
+
+// Frame blocks are aligned to their size.
+fn large_order(layout: &Layout) -> usize {
+    frame::order_for(layout.size().max(layout.align()))
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.cache(&layout) {
+            Some(cache) => cache.alloc(),
+            None => match large_order(&layout) {
+                order if order >= MAX_ORDER => ptr::null_mut(),
+                order => match alloc_frames(order) {
+                    Some(addr) => addr as *mut u8,
+                    None => ptr::null_mut(),
+                },
+            },
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match self.cache(&layout) {
+            Some(cache) => cache.free(ptr),
+            // never handed out by alloc
+            None if large_order(&layout) >= MAX_ORDER => {}
+            None => free_frames(ptr as usize, large_order(&layout)),
+        }
+    }
+}
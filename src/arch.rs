@@ -7,7 +7,12 @@ pub mod aarch64;
 #[cfg(target_arch = "x86_64")]
 pub mod x86_64;
 
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+pub mod asid;
+
 pub type CpuId = usize;
+// cores the scheduler keeps state for
+pub const MAX_CPUS: usize = 4;
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_SHIFT: usize = 12;
 
@@ -20,11 +25,32 @@ pub fn cpu_id() -> CpuId {
     return 0;
 }
 
+// Free-running counter used for time accounting. On riscv64 this is the time
+// CSR, which counts like mtime without going through the CLINT.
+pub fn timer_count() -> u64 {
+    #[cfg(target_arch = "riscv64")]
+    return riscv64::csr::Csr::Time.read() as u64;
+    #[cfg(not(target_arch = "riscv64"))]
+    return crate::time::clocksource().read();
+}
+
+pub fn timer_frequency() -> u64 {
+    crate::time::clocksource().frequency()
+}
+
+pub fn ticks_to_micros(ticks: u64) -> u64 {
+    (ticks as u128 * 1_000_000 / timer_frequency() as u128) as u64
+}
+
 pub fn interrupt_off() {
     #[cfg(target_arch = "riscv64")]
     unsafe {
         riscv64::riscv::STATE.interrupt_off();
     }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        aarch64::arm::STATE.interrupt_off();
+    }
 }
 
 pub fn interrupt_on() {
@@ -32,13 +58,212 @@ pub fn interrupt_on() {
     unsafe {
         riscv64::riscv::STATE.interrupt_on();
     }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        aarch64::arm::STATE.interrupt_on();
+    }
+}
+
+// Sleeps until an interrupt arrives, taking it if interrupts are off. Where
+// interrupts are not set up yet, it returns right away.
+pub fn wait_for_interrupt() {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        riscv64::riscv::STATE.interrupt_on();
+        core::arch::asm!("wfi");
+        riscv64::riscv::STATE.interrupt_off();
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        aarch64::arm::STATE.interrupt_on();
+        core::arch::asm!("wfi");
+        aarch64::arm::STATE.interrupt_off();
+    }
+    #[cfg(target_arch = "x86_64")]
+    core::hint::spin_loop();
 }
 
 pub fn is_interrupt_on() -> bool {
     #[cfg(target_arch = "riscv64")]
     return unsafe { riscv64::riscv::STATE.is_interrupt_on() };
     #[cfg(target_arch = "aarch64")]
-    return false;
+    return unsafe { aarch64::arm::STATE.is_interrupt_on() };
     #[cfg(target_arch = "x86_64")]
     return false;
 }
+
+// Frame pointer of the calling function. Frame pointers are forced on in
+// .cargo/config.toml.
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe {
+        #[cfg(target_arch = "riscv64")]
+        core::arch::asm!("mv {}, s0", out(reg)fp);
+        #[cfg(target_arch = "aarch64")]
+        core::arch::asm!("mov {}, x29", out(reg)fp);
+        #[cfg(target_arch = "x86_64")]
+        core::arch::asm!("mov {}, rbp", out(reg)fp);
+    }
+    fp
+}
+
+// Prints the callee-saved registers, the stack pointer and the return address
+// of the calling function. For panics, which come without a trap frame.
+#[inline(always)]
+pub fn dump_current_registers() {
+    #[cfg(target_arch = "riscv64")]
+    {
+        const NAMES: [&str; 14] = [
+            "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "sp", "ra",
+        ];
+        let mut values = [0usize; 14];
+        unsafe {
+            core::arch::asm!(
+                "sd s0, 0({0})",
+                "sd s1, 8({0})",
+                "sd s2, 16({0})",
+                "sd s3, 24({0})",
+                "sd s4, 32({0})",
+                "sd s5, 40({0})",
+                "sd s6, 48({0})",
+                "sd s7, 56({0})",
+                "sd s8, 64({0})",
+                "sd s9, 72({0})",
+                "sd s10, 80({0})",
+                "sd s11, 88({0})",
+                "sd sp, 96({0})",
+                "sd ra, 104({0})",
+                in(reg)values.as_mut_ptr(),
+            );
+        }
+        dump_registers(&core::array::from_fn::<_, 14, _>(|i| (NAMES[i], values[i])));
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        const NAMES: [&str; 13] = [
+            "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27", "x28", "fp", "lr", "sp",
+        ];
+        let mut values = [0usize; 13];
+        unsafe {
+            core::arch::asm!(
+                "stp x19, x20, [{0}]",
+                "stp x21, x22, [{0}, #16]",
+                "stp x23, x24, [{0}, #32]",
+                "stp x25, x26, [{0}, #48]",
+                "stp x27, x28, [{0}, #64]",
+                "stp x29, x30, [{0}, #80]",
+                "mov {1}, sp",
+                "str {1}, [{0}, #96]",
+                in(reg)values.as_mut_ptr(),
+                out(reg)_,
+            );
+        }
+        dump_registers(&core::array::from_fn::<_, 13, _>(|i| (NAMES[i], values[i])));
+    }
+}
+
+// Returns the return address stored in the frame `fp` and the frame pointer of
+// its caller. Chains end with a zero frame pointer.
+pub unsafe fn unwind_frame(fp: usize) -> Option<(usize, usize)> {
+    if fp == 0 || fp % core::mem::size_of::<usize>() != 0 {
+        return None;
+    }
+    let fp = fp as *const usize;
+    #[cfg(target_arch = "riscv64")]
+    let (ra, prev) = (*fp.sub(1), *fp.sub(2));
+    #[cfg(not(target_arch = "riscv64"))]
+    let (ra, prev) = (*fp.add(1), *fp);
+    Some((ra, prev))
+}
+
+// Prints saved registers, four per line.
+pub fn dump_registers(registers: &[(&str, usize)]) {
+    for row in registers.chunks(4) {
+        for (name, value) in row {
+            print!("{:>5}: {:#018x} ", name, value);
+        }
+        println!();
+    }
+}
+
+// User space is [user_start(), user_end()). The page at 0 is never part of
+// it, so that null pointers fault.
+pub fn user_start() -> usize {
+    #[cfg(target_arch = "aarch64")]
+    return aarch64::vm::USER_START;
+    #[cfg(not(target_arch = "aarch64"))]
+    return PAGE_SIZE;
+}
+
+pub fn user_end() -> usize {
+    #[cfg(target_arch = "riscv64")]
+    return unsafe { riscv64::vm::PAGING_MODE.user_end() };
+    #[cfg(target_arch = "aarch64")]
+    return aarch64::vm::USER_END;
+    #[cfg(target_arch = "x86_64")]
+    return crate::task::MMAP_TOP;
+}
+
+// Kernel virtual address of physical memory at `paddr`.
+pub fn phys_to_virt(paddr: usize) -> usize {
+    #[cfg(target_arch = "riscv64")]
+    return paddr + riscv64::address::PHYS_OFFSET;
+    #[cfg(not(target_arch = "riscv64"))]
+    return paddr;
+}
+
+// Physical address of a kernel virtual address in the direct map.
+pub fn virt_to_phys(vaddr: usize) -> usize {
+    #[cfg(target_arch = "riscv64")]
+    {
+        assert!(vaddr >= riscv64::address::PHYS_OFFSET);
+        return vaddr - riscv64::address::PHYS_OFFSET;
+    }
+    #[cfg(not(target_arch = "riscv64"))]
+    return vaddr;
+}
+
+// Unmaps the guard page below a kernel stack in the kernel page table.
+#[allow(unused_variables)]
+pub fn protect_guard_page(vaddr: usize) -> Result<(), crate::error::VMError> {
+    #[cfg(target_arch = "riscv64")]
+    return unsafe {
+        riscv64::vm::VM_MANAGER
+            .kernel_space()
+            .unmap_range(vaddr, PAGE_SIZE)
+    };
+    #[cfg(target_arch = "aarch64")]
+    return unsafe {
+        aarch64::vm::VM_MANAGER
+            .kernel_space()
+            .unmap_range(vaddr, PAGE_SIZE)
+    };
+    #[cfg(target_arch = "x86_64")]
+    return Ok(());
+}
+
+// Makes a guard page accessible again before it is handed back to the heap.
+#[allow(unused_variables)]
+pub fn release_guard_page(vaddr: usize) -> Result<(), crate::error::VMError> {
+    #[cfg(target_arch = "riscv64")]
+    return unsafe {
+        riscv64::vm::VM_MANAGER.kernel_space().map_range(
+            virt_to_phys(vaddr),
+            vaddr,
+            PAGE_SIZE,
+            true,
+            true,
+            false,
+            false,
+        )
+    };
+    #[cfg(target_arch = "aarch64")]
+    return unsafe {
+        aarch64::vm::VM_MANAGER
+            .kernel_space()
+            .map_range(vaddr, vaddr, PAGE_SIZE, true, true, false, false)
+    };
+    #[cfg(target_arch = "x86_64")]
+    return Ok(());
+}
@@ -1,6 +1,7 @@
 pub mod address;
 pub mod arm;
 pub mod exception;
+pub mod smp;
 pub mod start;
 pub mod task;
 pub mod vm;
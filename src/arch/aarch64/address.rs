@@ -9,8 +9,12 @@ extern "C" {
     pub fn _data_end();
     pub fn _bss_start();
     pub fn _bss_end();
+    pub fn _stack_guard();
     pub fn _stack_start();
     pub fn _stack_end();
     pub fn _heap_start();
     pub fn _heap_end();
+    pub fn _ram_end();
+    pub fn _trap_stack_start();
+    pub fn _trap_stack_end();
 }
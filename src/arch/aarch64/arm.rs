@@ -1,14 +1,40 @@
-use crate::arch::CpuId;
+use crate::arch::{CpuId, MAX_CPUS};
+use crate::interrupt::Backup;
 use crate::lazy::Lazy;
+use crate::time::ClockSource;
+use crate::timer::{self, TICK_HZ};
+use const_default::ConstDefault;
 use core::arch::asm;
 
 pub static mut STATE: Lazy<CpuState> = Lazy::new(|| CpuState::new());
 
-pub struct CpuState {}
+// DAIF.I, which masks IRQs
+const DAIF_IRQ: usize = 1 << 7;
+
+pub struct CpuState {
+    // one per core, indexed by cpuid()
+    disable_counter: [usize; MAX_CPUS],
+}
+
+pub fn daif() -> usize {
+    let daif: usize;
+    unsafe {
+        asm!("mrs {}, daif", out(reg)daif);
+    }
+    daif
+}
+
+pub fn set_daif(daif: usize) {
+    unsafe {
+        asm!("msr daif, {}", in(reg)daif);
+    }
+}
 
 impl CpuState {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            disable_counter: [0; MAX_CPUS],
+        }
     }
 
     pub fn cpuid(&self) -> CpuId {
@@ -19,4 +45,104 @@ impl CpuState {
 
         id & 0b11
     }
+
+    pub fn interrupt_off(&mut self) {
+        let counter = &mut self.disable_counter[self.cpuid()];
+        if *counter >= 1 {
+            *counter -= 1;
+        }
+        if *counter == 0 {
+            unsafe {
+                asm!("msr daifset, #2");
+            }
+        }
+    }
+
+    pub fn interrupt_on(&mut self) {
+        let counter = &mut self.disable_counter[self.cpuid()];
+        if *counter == 0 {
+            unsafe {
+                asm!("msr daifclr, #2");
+            }
+        }
+        *counter += 1;
+    }
+
+    pub fn is_interrupt_on(&self) -> bool {
+        daif() & DAIF_IRQ == 0
+    }
+}
+
+#[derive(ConstDefault)]
+pub struct InterruptFlag {
+    daif_mask: usize,
+}
+
+impl Backup for InterruptFlag {
+    fn save_and_off() -> Self {
+        let daif_mask = !daif() & DAIF_IRQ;
+        // Mask IRQs
+        set_daif(daif() | DAIF_IRQ);
+        Self { daif_mask }
+    }
+
+    fn restore(&self) {
+        set_daif(daif() & !self.daif_mask);
+    }
+}
+
+pub fn counter() -> u64 {
+    let count: u64;
+    unsafe {
+        asm!("mrs {}, cntpct_el0", out(reg)count);
+    }
+    count
+}
+
+pub fn counter_frequency() -> u64 {
+    let freq: u64;
+    unsafe {
+        asm!("mrs {}, cntfrq_el0", out(reg)freq);
+    }
+    freq
+}
+
+// Starts the EL1 physical timer, which raises CNTPNSIRQ TICK_HZ times a
+// second.
+pub fn timer_init() {
+    timer_reload();
+    unsafe {
+        // ENABLE, with IMASK clear
+        asm!("msr cntp_ctl_el0, {}", in(reg)1usize);
+    }
+}
+
+fn timer_reload() {
+    let interval = counter_frequency() / TICK_HZ;
+    unsafe {
+        asm!("msr cntp_tval_el0, {}", in(reg)interval);
+    }
+}
+
+pub unsafe fn timer_interrupt() {
+    timer_reload();
+    timer::poll();
+    crate::task::TASK_MANAGER.tick();
+}
+
+// The ARM generic timer's physical count.
+pub struct GenericTimer;
+
+impl ClockSource for GenericTimer {
+    fn name(&self) -> &'static str {
+        "arm-generic-timer"
+    }
+
+    fn read(&self) -> u64 {
+        counter()
+    }
+
+    fn frequency(&self) -> u64 {
+        counter_frequency()
+    }
 }
@@ -1,22 +1,223 @@
+use crate::arch::aarch64::address;
+use crate::arch::{self, PAGE_SIZE};
+use crate::task;
+use crate::KERNEL_LOCK;
+
 pub const EXCEPTION_SYNC: usize = 0;
 pub const EXCEPTION_IRQ: usize = 1;
 pub const EXCEPTION_FIQ: usize = 2;
 pub const EXCEPTION_SERROR: usize = 3;
 
+// Exception classes (ESR_EL1.EC)
+pub const EC_SVC_AARCH64: usize = 0b010101;
+pub const EC_INSTRUCTION_ABORT_LOWER_EL: usize = 0b100000;
+pub const EC_INSTRUCTION_ABORT_SAME_EL: usize = 0b100001;
+pub const EC_DATA_ABORT_LOWER_EL: usize = 0b100100;
+pub const EC_DATA_ABORT_SAME_EL: usize = 0b100101;
+
+// Registers saved by SAVE_AND_CALL in vector.S
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub x: [usize; 30],
+    pub lr: usize,
+    pub exception_type: usize,
+    pub esr: usize,
+    pub elr: usize,
+    pub spsr: usize,
+    pub far: usize,
+    pub sp_el0: usize,
+    _padding: usize,
+}
+
+impl ExceptionFrame {
+    pub fn exception_class(&self) -> usize {
+        (self.esr >> 26) & 0x3f
+    }
+
+    pub fn dump(&self) {
+        const NAMES: [&str; 30] = [
+            "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13",
+            "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25",
+            "x26", "x27", "x28", "x29",
+        ];
+        let mut registers = [("", 0); 36];
+        for (i, name) in NAMES.iter().enumerate() {
+            registers[i] = (*name, self.x[i]);
+        }
+        registers[30] = ("lr", self.lr);
+        registers[31] = ("elr", self.elr);
+        registers[32] = ("spsr", self.spsr);
+        registers[33] = ("esr", self.esr);
+        registers[34] = ("far", self.far);
+        registers[35] = ("sp_el0", self.sp_el0);
+        arch::dump_registers(&registers);
+    }
+
+    fn is_abort(&self) -> bool {
+        matches!(
+            self.exception_class(),
+            EC_INSTRUCTION_ABORT_LOWER_EL
+                | EC_INSTRUCTION_ABORT_SAME_EL
+                | EC_DATA_ABORT_LOWER_EL
+                | EC_DATA_ABORT_SAME_EL
+        )
+    }
+
+    fn is_fetch_fault(&self) -> bool {
+        matches!(
+            self.exception_class(),
+            EC_INSTRUCTION_ABORT_LOWER_EL | EC_INSTRUCTION_ABORT_SAME_EL
+        )
+    }
+}
+
+pub fn exception_type_name(exception_type: usize) -> &'static str {
+    match exception_type {
+        EXCEPTION_SYNC => "synchronous exception",
+        EXCEPTION_IRQ => "IRQ",
+        EXCEPTION_FIQ => "FIQ",
+        EXCEPTION_SERROR => "SError",
+        _ => "unknown exception type",
+    }
+}
+
+pub fn exception_class_name(ec: usize) -> &'static str {
+    match ec {
+        0b000000 => "unknown reason",
+        0b000001 => "trapped WFI/WFE",
+        0b000111 => "trapped SIMD/FP access",
+        0b001110 => "illegal execution state",
+        EC_SVC_AARCH64 => "SVC from AArch64",
+        0b011000 => "trapped MSR/MRS/system instruction",
+        EC_INSTRUCTION_ABORT_LOWER_EL => "instruction abort from a lower EL",
+        EC_INSTRUCTION_ABORT_SAME_EL => "instruction abort",
+        0b100010 => "PC alignment fault",
+        EC_DATA_ABORT_LOWER_EL => "data abort from a lower EL",
+        EC_DATA_ABORT_SAME_EL => "data abort",
+        0b100110 => "SP alignment fault",
+        0b101100 => "trapped floating-point exception",
+        0b101111 => "SError",
+        0b110000 | 0b110001 => "breakpoint",
+        0b110010 | 0b110011 => "software step",
+        0b110100 | 0b110101 => "watchpoint",
+        0b111100 => "BRK instruction",
+        _ => "unknown exception class",
+    }
+}
+
+// Fault status code of an instruction or data abort (ISS[5:0])
+pub fn fault_status_name(iss: usize) -> &'static str {
+    match iss & 0x3f {
+        0b000000..=0b000011 => "address size fault",
+        0b000100..=0b000111 => "translation fault",
+        0b001001..=0b001011 => "access flag fault",
+        0b001101..=0b001111 => "permission fault",
+        0b010000 => "synchronous external abort",
+        0b100001 => "alignment fault",
+        0b110000 => "TLB conflict abort",
+        _ => "unknown fault",
+    }
+}
+
+fn describe(frame: &ExceptionFrame) {
+    let ec = frame.exception_class();
+    if frame.exception_type != EXCEPTION_SYNC {
+        println!("{}", exception_type_name(frame.exception_type));
+    } else if frame.is_abort() {
+        let access = if frame.is_fetch_fault() {
+            "fetch"
+        } else if frame.esr & (1 << 6) != 0 {
+            "write"
+        } else {
+            "read"
+        };
+        println!(
+            "{}: {} on {} (level {})",
+            exception_class_name(ec),
+            fault_status_name(frame.esr),
+            access,
+            frame.esr & 0b11
+        );
+    } else {
+        println!("{}", exception_class_name(ec));
+    }
+    frame.dump();
+}
+
+// FIQs are not used.
+unsafe fn handle_interrupt(frame: &ExceptionFrame) {
+    if frame.exception_type == EXCEPTION_FIQ {
+        panic!("unhandled FIQ at {:#x}", frame.elr);
+    }
+    #[cfg(target_board = "raspi3b")]
+    crate::device::raspi3b::irq::IRQ_MANAGER.handle_irq();
+}
+
 #[no_mangle]
-pub unsafe extern "C" fn kernel_exception(
-    exception_type: usize,
-    exception_cause: usize,
-    return_address: usize,
-    saved_pstate: usize,
-    fault_address: usize,
-) -> ! {
+pub unsafe extern "C" fn kernel_exception(frame: &mut ExceptionFrame) {
+    if frame.exception_type == EXCEPTION_IRQ || frame.exception_type == EXCEPTION_FIQ {
+        // an idle core waits for interrupts without the lock
+        let held = KERNEL_LOCK.is_held();
+        if !held {
+            KERNEL_LOCK.lock();
+        }
+        handle_interrupt(frame);
+        KERNEL_LOCK.complete_intr();
+        if !held {
+            KERNEL_LOCK.release();
+        }
+        return;
+    }
+
+    if frame.exception_type == EXCEPTION_SYNC && frame.exception_class() == EC_DATA_ABORT_SAME_EL {
+        let guard = address::_stack_guard as usize;
+        if guard <= frame.far && frame.far < guard + PAGE_SIZE {
+            panic!("stack overflow in boot stack");
+        }
+        task::check_stack_overflow(frame.far);
+    }
+
+    crate::crashdump::begin();
+    describe(frame);
+    if frame.exception_type == EXCEPTION_SYNC && !frame.is_fetch_fault() {
+        println!("instruction: {:#010x}", *(frame.elr as *const u32));
+    }
+    crate::backtrace::print_backtrace_at(Some(frame.elr), frame.x[29]);
     panic!(
-        "type: {}, exception-class: {:#b}, ret: {:#x}, pstate: {:#x}, fault-addr: {:#x}",
-        exception_type,
-        (exception_cause >> 26) & 0x3f,
-        return_address,
-        saved_pstate,
-        fault_address
+        "kernel {} at {:#x}, address {:#x}",
+        exception_class_name(frame.exception_class()),
+        frame.elr,
+        frame.far
     );
 }
+
+// Exceptions taken from EL0: interrupts, system calls and faults, which kill
+// the running task. The frame is the task's user frame, at the top of its
+// kernel stack.
+#[no_mangle]
+pub unsafe extern "C" fn user_exception(frame: &mut ExceptionFrame) {
+    KERNEL_LOCK.lock();
+    task::TASK_MANAGER.enter_kernel();
+    if frame.exception_type == EXCEPTION_IRQ || frame.exception_type == EXCEPTION_FIQ {
+        handle_interrupt(frame);
+        KERNEL_LOCK.complete_intr();
+    } else if frame.exception_type == EXCEPTION_SYNC && frame.exception_class() == EC_SVC_AARCH64 {
+        // elr already points past the svc
+        let args = [
+            frame.x[0], frame.x[1], frame.x[2], frame.x[3], frame.x[4], frame.x[5],
+        ];
+        frame.x[0] = crate::syscall::syscall(frame.x[8], args) as usize;
+    } else {
+        describe(frame);
+        task::kill_current(format_args!(
+            "{} at {:#x}, address {:#x}",
+            exception_class_name(frame.exception_class()),
+            frame.elr,
+            frame.far
+        ));
+    }
+    task::exit_if_killed();
+    task::TASK_MANAGER.preempt();
+    task::TASK_MANAGER.enter_user();
+    KERNEL_LOCK.release();
+}
@@ -0,0 +1,111 @@
+use crate::arch::aarch64::task::KERNEL_STACK_SIZE;
+use crate::arch::aarch64::vm::{MmuConfig, VM_MANAGER};
+use crate::arch::{self, MAX_CPUS};
+use crate::device::raspi3b::irq::{self, Irq, IRQ_MANAGER};
+use crate::task::stack::KernelStack;
+use crate::task::TASK_MANAGER;
+use crate::time;
+use crate::KERNEL_LOCK;
+use alloc::format;
+use core::arch::{asm, global_asm};
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use log::{info, warn};
+
+global_asm!(include_str!("secondary.S"));
+
+extern "C" {
+    fn secondary_entry();
+}
+
+// Cores 1-3 poll their slot, 0xd8 + 8 * core, for the address to jump to.
+const SPIN_TABLE: usize = 0xd8;
+const START_TIMEOUT: Duration = Duration::from_secs(1);
+
+// What secondary_entry needs before the MMU is on. It sits alone in a cache
+// line, so cleaning that line is enough. Each core has a stack of its own, so
+// one that shows up late does not take the next one's.
+#[repr(C, align(64))]
+struct SecondaryBoot {
+    stacks: [usize; MAX_CPUS],
+    mmu: MmuConfig,
+}
+
+#[no_mangle]
+static mut SECONDARY_BOOT: SecondaryBoot = SecondaryBoot {
+    stacks: [0; MAX_CPUS],
+    mmu: MmuConfig {
+        tcr_el1: 0,
+        mair_el1: 0,
+        ttbr0_el1: 0,
+        sctlr_el1: 0,
+    },
+};
+
+// bit per core that reached secondary_start
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+pub fn online_cores() -> usize {
+    ONLINE.load(Ordering::Acquire).count_ones() as usize
+}
+
+// Writes a cache line back to memory, for readers with their caches off.
+unsafe fn clean_dcache_line(addr: usize) {
+    asm!("dc civac, {}", in(reg)addr);
+    asm!("dsb sy");
+}
+
+// Releases cores 1-3 one at a time. They wait for the kernel lock, so they
+// join scheduling once this core lets go of it. A core that does not come up
+// in time is left behind.
+pub unsafe fn start_secondary_cores() {
+    assert!(size_of::<SecondaryBoot>() <= 64);
+    SECONDARY_BOOT.mmu = VM_MANAGER.mmu_config();
+    for core in 1..MAX_CPUS {
+        let stack = match KernelStack::new(KERNEL_STACK_SIZE) {
+            Ok(stack) => stack,
+            Err(e) => {
+                warn!("smp: no stack for core {}: {:?}", core, e);
+                continue;
+            }
+        };
+        SECONDARY_BOOT.stacks[core] = stack.top();
+        // the core runs on it for good
+        core::mem::forget(stack);
+        clean_dcache_line(&SECONDARY_BOOT as *const SecondaryBoot as usize);
+
+        let slot = (SPIN_TABLE + 8 * core) as *mut usize;
+        slot.write_volatile(secondary_entry as usize);
+        clean_dcache_line(slot as usize);
+        asm!("sev");
+
+        let start = time::monotonic();
+        while ONLINE.load(Ordering::Acquire) & (1 << core) == 0 {
+            if time::monotonic() - start > START_TIMEOUT {
+                warn!("smp: core {} did not come up", core);
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    info!("smp: {} cores online", online_cores());
+}
+
+// Where secondary_entry lands, at EL1 with the MMU on.
+#[no_mangle]
+unsafe extern "C" fn secondary_start() -> ! {
+    let core = arch::cpu_id();
+    ONLINE.fetch_or(1 << core, Ordering::Release);
+
+    KERNEL_LOCK.lock();
+    info!("smp: core {} online", core);
+    IRQ_MANAGER.enable(Irq::Local(irq::LOCAL_CNTPNS));
+    super::arm::timer_init();
+    TASK_MANAGER.start_cpu(&format!("cpu{}", core)).unwrap();
+    // like the boot context of core 0, this one is never switched back to
+    TASK_MANAGER.block_current();
+    loop {
+        TASK_MANAGER.schedule();
+    }
+}
@@ -21,17 +21,24 @@ pub unsafe extern "C" fn start() {
         msr sctlr_el1, xzr
         ldr x0, =(1 << 31)
         msr hcr_el2, x0
+        // EL1 may use the physical counter and timer
+        mov x0, #0x3
+        msr cnthctl_el2, x0
+        msr cntvoff_el2, xzr
 
         mov x0, #0x4b1
         msr scr_el3, x0
         adr x0, main
         msr elr_el3, x0
-        mov x0, #0x5
+        // EL1h with DAIF masked; the kernel unmasks IRQs once it is ready
+        mov x0, #0x3c5
         msr spsr_el3, x0
         mov x0, sp
         msr sp_el1, x0
         ldr x0, =vector
         msr vbar_el1, x0
+        // main is the outermost frame
+        mov x29, #0
         eret
     "
     );
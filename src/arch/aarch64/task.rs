@@ -1,13 +1,20 @@
-use crate::error::TaskError;
+use crate::arch::aarch64::exception::ExceptionFrame;
+use crate::arch::aarch64::vm::{AddressSpace, USER_END, USER_START, VM_MANAGER};
+use crate::arch::PAGE_SIZE;
+use crate::error::{TaskError, VMError};
 use crate::lazy::Lazy;
+use crate::task::stack::KernelStack;
 use crate::task::{ArchTaskManager, TaskId};
-use alloc::alloc::{alloc_zeroed, Layout};
 use alloc::string::*;
-use core::arch::global_asm;
+use core::arch::{asm, global_asm};
+use core::mem::size_of;
 use hashbrown::HashMap;
 
 pub const KERNEL_STACK_SIZE: usize = 0x8000;
 
+// EL0t with DAIF clear, so interrupts are taken in user mode
+const SPSR_EL0: usize = 0;
+
 pub static mut ARCH_TASK_MANAGER: Lazy<TaskManager> = Lazy::new(|| TaskManager::new());
 
 global_asm!(include_str!("switch.S"));
@@ -26,12 +33,29 @@ impl TaskManager {
             tasks: HashMap::new(),
         }
     }
+
+    pub fn user_context(&self, id: TaskId) -> Option<*mut ExceptionFrame> {
+        self.tasks.get(&id).map(|task| task.user_frame())
+    }
+}
+
+fn check_user_range(vaddr: usize, size: usize) -> Result<(), TaskError> {
+    if vaddr < USER_START || vaddr > USER_END || USER_END - vaddr < size {
+        return Err(TaskError::MapError(VMError::OutOfRange));
+    }
+    Ok(())
+}
+
+unsafe fn set_ttbr0(ttbr0: usize) {
+    asm!("msr ttbr0_el1, {}", "isb", in(reg)ttbr0);
 }
 
 impl ArchTaskManager for TaskManager {
     unsafe fn context_switch(&mut self, from: TaskId, to: TaskId) {
         assert!(self.tasks.contains_key(&from));
         assert!(self.tasks.contains_key(&to));
+        // every address space maps the kernel, so the switch can go first
+        set_ttbr0(self.tasks.get_mut(&to).unwrap().address_space.activate());
         let task_from = self.tasks.get(&from).unwrap();
         let task_to = self.tasks.get(&to).unwrap();
         let context_from = &task_from.context as *const Context as usize;
@@ -41,27 +65,69 @@ impl ArchTaskManager for TaskManager {
 
     unsafe fn user_switch(&mut self, current: TaskId) -> ! {
         assert!(self.tasks.contains_key(&current));
-        unimplemented!();
+        let task = self.tasks.get_mut(&current).unwrap();
+        set_ttbr0(task.address_space.activate());
+        crate::KERNEL_LOCK.release();
+        // Exceptions from EL0 save the user registers to where sp_el1 was at
+        // the eret, which is the top of the kernel stack.
+        asm!(
+            "mov sp, {}",
+            "b restore_and_return",
+            in(reg)task.user_frame(),
+            options(noreturn)
+        );
     }
 
     fn map(
         &mut self,
         id: TaskId,
-        _paddr: usize,
-        _vaddr: usize,
-        _r: bool,
-        _w: bool,
-        _x: bool,
+        paddr: usize,
+        vaddr: usize,
+        r: bool,
+        w: bool,
+        x: bool,
     ) -> Result<(), TaskError> {
-        Err(TaskError::TaskNotFound(id))
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        task.map(paddr, vaddr, r, w, x)?;
+        Ok(())
     }
 
-    fn create_arch_task(&mut self, id: TaskId, name: String) {
-        let kernel_stack = unsafe {
-            let layout = Layout::from_size_align(KERNEL_STACK_SIZE, 0x1000).unwrap();
-            alloc_zeroed(layout)
-        };
+    fn unmap(&mut self, id: TaskId, vaddr: usize, size: usize) -> Result<(), TaskError> {
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        check_user_range(vaddr, size)?;
+        task.address_space
+            .unmap_range(vaddr, size)
+            .map_err(|e| TaskError::MapError(e))
+    }
+
+    fn protect(
+        &mut self,
+        id: TaskId,
+        vaddr: usize,
+        size: usize,
+        r: bool,
+        w: bool,
+        x: bool,
+    ) -> Result<(), TaskError> {
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        check_user_range(vaddr, size)?;
+        task.address_space
+            .protect_range(vaddr, size, r, w, x, true)
+            .map_err(|e| TaskError::MapError(e))
+    }
+
+    fn translate(&self, id: TaskId, vaddr: usize) -> Option<usize> {
+        self.tasks.get(&id)?.address_space.translate(vaddr)
+    }
+
+    fn create_arch_task(&mut self, id: TaskId, name: String) -> Result<(), TaskError> {
+        let kernel_stack = KernelStack::new(KERNEL_STACK_SIZE).map_err(TaskError::MapError)?;
         self.tasks.insert(id, Task::new(id, name, kernel_stack));
+        Ok(())
+    }
+
+    fn remove_arch_task(&mut self, id: TaskId) {
+        self.tasks.remove(&id);
     }
 
     fn init_start(&mut self, id: TaskId, start_address: usize) -> Result<(), TaskError> {
@@ -73,8 +139,22 @@ impl ArchTaskManager for TaskManager {
         Ok(())
     }
 
-    fn init_user_entry(&mut self, id: TaskId, _entry: usize) -> Result<(), TaskError> {
-        Err(TaskError::TaskNotFound(id))
+    fn init_user_entry(&mut self, id: TaskId, entry: usize) -> Result<(), TaskError> {
+        let frame = self
+            .tasks
+            .get(&id)
+            .ok_or(TaskError::TaskNotFound(id))?
+            .user_frame();
+        unsafe {
+            frame.write_bytes(0, 1);
+            (*frame).elr = entry;
+            (*frame).spsr = SPSR_EL0;
+        }
+        Ok(())
+    }
+
+    fn kernel_stack(&self, id: TaskId) -> Option<&KernelStack> {
+        self.tasks.get(&id).map(|task| &task.kernel_stack)
     }
 }
 
@@ -102,20 +182,46 @@ pub struct Context {
 pub struct Task {
     id: TaskId,
     name: String,
-    kernel_stack: *mut u8,
+    address_space: AddressSpace,
+    kernel_stack: KernelStack,
     pub context: Context,
 }
 
 impl Task {
-    pub fn new(id: TaskId, name: String, kernel_stack: *mut u8) -> Self {
+    pub fn new(id: TaskId, name: String, kernel_stack: KernelStack) -> Self {
+        assert!(size_of::<ExceptionFrame>() % 16 == 0);
+        // the kernel side starts below the user frame
+        let sp = kernel_stack.top() - size_of::<ExceptionFrame>();
         Self {
             id,
             name,
+            address_space: unsafe { VM_MANAGER.new_user_space() },
             kernel_stack,
             context: Context {
-                sp: kernel_stack as usize + KERNEL_STACK_SIZE,
+                sp,
                 ..Default::default()
             },
         }
     }
+
+    // The user registers, saved at the top of the kernel stack while the task
+    // is in the kernel.
+    pub fn user_frame(&self) -> *mut ExceptionFrame {
+        (self.kernel_stack.top() - size_of::<ExceptionFrame>()) as *mut ExceptionFrame
+    }
+
+    pub fn map(
+        &mut self,
+        paddr: usize,
+        vaddr: usize,
+        r: bool,
+        w: bool,
+        x: bool,
+    ) -> Result<(), TaskError> {
+        check_user_range(vaddr, PAGE_SIZE)?;
+        self.address_space
+            .map_range(paddr, vaddr, PAGE_SIZE, r, w, x, true)
+            .map_err(|e| TaskError::MapError(e))?;
+        Ok(())
+    }
 }
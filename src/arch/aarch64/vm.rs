@@ -1,18 +1,25 @@
-use crate::arch::PAGE_SIZE;
+use crate::allocator::frame::{alloc_frames_zeroed, free_frames};
+use crate::arch::aarch64::address;
+use crate::arch::asid::{Asid, ASID_ALLOCATOR, KERNEL_ASID};
+use crate::arch::{cpu_id, PAGE_SIZE};
 use crate::error::VMError;
 use crate::lazy::Lazy;
-use alloc::alloc::alloc_zeroed;
-use alloc::string::{String, ToString};
-use alloc::vec;
 use bitflags::bitflags;
-use core::alloc::Layout;
 use core::arch::asm;
 use core::mem::size_of;
-use hashbrown::HashMap;
 use log::info;
 
 pub const LEVELS: usize = 3;
 
+const TTBR_ASID_SHIFT: usize = 48;
+
+// The kernel runs from TTBR0 too, so user address spaces share the kernel's
+// tables for the first KERNEL_ENTRIES root entries, which cover RAM and the
+// peripherals. User space is what is left above.
+const KERNEL_ENTRIES: usize = 2;
+pub const USER_START: usize = KERNEL_ENTRIES << (12 + 9 * (LEVELS - 1));
+pub const USER_END: usize = 1 << (12 + 9 * LEVELS);
+
 // https://developer.arm.com/documentation/ddi0595/2021-12/AArch64-Registers/MAIR-EL1--Memory-Attribute-Indirection-Register--EL1-
 // mair_el1.attr0 = 0b0100_0100  means Normal memory, Inner/Outer Non-cacheable
 // mair_el1.attr1 = 0b1111_1111  means Normal memory, Inner/Outer WB/WA/RA
@@ -91,6 +98,14 @@ impl Entry {
         self.0 & PTE::OA.bits()
     }
 
+    pub fn is_table(&self) -> bool {
+        self.0 & 0b11 == PTE::TABLE.bits()
+    }
+
+    pub fn clear_flags(&mut self) {
+        self.0 &= !(PTE::AP_2_1 | PTE::UXN | PTE::PXN).bits();
+    }
+
     pub fn set_flags(&mut self, r: bool, w: bool, x: bool, u: bool) {
         assert!(r || w);
         assert!(!(!r && w)); // invalid read write combination
@@ -102,20 +117,51 @@ impl Entry {
             (true, false, false) => self.0 |= PTE::RO_EL1.bits(),
             _ => unreachable!("r: {} w: {} x: {} u: {}", r, w, x, u),
         }
+        // only the exception level that can access the entry may execute it
         match (x, u) {
-            (true, true) => self.0 = self.0 & !PTE::UXN.bits(),
-            (true, false) => self.0 = self.0 & !PTE::PXN.bits(),
-            (false, true) => self.0 |= PTE::UXN.bits(),
-            (false, false) => self.0 |= PTE::PXN.bits(),
+            (true, true) => self.0 = (self.0 & !PTE::UXN.bits()) | PTE::PXN.bits(),
+            (true, false) => self.0 = (self.0 & !PTE::PXN.bits()) | PTE::UXN.bits(),
+            (false, _) => self.0 |= (PTE::UXN | PTE::PXN).bits(),
         }
     }
 
+    pub fn is_user_accessible(&self) -> bool {
+        self.0 & PTE::RW_ALL.bits() != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.0 & PTE::RO_EL1.bits() == 0
+    }
+
+    // Executable at the exception level that can access the entry.
+    pub fn is_executable(&self) -> bool {
+        let xn = if self.is_user_accessible() {
+            PTE::UXN
+        } else {
+            PTE::PXN
+        };
+        self.0 & xn.bits() == 0
+    }
+
+    pub fn is_user_executable(&self) -> bool {
+        self.0 & PTE::UXN.bits() == 0
+    }
+
+    // Tag the TLB entry with the current ASID
+    pub fn set_ng(&mut self) {
+        self.0 |= PTE::NG.bits()
+    }
+
     pub fn set_af(&mut self) {
         self.0 |= PTE::AF.bits()
     }
 
     pub fn set_attr(&mut self, attr: usize) {
-        self.0 = (self.0 & !PTE::ATTRINDX.bits()) | attr;
+        self.0 = (self.0 & !(PTE::ATTRINDX | PTE::SH_1_0).bits()) | attr;
+        // normal memory is inner shareable, so the cores see each other's data
+        if attr != PTE::DEVICE.bits() {
+            self.0 |= PTE::SH_1_0.bits();
+        }
     }
 }
 
@@ -124,6 +170,25 @@ pub struct PageTable {
 }
 
 impl PageTable {
+    pub fn create() -> *mut PageTable {
+        assert_eq!(size_of::<PageTable>(), 4096);
+
+        alloc_frames_zeroed(0).expect("out of frames for page tables") as *mut PageTable
+    }
+
+    // Frees `table` and every table below it. The pages mapped by page and
+    // block entries are not touched.
+    pub unsafe fn destroy(table: *mut PageTable, level: usize) {
+        if level > 0 {
+            for entry in (*table).entries.iter() {
+                if entry.is_table() {
+                    Self::destroy(entry.get_oa() as *mut PageTable, level - 1);
+                }
+            }
+        }
+        free_frames(table as usize, 0);
+    }
+
     pub fn address(&self) -> usize {
         (self as *const PageTable) as usize
     }
@@ -142,197 +207,425 @@ impl PageTable {
         self.entries[index]
     }
 
-    pub fn identity_mapping(&mut self, level: usize, old: usize) {
+    // Maps everything that this table covers to the same physical address,
+    // readable and writable but not executable.
+    pub fn identity_mapping(&mut self, level: usize) {
         for i in 0..self.size() {
-            let paddr = (i << (12 + 9 * level)) | old;
+            let paddr = i << (12 + 9 * level);
             let mut entry = Entry::default();
-            entry.as_block();
-            entry.set_flags(true, true, true, false);
+            if level == 0 {
+                entry.as_page();
+            } else {
+                entry.as_block();
+            }
+            entry.set_flags(true, true, false, false);
             entry.set_attr(PTE::NORMAL_CACHEABLE.bits());
             entry.set_af();
             entry.set_oa(paddr);
             self.update_entry(i, entry);
         }
     }
+
+    // Fills the table with `level` entries that together map the same range,
+    // with the same attributes, as the block `block`.
+    pub fn split(&mut self, level: usize, block: Entry) {
+        for i in 0..self.size() {
+            let mut entry = Entry(block.0 + (i << (12 + 9 * level)));
+            if level == 0 {
+                entry.as_page();
+            }
+            self.update_entry(i, entry);
+        }
+    }
 }
 
-pub struct VMManager {
-    root_tables: HashMap<String, *mut PageTable>,
+fn table_index(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (12 + 9 * level)) & 0x1ff
 }
 
-unsafe impl Sync for VMManager {}
-unsafe impl Send for VMManager {}
+// Flushes every TLB entry, on all cores.
+pub fn invalidate_all() {
+    unsafe {
+        asm!("dsb ishst");
+        asm!("tlbi vmalle1is");
+        asm!("dsb ish");
+        asm!("isb");
+    }
+}
 
-impl VMManager {
+fn check_aligned(addrs: &[usize]) -> Result<(), VMError> {
+    if addrs.iter().any(|addr| addr & (PAGE_SIZE - 1) != 0) {
+        Err(VMError::Misaligned)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn memory_attr(paddr: usize) -> usize {
+    let mut attr = PTE::NORMAL_CACHEABLE.bits();
+    if cfg!(target_board = "raspi3b") {
+        use crate::device::raspi3b::base::*;
+        if (MMIO_BASE <= paddr && paddr < (MMIO_BASE + MMIO_SIZE))
+            || (LOCAL_BASE <= paddr && paddr < (LOCAL_BASE + LOCAL_SIZE))
+        {
+            attr = PTE::DEVICE.bits();
+        }
+    }
+    attr
+}
+
+// A tree of translation tables. Dropping it frees every table page, but not
+// the pages that are mapped.
+//
+// User address spaces are tagged with an ASID once they are activated and
+// their user pages are non-global, so switching TTBR0 doesn't flush the TLB.
+// The kernel address space always uses ASID 0.
+pub struct AddressSpace {
+    root: *mut PageTable,
+    kernel: bool,
+    asid: Option<Asid>,
+    // leading root entries that point to the kernel's tables
+    shared_entries: usize,
+}
+
+unsafe impl Sync for AddressSpace {}
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
     pub fn new() -> Self {
         Self {
-            root_tables: HashMap::new(),
+            root: PageTable::create(),
+            kernel: false,
+            asid: None,
+            shared_entries: 0,
         }
     }
 
-    pub fn create_table(&self) -> *mut PageTable {
-        assert_eq!(size_of::<PageTable>(), 4096);
+    pub fn new_kernel() -> Self {
+        Self {
+            kernel: true,
+            ..Self::new()
+        }
+    }
 
-        let table = unsafe {
-            let layout =
-                Layout::from_size_align(size_of::<PageTable>(), size_of::<PageTable>()).unwrap();
-            let table_ptr = alloc_zeroed(layout) as *mut PageTable;
-            // aligned?
-            assert_eq!(table_ptr as usize & 0xfff, 0);
-            table_ptr
-        };
-        table
+    pub fn root(&self) -> *mut PageTable {
+        self.root
     }
 
-    pub fn get_attr(&self, paddr: usize) -> usize {
-        let mut attr = PTE::NORMAL_CACHEABLE.bits();
-        if cfg!(target_board = "raspi3b") {
-            use crate::device::raspi3b::base::*;
-            if MMIO_BASE <= paddr && paddr < (MMIO_BASE + MMIO_SIZE) {
-                attr = PTE::DEVICE.bits();
+    // The ASID this address space may have entries in the TLB under.
+    pub fn asid(&self) -> Option<usize> {
+        if self.kernel {
+            return Some(KERNEL_ASID);
+        }
+        let asid = self.asid?;
+        if unsafe { ASID_ALLOCATOR.is_current(&asid) } {
+            Some(asid.id())
+        } else {
+            None
+        }
+    }
+
+    pub fn make_ttbr0(&self) -> usize {
+        let asid = self.asid().unwrap_or(KERNEL_ASID);
+        (asid << TTBR_ASID_SHIFT) | self.root as usize
+    }
+
+    // Makes sure the address space owns an ASID of the current generation and
+    // returns the TTBR0_EL1 value to switch to it.
+    pub fn activate(&mut self) -> usize {
+        if self.asid().is_none() {
+            let (asid, rollover) = unsafe { ASID_ALLOCATOR.alloc() };
+            if rollover {
+                invalidate_all();
+            }
+            self.asid = Some(asid);
+        }
+        // a rollover on another core leaves the ASID this core runs with alone
+        if let Some(asid) = self.asid {
+            unsafe {
+                ASID_ALLOCATOR.set_active(cpu_id(), asid);
             }
         }
-        attr
+        self.make_ttbr0()
     }
 
-    pub fn map_device_memory(&self, table: *mut PageTable) -> Result<(), VMError> {
-        #[cfg(target_board = "raspi3b")]
-        {
-            use crate::device::raspi3b::base::*;
-            for page in (MMIO_BASE..(MMIO_BASE + MMIO_SIZE)).step_by(PAGE_SIZE) {
-                assert!(page % PAGE_SIZE == 0);
-                self.map_page(
-                    table,
-                    page,
-                    page,
-                    true,
-                    true,
-                    false,
-                    false,
-                    PTE::DEVICE.bits(),
-                )?;
+    fn invalidate_page(&self, vaddr: usize) {
+        let page = (vaddr >> 12) & 0xfff_ffff_ffff;
+        unsafe {
+            asm!("dsb ishst");
+            if self.kernel {
+                // kernel mappings may be cached under any ASID
+                asm!("tlbi vaae1is, {}", in(reg)page);
+            } else if let Some(asid) = self.asid() {
+                asm!("tlbi vae1is, {}", in(reg)((asid << TTBR_ASID_SHIFT) | page));
             }
+            asm!("dsb ish");
+            asm!("isb");
         }
-        Ok(())
     }
 
-    pub fn walk(&self, name: &str, vaddr: usize) -> Result<usize, VMError> {
-        use crate::*;
-        let mut table = unsafe { self.get_table(name).as_mut().unwrap() };
-        let vaddr_page = vaddr & !0xfff_usize;
-        println!("vaddr_page: {:#x}", vaddr_page);
-        let indexes = vec![
-            (vaddr_page >> 12) & 0x1ff,
-            (vaddr_page >> 21) & 0x1ff,
-            (vaddr_page >> 30) & 0x1ff,
-        ];
+    // Returns the last-level table that covers `vaddr`. Blocks on the way are
+    // split into tables that keep the same mapping. Missing tables are created
+    // if `create` is set, otherwise the walk stops there.
+    fn walk(&mut self, vaddr: usize, create: bool) -> Option<&mut PageTable> {
+        let mut table = unsafe { self.root.as_mut().unwrap() };
         for level in (1..LEVELS).rev() {
-            let entry = table.get_entry(indexes[level]);
-            if entry.is_invalid() {
-                return Err(VMError::NotFound);
+            let entry = table.get_entry(table_index(vaddr, level));
+            if entry.is_invalid() && !create {
+                return None;
             }
-            if entry.is_block() {
-                let mask = (1 << (12 + 9 * level)) - 1;
-                return Ok((entry.get_oa() & !mask) + (vaddr & mask));
+            if entry.is_block() || entry.is_invalid() {
+                let new_table = PageTable::create();
+                if entry.is_block() {
+                    unsafe {
+                        new_table.as_mut().unwrap().split(level - 1, entry);
+                    }
+                }
+                let mut new_entry = Entry::default();
+                new_entry.as_table();
+                new_entry.set_oa(new_table as usize);
+                table.update_entry(table_index(vaddr, level), new_entry);
+                table = unsafe { new_table.as_mut().unwrap() };
+            } else {
+                table = unsafe { (entry.get_oa() as *mut PageTable).as_mut().unwrap() };
             }
-            // next page table
-            table = unsafe {
-                ((entry.get_oa() & PTE::OA.bits()) as *mut PageTable)
-                    .as_mut()
-                    .unwrap()
-            };
         }
-        Ok((table.get_entry(indexes[0]).get_oa() & PTE::OA.bits()) + (vaddr & 0xfff))
+        Some(table)
     }
 
-    pub fn map_page(
-        &self,
-        table: *mut PageTable,
+    pub fn map_range(
+        &mut self,
         paddr: usize,
         vaddr: usize,
+        size: usize,
+        r: bool,
+        w: bool,
+        x: bool,
+        u: bool,
+    ) -> Result<(), VMError> {
+        self.map_range_with_attr(paddr, vaddr, size, r, w, x, u, memory_attr(paddr))
+    }
+
+    pub fn map_range_with_attr(
+        &mut self,
+        paddr: usize,
+        vaddr: usize,
+        size: usize,
         r: bool,
         w: bool,
         x: bool,
         u: bool,
         attr: usize,
     ) -> Result<(), VMError> {
-        let mut table = unsafe { table.as_mut().unwrap() };
-        let indexes = vec![
-            (vaddr >> 12) & 0x1ff,
-            (vaddr >> 21) & 0x1ff,
-            (vaddr >> 30) & 0x1ff,
-        ];
-        unsafe {
-            for level in (1..LEVELS).rev() {
-                let entry = table.get_entry(indexes[level]);
-                if entry.is_block() || entry.is_invalid() {
-                    let new_table = self.create_table();
-                    let old = if entry.is_invalid() {
-                        0
-                    } else {
-                        entry.get_oa()
-                    };
-                    let child = level - 1;
-                    new_table.as_mut().unwrap().identity_mapping(child, old);
-                    let mut new_entry = Entry::default();
-                    new_entry.as_table();
-                    new_entry.set_oa(new_table as usize);
-                    table.update_entry(indexes[level], new_entry);
-                    table = new_table.as_mut().unwrap();
-                } else {
-                    let new_table = (table.get_entry(indexes[level]).get_oa()) as *mut PageTable;
-                    table = new_table.as_mut().unwrap();
-                }
+        check_aligned(&[paddr, vaddr, size])?;
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let table = self.walk(vaddr + offset, true).unwrap();
+            let mut entry = Entry::default();
+            entry.as_page();
+            entry.set_flags(r, w, x, u);
+            entry.set_oa(paddr + offset);
+            entry.set_attr(attr);
+            entry.set_af();
+            if u {
+                entry.set_ng();
+            }
+            table.update_entry(table_index(vaddr + offset, 0), entry);
+            self.invalidate_page(vaddr + offset);
+        }
+        Ok(())
+    }
+
+    pub fn unmap_range(&mut self, vaddr: usize, size: usize) -> Result<(), VMError> {
+        check_aligned(&[vaddr, size])?;
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            if let Some(table) = self.walk(vaddr + offset, false) {
+                table.update_entry(table_index(vaddr + offset, 0), Entry::default());
+                self.invalidate_page(vaddr + offset);
             }
-            let mut new_entry = Entry::default();
-            new_entry.as_page();
-            new_entry.set_flags(r, w, x, u);
-            new_entry.set_oa(paddr);
-            new_entry.set_attr(attr);
-            new_entry.set_af();
-            table.update_entry(indexes[0], new_entry);
         }
         Ok(())
     }
 
-    pub fn map(
+    pub fn protect_range(
         &mut self,
-        name: &str,
-        paddr: usize,
         vaddr: usize,
+        size: usize,
         r: bool,
         w: bool,
         x: bool,
         u: bool,
     ) -> Result<(), VMError> {
-        assert!(paddr & 0xfff == 0);
-        assert!(vaddr & 0xfff == 0);
-        let table = self.get_table(name);
-        self.map_page(table, paddr, vaddr, r, w, x, u, self.get_attr(paddr))?;
+        check_aligned(&[vaddr, size])?;
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let table = self.walk(vaddr + offset, false).ok_or(VMError::NotFound)?;
+            let mut entry = table.get_entry(table_index(vaddr + offset, 0));
+            if entry.is_invalid() {
+                return Err(VMError::NotFound);
+            }
+            entry.clear_flags();
+            entry.set_flags(r, w, x, u);
+            table.update_entry(table_index(vaddr + offset, 0), entry);
+            self.invalidate_page(vaddr + offset);
+        }
         Ok(())
     }
 
-    pub fn get_table(&self, name: &str) -> *mut PageTable {
-        *self.root_tables.get(name).unwrap()
+    // Returns the page or block entry that maps `vaddr` and its level.
+    fn leaf(&self, vaddr: usize) -> Option<(Entry, usize)> {
+        let mut table = unsafe { self.root.as_ref().unwrap() };
+        for level in (0..LEVELS).rev() {
+            let entry = table.get_entry(table_index(vaddr, level));
+            if entry.is_invalid() {
+                return None;
+            }
+            if level == 0 || entry.is_block() {
+                return Some((entry, level));
+            }
+            // next page table
+            table = unsafe { (entry.get_oa() as *const PageTable).as_ref().unwrap() };
+        }
+        None
+    }
+
+    // Returns the physical address `vaddr` is mapped to.
+    pub fn translate(&self, vaddr: usize) -> Option<usize> {
+        let (entry, level) = self.leaf(vaddr)?;
+        let mask = (1 << (12 + 9 * level)) - 1;
+        Some((entry.get_oa() & !mask) + (vaddr & mask))
+    }
+
+    // Returns whether `vaddr` is mapped readable, writable and executable.
+    pub fn permissions(&self, vaddr: usize) -> Option<(bool, bool, bool)> {
+        let (entry, _) = self.leaf(vaddr)?;
+        Some((true, entry.is_writable(), entry.is_executable()))
+    }
+
+    // Returns whether `vaddr` is mapped executable at EL0.
+    pub fn user_executable(&self, vaddr: usize) -> Option<bool> {
+        let (entry, _) = self.leaf(vaddr)?;
+        Some(entry.is_user_executable())
+    }
+}
+
+// ASIDs are not reused before the next rollover, which flushes the whole TLB,
+// so the entries left behind by a dropped address space are never hit.
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        unsafe {
+            let root = self.root.as_mut().unwrap();
+            for i in 0..self.shared_entries {
+                root.update_entry(i, Entry::default());
+            }
+            PageTable::destroy(self.root, LEVELS - 1);
+        }
+    }
+}
+
+pub struct VMManager {
+    kernel: AddressSpace,
+}
+
+unsafe impl Sync for VMManager {}
+unsafe impl Send for VMManager {}
+
+impl VMManager {
+    pub fn new() -> Self {
+        Self {
+            kernel: AddressSpace::new_kernel(),
+        }
+    }
+
+    pub fn kernel_space(&mut self) -> &mut AddressSpace {
+        &mut self.kernel
     }
 
-    pub fn set_table(&mut self, name: &str, table: *mut PageTable) {
-        self.root_tables.insert(name.to_string(), table);
+    // An address space for a task: the kernel as in kernel_space(), and
+    // nothing in user space yet.
+    pub fn new_user_space(&self) -> AddressSpace {
+        let mut space = AddressSpace::new();
+        let kernel_root = unsafe { self.kernel.root.as_ref().unwrap() };
+        let root = unsafe { space.root.as_mut().unwrap() };
+        for i in 0..KERNEL_ENTRIES {
+            let entry = kernel_root.get_entry(i);
+            // a block would be copied, and miss later changes to the kernel
+            assert!(entry.is_table());
+            root.update_entry(i, entry);
+        }
+        space.shared_entries = KERNEL_ENTRIES;
+        space
+    }
+
+    pub fn map_device_memory(&mut self) -> Result<(), VMError> {
+        #[cfg(target_board = "raspi3b")]
+        {
+            use crate::device::raspi3b::base::*;
+            self.kernel.map_range_with_attr(
+                MMIO_BASE,
+                MMIO_BASE,
+                MMIO_SIZE,
+                true,
+                true,
+                false,
+                false,
+                PTE::DEVICE.bits(),
+            )?;
+            self.kernel.map_range_with_attr(
+                LOCAL_BASE,
+                LOCAL_BASE,
+                LOCAL_SIZE,
+                true,
+                true,
+                false,
+                false,
+                PTE::DEVICE.bits(),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Only .text is executable and only .data, .bss and the heap are writable
+    // in the kernel image.
+    fn protect_kernel_image(&mut self) -> Result<(), VMError> {
+        let text = address::_text_start as usize;
+        let rodata = address::_rodata_start as usize;
+        self.kernel.protect_range(
+            text,
+            address::_text_end as usize - text,
+            true,
+            false,
+            true,
+            false,
+        )?;
+        self.kernel.protect_range(
+            rodata,
+            address::_rodata_end as usize - rodata,
+            true,
+            false,
+            false,
+            false,
+        )
     }
 
     pub fn init(&mut self) {
         info!("Initialize VM Manager");
-        let root_table = self.create_table();
-        self.set_table("kernel", root_table);
-        assert!(self.root_tables.contains_key("kernel"));
+        let root_table = self.kernel.root();
         unsafe {
-            root_table.as_mut().unwrap().identity_mapping(3, 0);
+            root_table.as_mut().unwrap().identity_mapping(LEVELS - 1);
         }
-        self.map_device_memory(root_table).unwrap();
+        self.map_device_memory().unwrap();
+        self.protect_kernel_image().unwrap();
+        self.kernel
+            .unmap_range(address::_stack_guard as usize, PAGE_SIZE)
+            .unwrap();
 
         // Is root_table aligned to 2^12?
         assert_eq!(root_table as usize & 0xfff, 0);
+        unsafe { ASID_ALLOCATOR.set_bits(asid_bits()) };
+        self.enable_mmu();
+    }
 
+    // Register values that turn the MMU on with the kernel's mappings.
+    pub fn mmu_config(&self) -> MmuConfig {
         let mut tcr_el1: usize = 0;
         // T0SZ = 25 (The region size is 2^39)
         tcr_el1 |= 0x19;
@@ -346,6 +639,11 @@ impl VMManager {
         tcr_el1 |= 0b11 << 12;
         // EPD1: A TLB miss on an address that is translated using TTBR1_EL1 generates a Translation fault
         tcr_el1 |= 0b1 << 23;
+        // A1 = 0: TTBR0_EL1.ASID defines the ASID
+        if asid_bits() == 16 {
+            // AS: the upper 8 bits of TTBR0_EL1.ASID are used
+            tcr_el1 |= 0b1 << 36;
+        }
 
         let mair_el1: usize = MAIR_EL1;
         let mut sctlr_el1: usize = 0;
@@ -356,20 +654,57 @@ impl VMManager {
         // I=1  Enable instruction fetches to allocate into unified caches
         sctlr_el1 |= 0b1 << 12;
 
+        MmuConfig {
+            tcr_el1,
+            mair_el1,
+            ttbr0_el1: self.kernel.make_ttbr0(),
+            sctlr_el1,
+        }
+    }
+
+    fn enable_mmu(&self) {
+        let config = self.mmu_config();
         unsafe {
-            asm!("msr tcr_el1, {}", in(reg)tcr_el1);
-            asm!("msr mair_el1, {}", in(reg)mair_el1);
+            asm!("msr tcr_el1, {}", in(reg)config.tcr_el1);
+            asm!("msr mair_el1, {}", in(reg)config.mair_el1);
 
             asm!("dsb sy");
             asm!("isb");
             // Invalidate TLB
             asm!("tlbi vmalle1");
 
-            asm!("msr ttbr0_el1, {}", in(reg)root_table);
+            asm!("msr ttbr0_el1, {}", in(reg)config.ttbr0_el1);
 
             // Enable MMU
-            asm!("msr sctlr_el1, {}", in(reg)sctlr_el1);
+            asm!("msr sctlr_el1, {}", in(reg)config.sctlr_el1);
             asm!("isb");
         }
     }
 }
+
+// Read by the secondary cores' entry code, field by field.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct MmuConfig {
+    pub tcr_el1: usize,
+    pub mair_el1: usize,
+    pub ttbr0_el1: usize,
+    pub sctlr_el1: usize,
+}
+
+fn id_aa64mmfr0() -> usize {
+    let mmfr0: usize;
+    unsafe {
+        asm!("mrs {}, id_aa64mmfr0_el1", out(reg)mmfr0);
+    }
+    mmfr0
+}
+
+// ASIDBits: 0b0010 means 16 bits, otherwise 8 bits
+fn asid_bits() -> usize {
+    if (id_aa64mmfr0() >> 4) & 0xf == 0b0010 {
+        16
+    } else {
+        8
+    }
+}
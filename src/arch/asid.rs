@@ -0,0 +1,112 @@
+use crate::arch::{CpuId, MAX_CPUS};
+use crate::lazy::Lazy;
+use log::info;
+
+// ASID 0 is used by the kernel address space and never handed out.
+pub const KERNEL_ASID: usize = 0;
+
+pub static mut ASID_ALLOCATOR: Lazy<AsidAllocator> =
+    Lazy::<AsidAllocator, fn() -> AsidAllocator>::new(|| AsidAllocator::new());
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Asid {
+    id: usize,
+    generation: usize,
+}
+
+impl Asid {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+// Hands out ASIDs from a bump counter. When the counter runs out, a new
+// generation starts: every ASID of the old generation becomes stale and the
+// caller has to flush the whole TLB, on all cores, before using the new one.
+// Address spaces holding a stale ASID get a fresh one the next time they are
+// activated.
+//
+// Other cores keep running with the ASID they had at the rollover, so those
+// ASIDs are reserved: they stay valid in the new generation and are not
+// handed out again until the next rollover.
+pub struct AsidAllocator {
+    generation: usize,
+    next: usize,
+    // number of ASIDs the hardware supports
+    limit: usize,
+    rollovers: usize,
+    // the ASID each core last switched to
+    active: [Option<Asid>; MAX_CPUS],
+    reserved: [Option<Asid>; MAX_CPUS],
+}
+
+impl AsidAllocator {
+    pub fn new() -> Self {
+        Self {
+            generation: 1,
+            next: KERNEL_ASID + 1,
+            limit: 0,
+            rollovers: 0,
+            active: [None; MAX_CPUS],
+            reserved: [None; MAX_CPUS],
+        }
+    }
+
+    pub fn set_bits(&mut self, bits: usize) {
+        self.limit = 1 << bits;
+        info!("ASID: {} bits", bits);
+    }
+
+    // Without an ASID for every core to reserve plus one to hand out next to
+    // the kernel's, the TLB has to be flushed on every switch between address
+    // spaces.
+    pub fn is_supported(&self) -> bool {
+        self.limit > KERNEL_ASID + 1 + MAX_CPUS
+    }
+
+    pub fn is_current(&self, asid: &Asid) -> bool {
+        asid.generation == self.generation || self.reserved.contains(&Some(*asid))
+    }
+
+    // Records that `cpu` switched to an address space with `asid`.
+    pub fn set_active(&mut self, cpu: CpuId, asid: Asid) {
+        self.active[cpu] = Some(asid);
+    }
+
+    fn is_reserved(&self, id: usize) -> bool {
+        self.reserved.iter().flatten().any(|asid| asid.id == id)
+    }
+
+    pub fn rollovers(&self) -> usize {
+        self.rollovers
+    }
+
+    // Returns a fresh ASID. The flag is set if a new generation was started.
+    pub fn alloc(&mut self) -> (Asid, bool) {
+        let mut rollover = false;
+        let id = loop {
+            // without ASIDs, every address space is a generation of its own
+            if self.next >= self.limit || !self.is_supported() {
+                self.generation += 1;
+                self.next = KERNEL_ASID + 1;
+                self.rollovers += 1;
+                self.reserved = self.active;
+                rollover = true;
+            }
+            if !self.is_supported() {
+                break KERNEL_ASID;
+            }
+            self.next += 1;
+            if !self.is_reserved(self.next - 1) {
+                break self.next - 1;
+            }
+        };
+        (
+            Asid {
+                id,
+                generation: self.generation,
+            },
+            rollover,
+        )
+    }
+}
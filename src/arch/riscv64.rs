@@ -1,4 +1,5 @@
 pub mod address;
+pub mod clint;
 pub mod csr;
 pub mod plic;
 pub mod riscv;
@@ -11,6 +11,9 @@ extern "C" {
     pub fn _stack_end();
     pub fn _heap_start();
     pub fn _heap_end();
+    pub fn _ram_end();
+    pub fn _trap_stack_start();
+    pub fn _trap_stack_end();
 
     pub fn _clint_start();
     pub fn _clint_end();
@@ -22,4 +25,31 @@ extern "C" {
     pub fn _virtio_end();
 }
 
+// physical address
 pub const SIFIVE_TEST: usize = 0x100000;
+pub const GOLDFISH_RTC: usize = 0x101000;
+pub const CLINT: usize = 0x2000000;
+
+// Physical memory is mapped at PHYS_OFFSET in the upper half, and the kernel is
+// linked at its place in that direct map. User space gets the lower half.
+// Keep in sync with linker/virt.ld.
+pub const PHYS_OFFSET: usize = 0xffff_ffc0_0000_0000;
+pub const DIRECT_MAP_SIZE: usize = 128 << 30;
+
+// Each hart boots on its own BOOT_STACK_SIZE stack with an unmapped guard page
+// below it. Keep in sync with linker/virt.ld and boot.S.
+pub const MAX_HARTS: usize = 4;
+pub const BOOT_STACK_SIZE: usize = 0x8000;
+pub const BOOT_STACK_STRIDE: usize = BOOT_STACK_SIZE + crate::arch::PAGE_SIZE;
+
+pub fn boot_stack_guard(hart: usize) -> usize {
+    _stack_start as usize + hart * BOOT_STACK_STRIDE
+}
+
+// Returns the hart whose boot stack guard page contains `addr`.
+pub fn boot_stack_owner(addr: usize) -> Option<usize> {
+    (0..MAX_HARTS).find(|hart| {
+        let guard = boot_stack_guard(*hart);
+        guard <= addr && addr < guard + crate::arch::PAGE_SIZE
+    })
+}
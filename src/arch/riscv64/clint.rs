@@ -0,0 +1,64 @@
+use crate::arch::riscv64::address::{self, CLINT, MAX_HARTS};
+use crate::arch::riscv64::csr::*;
+use crate::arch::riscv64::riscv::TIMEBASE_FREQUENCY;
+use crate::time::ClockSource;
+use crate::timer::TICK_HZ;
+use core::arch::global_asm;
+
+global_asm!(include_str!("timervec.S"));
+
+extern "C" {
+    fn timer_vec();
+}
+
+// https://github.com/riscv/riscv-aclint/blob/main/riscv-aclint.adoc
+const MTIMECMP: usize = 0x4000;
+const MTIME: usize = 0xbff8;
+
+pub fn mtime() -> u64 {
+    unsafe { ((address::_clint_start as usize + MTIME) as *const u64).read_volatile() }
+}
+
+// The CLINT's mtime counter, read through MMIO.
+pub struct ClintMtime;
+
+impl ClockSource for ClintMtime {
+    fn name(&self) -> &'static str {
+        "clint-mtime"
+    }
+
+    fn read(&self) -> u64 {
+        mtime()
+    }
+
+    fn frequency(&self) -> u64 {
+        TIMEBASE_FREQUENCY
+    }
+}
+
+// Used by timer_vec: saved a1-a3, the address of mtimecmp, the interval.
+#[repr(C)]
+struct TimerScratch([usize; 5]);
+
+static mut TIMER_SCRATCH: [TimerScratch; MAX_HARTS] = [
+    TimerScratch([0; 5]),
+    TimerScratch([0; 5]),
+    TimerScratch([0; 5]),
+    TimerScratch([0; 5]),
+];
+
+// Starts the machine-mode timer interrupt of `hart`, TICK_HZ times a second.
+// Runs in machine mode before paging, so addresses are physical.
+pub unsafe fn timer_init(hart: usize) {
+    let interval = TIMEBASE_FREQUENCY / TICK_HZ;
+    let mtimecmp = (CLINT + MTIMECMP + 8 * hart) as *mut u64;
+    let now = ((CLINT + MTIME) as *const u64).read_volatile();
+    mtimecmp.write_volatile(now + interval);
+
+    let scratch = &mut TIMER_SCRATCH[hart].0;
+    scratch[3] = mtimecmp as usize;
+    scratch[4] = interval as usize;
+    Csr::Mscratch.write(scratch.as_ptr() as usize);
+    Csr::Mtvec.write(timer_vec as usize);
+    Csr::Mie.write(Csr::Mie.read() | Mie::MTIE.mask());
+}
@@ -89,6 +89,8 @@ pub enum Csr {
     Scause,
     Stval,
     Satp,
+
+    Time,
 }
 
 #[allow(unreachable_patterns)]
@@ -124,6 +126,7 @@ impl Csr {
                 Csr::Scause => asm!("csrr {}, scause", out(reg)val),
                 Csr::Stval => asm!("csrr {}, stval", out(reg)val),
                 Csr::Satp => asm!("csrr {}, satp", out(reg)val),
+                Csr::Time => asm!("csrr {}, time", out(reg)val),
                 _ => panic!("unimplemented csr: {:?}", *self),
             }
         }
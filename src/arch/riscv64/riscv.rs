@@ -5,6 +5,9 @@ use crate::lazy::Lazy;
 use const_default::ConstDefault;
 use core::arch::asm;
 
+// frequency of the time CSR on QEMU's virt machine
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
 pub static mut STATE: Lazy<CpuState> = Lazy::<CpuState, fn() -> CpuState>::new(|| CpuState::new());
 
 pub struct CpuState {
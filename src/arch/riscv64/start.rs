@@ -1,9 +1,32 @@
+use crate::arch::riscv64::address::{DIRECT_MAP_SIZE, PHYS_OFFSET};
+use crate::arch::riscv64::vm::{PagingMode, PAGING_MODE};
 use core::arch::asm;
 
 extern "C" {
     pub fn main();
 }
 
+#[repr(C, align(4096))]
+struct BootPageTable([usize; 512]);
+
+// Maps the direct map with Sv39 gigapages so that main() can run at the address
+// the kernel is linked at. VMManager::init replaces it with the kernel page
+// table, which uses PAGING_MODE.
+static mut BOOT_PAGE_TABLE: BootPageTable = BootPageTable([0; 512]);
+
+// Runs before paging, where symbol addresses are still physical.
+#[cfg(target_arch = "riscv64")]
+unsafe fn boot_page_table_init() -> usize {
+    // V | R | W | X
+    let flags = 0b1111;
+    let first = (PHYS_OFFSET >> 30) & 0x1ff;
+    for gigapage in 0..(DIRECT_MAP_SIZE >> 30) {
+        BOOT_PAGE_TABLE.0[first + gigapage] = ((gigapage << 30) >> 2) | flags;
+    }
+    // Sv39
+    (8 << 60) | (BOOT_PAGE_TABLE.0.as_ptr() as usize >> 12)
+}
+
 #[cfg(target_arch = "riscv64")]
 unsafe extern "C" fn pmp_init() {
     let pmpaddr0 = (!0_usize) >> 10;
@@ -39,13 +62,21 @@ pub unsafe extern "C" fn start() -> ! {
     sstatus |= 0b01 << Sstatus::FS.index();
     Csr::Sstatus.write(sstatus);
 
-    let mepc = main as usize;
+    // main runs in the direct map
+    let mepc = main as usize + PHYS_OFFSET;
     Csr::Mepc.write(mepc);
 
     pmp_init();
 
-    // disable paging
-    asm!("csrw satp, zero");
+    // allow supervisor mode to read the time CSR
+    Csr::Mcounteren.write(Csr::Mcounteren.read() | 0b10);
+
+    PAGING_MODE = PagingMode::probe();
+
+    // satp only takes effect once we are in supervisor mode
+    let satp = boot_page_table_init();
+    asm!("csrw satp, {}", in(reg)satp);
+    asm!("sfence.vma zero, zero");
 
     // delegate all interrupts and exceptions
     asm!("li t0, 0xffff");
@@ -53,9 +84,8 @@ pub unsafe extern "C" fn start() -> ! {
     asm!("li t0, 0xffff");
     asm!("csrw medeleg, t0");
 
-    // let mut mie = Csr::Mie.read();
-    // mie |= Mie::MTIE.mask();
-    // Csr::Mie.write(mie);
+    let hart = Csr::Mhartid.read();
+    arch::riscv64::clint::timer_init(hart);
 
     let mut sie = Csr::Sie.read();
     sie |= Sie::SEIE.mask();
@@ -65,9 +95,15 @@ pub unsafe extern "C" fn start() -> ! {
 
     asm!("csrr tp, mhartid");
 
-    Csr::Stvec.write(arch::riscv64::trap::kernel_vec as usize);
-
-    asm!("mret");
-
-    loop {}
+    Csr::Stvec.write(arch::riscv64::trap::kernel_vec as usize + PHYS_OFFSET);
+
+    // Move the boot stack into the direct map as well. main is the outermost
+    // frame, so it must not see our frame pointer.
+    asm!(
+        "add sp, sp, {}",
+        "li s0, 0",
+        "mret",
+        in(reg) PHYS_OFFSET,
+        options(noreturn)
+    );
 }
@@ -1,21 +1,22 @@
+use crate::allocator::frame::{alloc_frames_zeroed, free_frames};
+use crate::arch::asid::ASID_ALLOCATOR;
 use crate::arch::riscv64::csr::*;
 use crate::arch::riscv64::trap;
-use crate::arch::riscv64::vm;
-use crate::arch::riscv64::vm::PageTable;
-use crate::arch::PAGE_SIZE;
-use crate::error::TaskError;
+use crate::arch::riscv64::vm::AddressSpace;
+use crate::arch::{user_end, user_start, virt_to_phys, PAGE_SIZE};
+use crate::error::{TaskError, VMError};
 use crate::lazy::Lazy;
+use crate::task::stack::KernelStack;
 use crate::task::{ArchTaskManager, TaskId};
-use alloc::alloc::{alloc_zeroed, Layout};
-use alloc::format;
 use alloc::string::*;
 use core::arch::{asm, global_asm};
 use core::mem::size_of;
 use hashbrown::HashMap;
 
-pub const USER_CONTEXT: usize = 0x3f_ffff_e000;
+// Both are at the top of the upper half, out of the way of the direct map.
+pub const USER_CONTEXT: usize = 0xffff_ffff_ffff_e000;
 // virtual address of trampoline
-pub const TRAMPOLINE: usize = 0x3f_ffff_f000;
+pub const TRAMPOLINE: usize = 0xffff_ffff_ffff_f000;
 // max kernel stack size
 pub const KERNEL_STACK_SIZE: usize = 0x8000;
 
@@ -33,7 +34,7 @@ global_asm!(include_str!("trampoline.S"));
 extern "C" {
     pub fn trampoline();
     pub fn uservec();
-    pub fn userret(context: usize, satp: usize);
+    pub fn userret(context: usize, satp: usize, flush_tlb: usize);
 }
 
 pub struct TaskManager {
@@ -46,6 +47,17 @@ impl TaskManager {
             tasks: HashMap::new(),
         }
     }
+
+    pub fn user_context(&self, id: TaskId) -> Option<*mut UserContext> {
+        self.tasks.get(&id).map(|task| task.ucontext)
+    }
+}
+
+fn check_user_range(vaddr: usize, size: usize) -> Result<(), TaskError> {
+    if vaddr < user_start() || vaddr > user_end() || user_end() - vaddr < size {
+        return Err(TaskError::MapError(VMError::OutOfRange));
+    }
+    Ok(())
 }
 
 impl ArchTaskManager for TaskManager {
@@ -64,14 +76,18 @@ impl ArchTaskManager for TaskManager {
         // write virtual address of uservec to stvec
         Csr::Stvec.write(TRAMPOLINE + ((uservec as usize) - (trampoline as usize)));
         let task = self.tasks.get_mut(&current).unwrap();
-        let user_satp = vm::VM_MANAGER.make_satp(task.page_table_name.as_str());
+        let user_satp = task.address_space.activate();
+        // Without ASIDs, kernel and user entries share the TLB tag, so the
+        // trampoline has to flush on every switch.
+        let flush_tlb = !ASID_ALLOCATOR.is_supported() as usize;
         let mut tp: usize;
         asm!("mv {}, tp", out(reg)tp);
 
         (*task.ucontext).kernel_satp = Csr::Satp.read();
-        (*task.ucontext).kernel_sp = (task.kernel_stack as usize) + KERNEL_STACK_SIZE;
+        (*task.ucontext).kernel_sp = task.kernel_stack.top();
         (*task.ucontext).kernel_hartid = tp;
         (*task.ucontext).kernel_trap = trap::user_trap as usize;
+        (*task.ucontext).flush_tlb = flush_tlb;
 
         let mut sstatus = Csr::Sstatus.read();
         sstatus &= !Sstatus::SPP.mask();
@@ -81,9 +97,10 @@ impl ArchTaskManager for TaskManager {
         Csr::Sepc.write((*task.ucontext).epc);
 
         let fn_ret = TRAMPOLINE + ((userret as usize) - (trampoline as usize));
-        (core::mem::transmute::<*mut u8, fn(usize, usize) -> !>(fn_ret as *mut u8))(
+        (core::mem::transmute::<*mut u8, fn(usize, usize, usize) -> !>(fn_ret as *mut u8))(
             USER_CONTEXT,
             user_satp,
+            flush_tlb,
         );
     }
 
@@ -101,15 +118,41 @@ impl ArchTaskManager for TaskManager {
         Ok(())
     }
 
-    fn create_arch_task(&mut self, id: TaskId, name: String) {
-        let page_table_name = format!("{}.{}", name, id);
-        let page_table = unsafe {
-            let ptr = vm::VM_MANAGER.create_table();
-            vm::VM_MANAGER.set_table(page_table_name.clone(), ptr);
-            ptr
-        };
-        self.tasks
-            .insert(id, Task::new(id, name, page_table_name, page_table));
+    fn unmap(&mut self, id: TaskId, vaddr: usize, size: usize) -> Result<(), TaskError> {
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        check_user_range(vaddr, size)?;
+        task.address_space
+            .unmap_range(vaddr, size)
+            .map_err(|e| TaskError::MapError(e))
+    }
+
+    fn protect(
+        &mut self,
+        id: TaskId,
+        vaddr: usize,
+        size: usize,
+        r: bool,
+        w: bool,
+        x: bool,
+    ) -> Result<(), TaskError> {
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        check_user_range(vaddr, size)?;
+        task.address_space
+            .protect_range(vaddr, size, r, w, x, true)
+            .map_err(|e| TaskError::MapError(e))
+    }
+
+    fn translate(&self, id: TaskId, vaddr: usize) -> Option<usize> {
+        self.tasks.get(&id)?.address_space.translate(vaddr)
+    }
+
+    fn create_arch_task(&mut self, id: TaskId, name: String) -> Result<(), TaskError> {
+        self.tasks.insert(id, Task::new(id, name)?);
+        Ok(())
+    }
+
+    fn remove_arch_task(&mut self, id: TaskId) {
+        self.tasks.remove(&id);
     }
 
     fn init_start(&mut self, id: TaskId, start_address: usize) -> Result<(), TaskError> {
@@ -132,6 +175,10 @@ impl ArchTaskManager for TaskManager {
         }
         Ok(())
     }
+
+    fn kernel_stack(&self, id: TaskId) -> Option<&KernelStack> {
+        self.tasks.get(&id).map(|task| &task.kernel_stack)
+    }
 }
 
 // For the context switch in the kernel
@@ -166,7 +213,7 @@ pub struct UserContext {
     kernel_satp: usize,   // 0
     kernel_sp: usize,     // 8
     kernel_trap: usize,   // 16
-    epc: usize,           // 24
+    pub epc: usize,       // 24
     kernel_hartid: usize, // 32
     ra: usize,            // 40
     sp: usize,            // 48
@@ -177,14 +224,14 @@ pub struct UserContext {
     t2: usize,            // 88
     s0: usize,            // 96
     s1: usize,            // 104
-    a0: usize,            // 112
-    a1: usize,            // 120
-    a2: usize,            // 128
-    a3: usize,            // 136
-    a4: usize,            // 144
-    a5: usize,            // 152
-    a6: usize,            // 160
-    a7: usize,            // 168
+    pub a0: usize,        // 112
+    pub a1: usize,        // 120
+    pub a2: usize,        // 128
+    pub a3: usize,        // 136
+    pub a4: usize,        // 144
+    pub a5: usize,        // 152
+    pub a6: usize,        // 160
+    pub a7: usize,        // 168
     s2: usize,            // 176
     s3: usize,            // 184
     s4: usize,            // 192
@@ -193,77 +240,105 @@ pub struct UserContext {
     s7: usize,            // 216
     s8: usize,            // 224
     s9: usize,            // 232
-    s11: usize,           // 240
-    s12: usize,           // 248
+    s10: usize,           // 240
+    s11: usize,           // 248
     t3: usize,            // 256
     t4: usize,            // 264
     t5: usize,            // 272
     t6: usize,            // 280
+    flush_tlb: usize,     // 288
+}
+
+impl UserContext {
+    pub fn registers(&self) -> [(&'static str, usize); 32] {
+        [
+            ("pc", self.epc),
+            ("ra", self.ra),
+            ("sp", self.sp),
+            ("gp", self.gp),
+            ("tp", self.tp),
+            ("t0", self.t0),
+            ("t1", self.t1),
+            ("t2", self.t2),
+            ("s0", self.s0),
+            ("s1", self.s1),
+            ("a0", self.a0),
+            ("a1", self.a1),
+            ("a2", self.a2),
+            ("a3", self.a3),
+            ("a4", self.a4),
+            ("a5", self.a5),
+            ("a6", self.a6),
+            ("a7", self.a7),
+            ("s2", self.s2),
+            ("s3", self.s3),
+            ("s4", self.s4),
+            ("s5", self.s5),
+            ("s6", self.s6),
+            ("s7", self.s7),
+            ("s8", self.s8),
+            ("s9", self.s9),
+            ("s10", self.s10),
+            ("s11", self.s11),
+            ("t3", self.t3),
+            ("t4", self.t4),
+            ("t5", self.t5),
+            ("t6", self.t6),
+        ]
+    }
 }
 
 #[allow(dead_code)]
 pub struct Task {
     id: TaskId,
     name: String,
-    page_table_name: String,
-    page_table: *mut PageTable,
-    kernel_stack: *mut u8,
+    address_space: AddressSpace,
+    kernel_stack: KernelStack,
     pub kcontext: KernelContext,
     pub ucontext: *mut UserContext,
 }
 
 impl Task {
-    pub fn new(
-        id: TaskId,
-        name: String,
-        page_table_name: String,
-        page_table: *mut PageTable,
-    ) -> Self {
+    pub fn new(id: TaskId, name: String) -> Result<Self, TaskError> {
         assert!(size_of::<UserContext>() <= PAGE_SIZE);
-        let ucontext = unsafe {
-            let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
-            alloc_zeroed(layout)
-        } as *mut UserContext;
-        unsafe {
-            vm::VM_MANAGER
-                .map(
-                    &page_table_name,
-                    trampoline as usize,
-                    TRAMPOLINE,
-                    true,
-                    true,
-                    true,
-                    false,
-                )
-                .unwrap();
-            vm::VM_MANAGER
-                .map(
-                    &page_table_name,
-                    ucontext as usize,
-                    USER_CONTEXT,
-                    true,
-                    true,
-                    false,
-                    false,
-                )
-                .unwrap();
-        }
-        let kernel_stack = unsafe {
-            let layout = Layout::from_size_align(KERNEL_STACK_SIZE, 0x1000).unwrap();
-            alloc_zeroed(layout)
-        };
-        Self {
+        let kernel_stack = KernelStack::new(KERNEL_STACK_SIZE).map_err(TaskError::MapError)?;
+        let ucontext =
+            alloc_frames_zeroed(0).expect("out of frames for UserContext") as *mut UserContext;
+        let mut address_space = AddressSpace::new();
+        address_space
+            .map_range(
+                virt_to_phys(trampoline as usize),
+                TRAMPOLINE,
+                PAGE_SIZE,
+                true,
+                false,
+                true,
+                false,
+            )
+            .unwrap();
+        address_space
+            .map_range(
+                virt_to_phys(ucontext as usize),
+                USER_CONTEXT,
+                PAGE_SIZE,
+                true,
+                true,
+                false,
+                false,
+            )
+            .unwrap();
+        let sp = kernel_stack.top();
+        Ok(Self {
             id,
             name,
-            page_table_name,
-            page_table,
+            address_space,
             kernel_stack,
             kcontext: KernelContext {
-                sp: kernel_stack as usize + KERNEL_STACK_SIZE,
+                sp,
                 ..Default::default()
             },
             ucontext,
-        }
+        })
     }
 
     pub fn map(
@@ -274,8 +349,16 @@ impl Task {
         w: bool,
         x: bool,
     ) -> Result<(), TaskError> {
-        unsafe { vm::VM_MANAGER.map(self.page_table_name.as_str(), paddr, vaddr, r, w, x, true) }
+        check_user_range(vaddr, PAGE_SIZE)?;
+        self.address_space
+            .map_range(paddr, vaddr, PAGE_SIZE, r, w, x, true)
             .map_err(|e| TaskError::MapError(e))?;
         Ok(())
     }
 }
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        free_frames(self.ucontext as usize, 0);
+    }
+}
@@ -2,6 +2,7 @@ use crate::arch::riscv64::csr::*;
 use crate::arch::riscv64::*;
 use crate::device::common::uart::UART;
 use crate::device::common::virtio::block;
+use crate::task::ArchTaskManager;
 use crate::*;
 use core::arch::global_asm;
 
@@ -11,41 +12,237 @@ extern "C" {
     pub fn kernel_vec();
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn user_trap() -> ! {
-    println!("user_trap: {:#x}", Csr::Stval.read());
-    println!("scause: {:#x}", Csr::Scause.read());
-    loop {}
+// Set in scause for interrupts
+pub const INTERRUPT: usize = 1 << 63;
+
+// exception codes
+pub const INSTRUCTION_ACCESS_FAULT: usize = 1;
+pub const ECALL_FROM_U: usize = 8;
+pub const INSTRUCTION_PAGE_FAULT: usize = 12;
+pub const LOAD_PAGE_FAULT: usize = 13;
+pub const STORE_PAGE_FAULT: usize = 15;
+
+// interrupt codes
+pub const SUPERVISOR_SOFTWARE: usize = 1;
+pub const SUPERVISOR_EXTERNAL: usize = 9;
+
+pub fn cause_name(scause: usize) -> &'static str {
+    if scause & INTERRUPT != 0 {
+        return match scause & !INTERRUPT {
+            1 => "supervisor software interrupt",
+            5 => "supervisor timer interrupt",
+            9 => "supervisor external interrupt",
+            _ => "unknown interrupt",
+        };
+    }
+    match scause {
+        0 => "instruction address misaligned",
+        1 => "instruction access fault",
+        2 => "illegal instruction",
+        3 => "breakpoint",
+        4 => "load address misaligned",
+        5 => "load access fault",
+        6 => "store/AMO address misaligned",
+        7 => "store/AMO access fault",
+        8 => "environment call from U-mode",
+        9 => "environment call from S-mode",
+        12 => "instruction page fault",
+        13 => "load page fault",
+        15 => "store/AMO page fault",
+        _ => "unknown exception",
+    }
+}
+
+// Registers saved by kernel_vec
+#[repr(C)]
+pub struct TrapFrame {
+    ra: usize,
+    sp: usize,
+    gp: usize,
+    tp: usize,
+    t0: usize,
+    t1: usize,
+    t2: usize,
+    s0: usize,
+    s1: usize,
+    a0: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+    a5: usize,
+    a6: usize,
+    a7: usize,
+    s2: usize,
+    s3: usize,
+    s4: usize,
+    s5: usize,
+    s6: usize,
+    s7: usize,
+    s8: usize,
+    s9: usize,
+    s10: usize,
+    s11: usize,
+    t3: usize,
+    t4: usize,
+    t5: usize,
+    t6: usize,
+}
+
+impl TrapFrame {
+    pub fn registers(&self) -> [(&'static str, usize); 31] {
+        [
+            ("ra", self.ra),
+            ("sp", self.sp),
+            ("gp", self.gp),
+            ("tp", self.tp),
+            ("t0", self.t0),
+            ("t1", self.t1),
+            ("t2", self.t2),
+            ("s0", self.s0),
+            ("s1", self.s1),
+            ("a0", self.a0),
+            ("a1", self.a1),
+            ("a2", self.a2),
+            ("a3", self.a3),
+            ("a4", self.a4),
+            ("a5", self.a5),
+            ("a6", self.a6),
+            ("a7", self.a7),
+            ("s2", self.s2),
+            ("s3", self.s3),
+            ("s4", self.s4),
+            ("s5", self.s5),
+            ("s6", self.s6),
+            ("s7", self.s7),
+            ("s8", self.s8),
+            ("s9", self.s9),
+            ("s10", self.s10),
+            ("s11", self.s11),
+            ("t3", self.t3),
+            ("t4", self.t4),
+            ("t5", self.t5),
+            ("t6", self.t6),
+        ]
+    }
+}
+
+// Fetches the instruction at `epc` through `read`, which returns the halfword
+// at an address if it can be read. Compressed instructions are 16 bits long.
+pub fn instruction(epc: usize, read: impl Fn(usize) -> Option<u16>) -> Option<u32> {
+    let low = read(epc)? as u32;
+    if low & 0b11 != 0b11 {
+        return Some(low);
+    }
+    Some(low | (read(epc + 2)? as u32) << 16)
+}
+
+fn is_fetch_fault(scause: usize) -> bool {
+    scause == INSTRUCTION_ACCESS_FAULT || scause == INSTRUCTION_PAGE_FAULT
+}
+
+unsafe fn handle_interrupt(scause: usize) {
+    // timer_vec passes timer ticks on as software interrupts
+    if scause & !INTERRUPT == SUPERVISOR_SOFTWARE {
+        Csr::Sip.write(Csr::Sip.read() & !Sie::SSIE.mask());
+        timer::poll();
+        crate::task::TASK_MANAGER.tick();
+        return;
+    }
+    if scause & !INTERRUPT != SUPERVISOR_EXTERNAL {
+        panic!("unexpected {}", cause_name(scause));
+    }
+    let irq = plic::PLIC_MANAGER.read_claim();
+    if irq as usize == plic::PlicIRQ::Uart0 as usize {
+        UART.interrupt();
+    } else if irq as usize == plic::PlicIRQ::VirtIO0 as usize {
+        block::VIRTIO_BLOCK.interrupt();
+    } else {
+        panic!("Unknown interrupt irq: {}", irq);
+    }
+
+    plic::PLIC_MANAGER.send_complete(irq);
 }
 
-// TODO: refine
 #[no_mangle]
-pub unsafe extern "C" fn kernel_trap() {
+pub unsafe extern "C" fn user_trap() -> ! {
+    // stvec still points at uservec
+    Csr::Stvec.write(kernel_vec as usize);
+    crate::task::TASK_MANAGER.enter_kernel();
+    let id = crate::task::TASK_MANAGER.current();
+    let context = super::task::ARCH_TASK_MANAGER.user_context(id).unwrap();
+    // uservec does not save sepc
+    (*context).epc = Csr::Sepc.read();
     let scause = Csr::Scause.read();
-    if scause & (1 << 63) == 0 {
-        // exception
-        match scause {
-            12 | 13 | 15 => {
-                println!("Page Fault occurred");
-            }
-            _ => {
-                println!("Exception occurred");
+    if scause & INTERRUPT != 0 {
+        handle_interrupt(scause);
+        KERNEL_LOCK.complete_intr();
+    } else if scause == ECALL_FROM_U {
+        // return past the ecall
+        (*context).epc += 4;
+        let args = [
+            (*context).a0,
+            (*context).a1,
+            (*context).a2,
+            (*context).a3,
+            (*context).a4,
+            (*context).a5,
+        ];
+        (*context).a0 = syscall::syscall((*context).a7, args) as usize;
+    } else {
+        let epc = (*context).epc;
+        let stval = Csr::Stval.read();
+        arch::dump_registers(&(*context).registers());
+        if !is_fetch_fault(scause) {
+            let read = |addr: usize| {
+                super::task::ARCH_TASK_MANAGER
+                    .translate(id, addr)
+                    .map(|paddr| *(arch::phys_to_virt(paddr) as *const u16))
+            };
+            if let Some(instruction) = instruction(epc, read) {
+                println!("instruction: {:#x}", instruction);
             }
         }
+        crate::task::kill_current(format_args!(
+            "{} at {:#x}, address {:#x}",
+            cause_name(scause),
+            epc,
+            stval
+        ));
+    }
+    crate::task::user_entry();
+}
 
-        loop {}
-    } else if scause & 0x8000000000000000 != 0 && scause & 0xff == 9 {
-        let irq = plic::PLIC_MANAGER.read_claim();
-        if irq as usize == plic::PlicIRQ::Uart0 as usize {
-            UART.interrupt();
-        } else if irq as usize == plic::PlicIRQ::VirtIO0 as usize {
-            block::VIRTIO_BLOCK.interrupt();
-        } else {
-            panic!("Unknown interrupt irq: {}", irq);
-        }
+#[no_mangle]
+pub unsafe extern "C" fn kernel_trap(frame: &TrapFrame) {
+    let scause = Csr::Scause.read();
+    if scause & INTERRUPT != 0 {
+        handle_interrupt(scause);
+        KERNEL_LOCK.complete_intr();
+        return;
+    }
 
-        plic::PLIC_MANAGER.send_complete(irq);
+    let sepc = Csr::Sepc.read();
+    let stval = Csr::Stval.read();
+    if scause == INSTRUCTION_PAGE_FAULT || scause == LOAD_PAGE_FAULT || scause == STORE_PAGE_FAULT {
+        if let Some(hart) = address::boot_stack_owner(stval) {
+            panic!("stack overflow in boot stack of hart {}", hart);
+        }
+        crate::task::check_stack_overflow(stval);
     }
 
-    KERNEL_LOCK.complete_intr();
+    crashdump::begin();
+    arch::dump_registers(&frame.registers());
+    if !is_fetch_fault(scause) {
+        if let Some(instruction) = instruction(sepc, |addr| Some(*(addr as *const u16))) {
+            println!("instruction: {:#x}", instruction);
+        }
+    }
+    backtrace::print_backtrace_at(Some(sepc), frame.s0);
+    panic!(
+        "kernel {} at {:#x}, address {:#x}",
+        cause_name(scause),
+        sepc,
+        stval
+    );
 }
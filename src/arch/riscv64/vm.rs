@@ -1,16 +1,23 @@
+use crate::allocator::frame::{alloc_frames_zeroed, free_frames};
+use crate::arch::asid::{Asid, ASID_ALLOCATOR, KERNEL_ASID};
+use crate::arch::riscv64::address::{
+    self, boot_stack_guard, DIRECT_MAP_SIZE, MAX_HARTS, PHYS_OFFSET,
+};
 use crate::arch::riscv64::csr::*;
 use crate::arch::riscv64::task::{trampoline, TRAMPOLINE};
+use crate::arch::{cpu_id, phys_to_virt, virt_to_phys, PAGE_SIZE};
 use crate::error::VMError;
 use crate::lazy::Lazy;
-use alloc::alloc::*;
-use alloc::string::{String, ToString};
-use alloc::vec;
 use bitflags::bitflags;
 use core::arch::asm;
-use hashbrown::HashMap;
 use log::info;
 
-pub const LEVELS: usize = 3;
+const SATP_MODE_SHIFT: usize = 60;
+const SATP_ASID_SHIFT: usize = 44;
+const SATP_ASID_MASK: usize = 0xffff << SATP_ASID_SHIFT;
+
+// Probed by start() before anything is mapped.
+pub static mut PAGING_MODE: PagingMode = PagingMode::Sv39;
 
 pub static mut VM_MANAGER: Lazy<VMManager> =
     Lazy::<VMManager, fn() -> VMManager>::new(|| VMManager::new());
@@ -26,6 +33,40 @@ bitflags! {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PagingMode {
+    Sv39 = 8,
+    Sv48 = 9,
+}
+
+impl PagingMode {
+    // Picks the largest mode the hart supports. satp ignores writes of
+    // unsupported modes. This has to run in machine mode, where satp doesn't
+    // affect translation.
+    pub unsafe fn probe() -> Self {
+        Csr::Satp.write((PagingMode::Sv48 as usize) << SATP_MODE_SHIFT);
+        let mode = Csr::Satp.read() >> SATP_MODE_SHIFT;
+        Csr::Satp.write(0);
+        if mode == PagingMode::Sv48 as usize {
+            PagingMode::Sv48
+        } else {
+            PagingMode::Sv39
+        }
+    }
+
+    pub fn levels(&self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+        }
+    }
+
+    // User space is the lower half of the address space.
+    pub fn user_end(&self) -> usize {
+        1 << (12 + 9 * self.levels() - 1)
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 pub struct Entry(usize);
@@ -71,6 +112,10 @@ impl Entry {
         self.set_flags(true, false, false, false, false);
     }
 
+    pub fn clear_permissions(&mut self) {
+        self.0 &= !(PTE::R | PTE::W | PTE::X | PTE::U).bits();
+    }
+
     pub fn set_flags(&mut self, v: bool, r: bool, w: bool, x: bool, u: bool) {
         if v {
             self.0 |= PTE::V.bits();
@@ -96,6 +141,11 @@ impl Entry {
     pub fn get_ppn(&self) -> usize {
         self.0 & PTE::PPN.bits()
     }
+
+    // Page tables are reached through the direct map.
+    pub fn next_table(&self) -> *mut PageTable {
+        phys_to_virt(self.get_ppn() << 2) as *mut PageTable
+    }
 }
 
 #[repr(C)]
@@ -104,15 +154,32 @@ pub struct PageTable {
 }
 
 impl PageTable {
+    pub fn create() -> *mut PageTable {
+        alloc_frames_zeroed(0).expect("out of frames for page tables") as *mut PageTable
+    }
+
+    // Frees `table` and every table below it. The pages mapped by leaf entries
+    // are not touched.
+    pub unsafe fn destroy(table: *mut PageTable, level: usize) {
+        if level > 0 {
+            for entry in (*table).entries.iter() {
+                if entry.is_valid() && entry.is_next_ptr() {
+                    Self::destroy(entry.next_table(), level - 1);
+                }
+            }
+        }
+        free_frames(table as usize, 0);
+    }
+
     pub fn size(&self) -> usize {
         self.entries.len()
     }
 
-    pub fn identity_mapping(&mut self, level: usize, old_ppn: usize) {
+    // Fills the table with `level` pages that together map the same range,
+    // with the same permissions, as the superpage `leaf`.
+    pub fn split(&mut self, level: usize, leaf: Entry) {
         for i in 0..self.size() {
-            let entry = &mut self.entries[i];
-            entry.set_ppn((i << (10 + 9 * level)) | old_ppn);
-            entry.set_flags(true, true, true, true, false);
+            self.entries[i] = Entry(leaf.0 + (i << (10 + 9 * level)));
         }
     }
 
@@ -127,121 +194,324 @@ impl PageTable {
     }
 }
 
-pub struct VMManager {
-    root_tables: HashMap<String, *mut PageTable>,
+fn vpn(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (12 + 9 * level)) & 0x1ff
 }
 
-unsafe impl Sync for VMManager {}
-unsafe impl Send for VMManager {}
+// Flushes every TLB entry of this hart.
+pub fn flush_all() {
+    unsafe {
+        asm!("sfence.vma zero, zero");
+    }
+}
 
-impl VMManager {
+fn check_aligned(addrs: &[usize]) -> Result<(), VMError> {
+    if addrs.iter().any(|addr| addr & (PAGE_SIZE - 1) != 0) {
+        Err(VMError::Misaligned)
+    } else {
+        Ok(())
+    }
+}
+
+// A tree of page tables. Dropping it frees every table page, but not the
+// pages that are mapped.
+//
+// User address spaces are tagged with an ASID once they are activated, so
+// switching to and from them doesn't flush the TLB. The kernel address space
+// always uses ASID 0.
+pub struct AddressSpace {
+    root: *mut PageTable,
+    mode: PagingMode,
+    kernel: bool,
+    asid: Option<Asid>,
+}
+
+unsafe impl Sync for AddressSpace {}
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
     pub fn new() -> Self {
         Self {
-            root_tables: HashMap::new(),
+            root: PageTable::create(),
+            mode: unsafe { PAGING_MODE },
+            kernel: false,
+            asid: None,
+        }
+    }
+
+    pub fn new_kernel() -> Self {
+        Self {
+            kernel: true,
+            ..Self::new()
+        }
+    }
+
+    pub fn root(&self) -> *mut PageTable {
+        self.root
+    }
+
+    // The ASID this address space may have entries in the TLB under.
+    pub fn asid(&self) -> Option<usize> {
+        if self.kernel {
+            return Some(KERNEL_ASID);
+        }
+        let asid = self.asid?;
+        if unsafe { ASID_ALLOCATOR.is_current(&asid) } {
+            Some(asid.id())
+        } else {
+            None
+        }
+    }
+
+    pub fn make_satp(&self) -> usize {
+        let asid = self.asid().unwrap_or(KERNEL_ASID);
+        ((self.mode as usize) << SATP_MODE_SHIFT)
+            | (asid << SATP_ASID_SHIFT)
+            | (virt_to_phys(self.root as usize) >> 12)
+    }
+
+    // Makes sure the address space owns an ASID of the current generation and
+    // returns the satp value to switch to it.
+    pub fn activate(&mut self) -> usize {
+        if self.asid().is_none() {
+            let (asid, rollover) = unsafe { ASID_ALLOCATOR.alloc() };
+            if rollover {
+                flush_all();
+            }
+            self.asid = Some(asid);
+        }
+        // a rollover on another core leaves the ASID this core runs with alone
+        if let Some(asid) = self.asid {
+            unsafe {
+                ASID_ALLOCATOR.set_active(cpu_id(), asid);
+            }
+        }
+        self.make_satp()
+    }
+
+    fn flush_page(&self, vaddr: usize) {
+        if self.kernel {
+            // kernel mappings may be cached under any ASID
+            unsafe {
+                asm!("sfence.vma {}, zero", in(reg)vaddr);
+            }
+        } else if let Some(asid) = self.asid() {
+            unsafe {
+                asm!("sfence.vma {}, {}", in(reg)vaddr, in(reg)asid);
+            }
+        }
+    }
+
+    // Returns the table at `target` level that covers `vaddr`. Superpages on the way
+    // are split into tables that keep the same mapping. Missing tables are
+    // created if `create` is set, otherwise the walk stops there.
+    fn walk(&mut self, vaddr: usize, target: usize, create: bool) -> Option<&mut PageTable> {
+        let mut table = unsafe { self.root.as_mut().unwrap() };
+        for level in (target + 1..self.mode.levels()).rev() {
+            let entry = table.get_entry(vpn(vaddr, level));
+            if entry.is_invalid() && !create {
+                return None;
+            }
+            if entry.is_leaf() || entry.is_invalid() {
+                let new_table = PageTable::create();
+                if entry.is_leaf() {
+                    unsafe {
+                        new_table.as_mut().unwrap().split(level - 1, entry);
+                    }
+                }
+                let mut new_entry = Entry::new();
+                new_entry.as_next_ptr();
+                new_entry.set_ppn(virt_to_phys(new_table as usize) >> 2);
+                table.update_entry(vpn(vaddr, level), new_entry);
+                table = unsafe { new_table.as_mut().unwrap() };
+            } else {
+                table = unsafe { entry.next_table().as_mut().unwrap() };
+            }
         }
+        Some(table)
     }
 
-    pub fn map_page(
-        &self,
-        table: *mut PageTable,
+    pub fn map_range(
+        &mut self,
         paddr: usize,
         vaddr: usize,
+        size: usize,
         r: bool,
         w: bool,
         x: bool,
         u: bool,
     ) -> Result<(), VMError> {
-        let mut table = unsafe { table.as_mut().unwrap() };
-        let vpn = vec![
-            (vaddr >> 12) & 0x1ff,
-            (vaddr >> 21) & 0x1ff,
-            (vaddr >> 30) & 0x1ff,
-        ];
-        unsafe {
-            for level in (1..LEVELS).rev() {
-                let entry = table.get_entry(vpn[level]);
-                if entry.is_leaf() || entry.is_invalid() {
-                    let new_table = self.create_table();
-                    let ppn = if entry.is_invalid() {
-                        0
-                    } else {
-                        entry.get_ppn()
-                    };
-                    new_table.as_mut().unwrap().identity_mapping(level - 1, ppn);
-                    let mut new_entry = Entry::new();
-                    new_entry.as_next_ptr();
-                    new_entry.set_ppn((new_table as usize) >> 2);
-                    table.update_entry(vpn[level], new_entry);
-                    table = new_table.as_mut().unwrap();
-                } else {
-                    let new_table = (table.get_entry(vpn[level]).get_ppn() << 2) as *mut PageTable;
-                    table = new_table.as_mut().unwrap();
-                }
+        check_aligned(&[paddr, vaddr, size])?;
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let table = self.walk(vaddr + offset, 0, true).unwrap();
+            let mut entry = Entry::new();
+            entry.set_flags(true, r, w, x, u);
+            entry.set_ppn((paddr + offset) >> 2);
+            table.update_entry(vpn(vaddr + offset, 0), entry);
+            self.flush_page(vaddr + offset);
+        }
+        Ok(())
+    }
+
+    pub fn unmap_range(&mut self, vaddr: usize, size: usize) -> Result<(), VMError> {
+        check_aligned(&[vaddr, size])?;
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            if let Some(table) = self.walk(vaddr + offset, 0, false) {
+                table.update_entry(vpn(vaddr + offset, 0), Entry::new());
+                self.flush_page(vaddr + offset);
             }
-            let mut new_entry = Entry::new();
-            new_entry.set_flags(true, r, w, x, u);
-            new_entry.set_ppn(paddr >> 2);
-            table.update_entry(vpn[0], new_entry);
         }
         Ok(())
     }
 
-    pub fn map(
+    pub fn protect_range(
         &mut self,
-        name: &str,
-        paddr: usize,
         vaddr: usize,
+        size: usize,
         r: bool,
         w: bool,
         x: bool,
         u: bool,
     ) -> Result<(), VMError> {
-        assert!(paddr & 0xfff == 0);
-        assert!(vaddr & 0xfff == 0);
-        let table = self.get_table(name);
-        self.map_page(table, paddr, vaddr, r, w, x, u)?;
+        check_aligned(&[vaddr, size])?;
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let table = self
+                .walk(vaddr + offset, 0, false)
+                .ok_or(VMError::NotFound)?;
+            let mut entry = table.get_entry(vpn(vaddr + offset, 0));
+            if entry.is_invalid() {
+                return Err(VMError::NotFound);
+            }
+            entry.clear_permissions();
+            entry.set_flags(true, r, w, x, u);
+            table.update_entry(vpn(vaddr + offset, 0), entry);
+            self.flush_page(vaddr + offset);
+        }
         Ok(())
     }
 
-    pub fn create_table(&self) -> *mut PageTable {
+    // Returns the leaf entry that maps `vaddr` and its level.
+    fn leaf(&self, vaddr: usize) -> Option<(Entry, usize)> {
+        let mut table = unsafe { self.root.as_ref().unwrap() };
+        for level in (0..self.mode.levels()).rev() {
+            let entry = table.get_entry(vpn(vaddr, level));
+            if entry.is_invalid() {
+                return None;
+            }
+            if entry.is_leaf() {
+                return Some((entry, level));
+            }
+            table = unsafe { entry.next_table().as_ref().unwrap() };
+        }
+        None
+    }
+
+    // Returns the physical address `vaddr` is mapped to.
+    pub fn translate(&self, vaddr: usize) -> Option<usize> {
+        let (entry, level) = self.leaf(vaddr)?;
+        let mask = (1 << (12 + 9 * level)) - 1;
+        Some(((entry.get_ppn() << 2) & !mask) | (vaddr & mask))
+    }
+
+    // Returns whether `vaddr` is mapped readable, writable and executable.
+    pub fn permissions(&self, vaddr: usize) -> Option<(bool, bool, bool)> {
+        let (entry, _) = self.leaf(vaddr)?;
+        Some((
+            entry.is_readable(),
+            entry.is_writable(),
+            entry.is_executable(),
+        ))
+    }
+}
+
+// ASIDs are not reused before the next rollover, which flushes the whole TLB,
+// so the entries left behind by a dropped address space are never hit.
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
         unsafe {
-            let layout = Layout::from_size_align(0x1000, 0x1000).unwrap();
-            alloc_zeroed(layout) as *mut PageTable
+            PageTable::destroy(self.root, self.mode.levels() - 1);
         }
     }
+}
+
+pub struct VMManager {
+    kernel: AddressSpace,
+}
 
-    pub fn get_table(&self, name: &str) -> *mut PageTable {
-        **self.root_tables.get(name).as_mut().unwrap()
+unsafe impl Sync for VMManager {}
+unsafe impl Send for VMManager {}
+
+impl VMManager {
+    pub fn new() -> Self {
+        Self {
+            kernel: AddressSpace::new_kernel(),
+        }
     }
 
-    pub fn set_table(&mut self, name: String, table: *mut PageTable) {
-        self.root_tables.insert(name, table);
+    pub fn kernel_space(&mut self) -> &mut AddressSpace {
+        &mut self.kernel
     }
 
-    pub fn make_satp(&self, name: &str) -> usize {
-        // Sv39
-        (8 << 60) | (self.get_table(name) as usize >> 12)
+    // Only .text is executable and only .data, .bss and the heap are writable
+    // in the kernel image.
+    fn protect_kernel_image(&mut self) -> Result<(), VMError> {
+        let text = address::_text_start as usize;
+        let rodata = address::_rodata_start as usize;
+        self.kernel.protect_range(
+            text,
+            address::_text_end as usize - text,
+            true,
+            false,
+            true,
+            false,
+        )?;
+        self.kernel.protect_range(
+            rodata,
+            address::_rodata_end as usize - rodata,
+            true,
+            false,
+            false,
+            false,
+        )
     }
 
     pub fn init(&mut self) {
         info!("Initialize VM Manager");
-        let root_table = self.create_table();
-        self.set_table("kernel".to_string(), root_table);
-        unsafe {
-            let root_table = self.get_table("kernel").as_mut().unwrap();
-            root_table.identity_mapping(2, 0);
-            self.map(
-                "kernel",
-                trampoline as usize,
+        // Direct map of physical memory. It covers the kernel image and MMIO,
+        // so nothing in it is executable unless protect_kernel_image says so.
+        info!("Paging mode: {:?}", self.kernel.mode);
+        for gigapage in 0..(DIRECT_MAP_SIZE >> 30) {
+            let vaddr = PHYS_OFFSET + (gigapage << 30);
+            let table = self.kernel.walk(vaddr, 2, true).unwrap();
+            let mut entry = Entry::new();
+            entry.set_flags(true, true, true, false, false);
+            entry.set_ppn((gigapage << 30) >> 2);
+            table.update_entry(vpn(vaddr, 2), entry);
+        }
+        self.kernel
+            .map_range(
+                virt_to_phys(trampoline as usize),
                 TRAMPOLINE,
+                PAGE_SIZE,
                 true,
-                true,
+                false,
                 true,
                 false,
             )
             .unwrap();
-            Csr::Satp.write(self.make_satp("kernel"));
-            asm!("sfence.vma zero, zero");
+        self.protect_kernel_image().unwrap();
+        for hart in 0..MAX_HARTS {
+            self.kernel
+                .unmap_range(boot_stack_guard(hart), PAGE_SIZE)
+                .unwrap();
+        }
+        // Unsupported ASID bits read back as zero.
+        Csr::Satp.write(self.kernel.make_satp() | SATP_ASID_MASK);
+        let bits = ((Csr::Satp.read() & SATP_ASID_MASK) >> SATP_ASID_SHIFT).count_ones();
+        unsafe {
+            ASID_ALLOCATOR.set_bits(bits as usize);
         }
+        Csr::Satp.write(self.kernel.make_satp());
+        flush_all();
     }
 }
@@ -1 +1,2 @@
 pub mod task;
+pub mod tsc;
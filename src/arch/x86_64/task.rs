@@ -2,6 +2,7 @@
 
 use crate::error::*;
 use crate::lazy::Lazy;
+use crate::task::stack::KernelStack;
 use crate::task::{ArchTaskManager, TaskId};
 use alloc::string::String;
 
@@ -35,7 +36,31 @@ impl ArchTaskManager for TaskManager {
         Ok(())
     }
 
-    fn create_arch_task(&mut self, id: TaskId, name: String) {}
+    fn unmap(&mut self, id: TaskId, vaddr: usize, size: usize) -> Result<(), TaskError> {
+        Ok(())
+    }
+
+    fn protect(
+        &mut self,
+        id: TaskId,
+        vaddr: usize,
+        size: usize,
+        r: bool,
+        w: bool,
+        x: bool,
+    ) -> Result<(), TaskError> {
+        Ok(())
+    }
+
+    fn translate(&self, id: TaskId, vaddr: usize) -> Option<usize> {
+        None
+    }
+
+    fn create_arch_task(&mut self, id: TaskId, name: String) -> Result<(), TaskError> {
+        Ok(())
+    }
+
+    fn remove_arch_task(&mut self, id: TaskId) {}
 
     fn init_start(&mut self, id: TaskId, start_address: usize) -> Result<(), TaskError> {
         Ok(())
@@ -44,4 +69,8 @@ impl ArchTaskManager for TaskManager {
     fn init_user_entry(&mut self, id: TaskId, entry: usize) -> Result<(), TaskError> {
         Ok(())
     }
+
+    fn kernel_stack(&self, id: TaskId) -> Option<&KernelStack> {
+        None
+    }
 }
@@ -0,0 +1,62 @@
+use crate::lazy::Lazy;
+use crate::time::ClockSource;
+use ::x86_64::instructions::port::Port;
+
+pub static mut TSC: Lazy<Tsc> = Lazy::<Tsc, fn() -> Tsc>::new(|| unsafe { Tsc::calibrate() });
+
+// https://wiki.osdev.org/Programmable_Interval_Timer
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// bit 0: channel 2 gate, bit 1: speaker, bit 5: channel 2 output
+const PIT_CONTROL: u16 = 0x61;
+const CALIBRATION_MS: u64 = 10;
+
+// The time stamp counter, assumed to be invariant.
+pub struct Tsc {
+    frequency: u64,
+}
+
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+impl Tsc {
+    // Counts TSC ticks while PIT channel 2 counts down CALIBRATION_MS.
+    unsafe fn calibrate() -> Self {
+        let mut control = Port::<u8>::new(PIT_CONTROL);
+        let mut command = Port::<u8>::new(PIT_COMMAND);
+        let mut channel2 = Port::<u8>::new(PIT_CHANNEL2);
+
+        let value = control.read();
+        control.write((value & !0x03) | 0x01);
+        // channel 2, low byte then high byte, mode 0
+        command.write(0xb0);
+        let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+
+        let start = rdtsc();
+        while control.read() & 0x20 == 0 {}
+        let end = rdtsc();
+        control.write(value);
+
+        Self {
+            frequency: (end - start) * 1000 / CALIBRATION_MS,
+        }
+    }
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn read(&self) -> u64 {
+        rdtsc()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
@@ -0,0 +1,138 @@
+use crate::arch;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Space for the symbol table, filled in by tools/ksyms after linking. Images
+// that did not go through it (e.g. `cargo test`) print bare addresses.
+// KSYMS_SIZE comes from build.rs, which reserves what the last image needed.
+//
+// The table is little-endian:
+//   header:  magic "KSYM", symbol count, offset of the names, reserved (u32 each)
+//   symbols: address, size (u64 each), name offset, name length (u32 each),
+//            sorted by address
+//   names:   UTF-8, not terminated
+include!(concat!(env!("OUT_DIR"), "/ksyms_size.rs"));
+
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+extern "C" {
+    fn _ksyms_start();
+}
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 16;
+const SYMBOL_SIZE: usize = 24;
+
+// frames printed at most
+const MAX_DEPTH: usize = 64;
+// larger gaps between frame pointers mean the chain is corrupted
+const MAX_FRAME_SIZE: usize = 0x10000;
+
+static IN_BACKTRACE: AtomicBool = AtomicBool::new(false);
+
+// The table is read through _ksyms_start, since the compiler would assume
+// KSYMS still holds zeros.
+fn table() -> Option<&'static [u8]> {
+    let table = unsafe { core::slice::from_raw_parts(_ksyms_start as *const u8, KSYMS_SIZE) };
+    if &table[..4] == MAGIC {
+        Some(table)
+    } else {
+        None
+    }
+}
+
+fn read_u32(table: &[u8], offset: usize) -> Option<usize> {
+    let bytes = table.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+fn read_u64(table: &[u8], offset: usize) -> Option<usize> {
+    let bytes = table.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+// Returns the function containing `addr` and the offset into it.
+pub fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    lookup(table()?, addr)
+}
+
+// Like symbolize(), in the table `table`. A damaged table gives None.
+pub fn lookup(table: &[u8], addr: usize) -> Option<(&str, usize)> {
+    if table.get(..4)? != MAGIC {
+        return None;
+    }
+    let count = read_u32(table, 4)?;
+    let strings = read_u32(table, 8)?;
+    let symbol = |i: usize| HEADER_SIZE + i * SYMBOL_SIZE;
+
+    // first symbol above addr
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if read_u64(table, symbol(mid))? <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let entry = symbol(low.checked_sub(1)?);
+    let start = read_u64(table, entry)?;
+    let size = read_u64(table, entry + 8)?;
+    if size != 0 && addr - start >= size {
+        return None;
+    }
+    let name = strings.checked_add(read_u32(table, entry + 16)?)?;
+    let len = read_u32(table, entry + 20)?;
+    let name = core::str::from_utf8(table.get(name..name.checked_add(len)?)?).ok()?;
+    Some((name, addr - start))
+}
+
+fn print_frame(depth: usize, addr: usize, lookup: usize) {
+    match symbolize(lookup) {
+        Some((name, offset)) => println!(
+            "  #{:<2} {:#018x} {}+{:#x}",
+            depth,
+            addr,
+            name,
+            offset + addr - lookup
+        ),
+        None => println!("  #{:<2} {:#018x} ?", depth, addr),
+    }
+}
+
+// Prints `pc`, then walks the frame pointer chain starting at `fp`.
+pub fn print_backtrace_at(pc: Option<usize>, mut fp: usize) {
+    // a fault while unwinding must not unwind again
+    if IN_BACKTRACE.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    println!("backtrace:");
+    let mut depth = 0;
+    if let Some(pc) = pc {
+        print_frame(depth, pc, pc);
+        depth += 1;
+    }
+    while depth < MAX_DEPTH {
+        let (ra, prev) = match unsafe { arch::unwind_frame(fp) } {
+            Some(frame) => frame,
+            None => break,
+        };
+        if ra == 0 {
+            break;
+        }
+        // return addresses point past the call
+        print_frame(depth, ra, ra - 1);
+        if prev <= fp || prev - fp > MAX_FRAME_SIZE {
+            break;
+        }
+        fp = prev;
+        depth += 1;
+    }
+    IN_BACKTRACE.store(false, Ordering::SeqCst);
+}
+
+#[inline(never)]
+pub fn print_backtrace() {
+    print_backtrace_at(None, arch::frame_pointer());
+}
@@ -0,0 +1,318 @@
+use crate::device::block::BlockDevice;
+use crate::error::BlockError;
+use crate::lazy::Lazy;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::info;
+
+// Crash records go to the last CRASH_SECTORS sectors of the boot disk. The FAT
+// volume must end before them; make-disk leaves the room.
+pub const CRASH_SECTORS: usize = 128;
+const SECTOR_SIZE: usize = 512;
+
+// The first sector holds the header: magic, then the length and a checksum of
+// the text (u32, little-endian). The text follows in the other sectors.
+const MAGIC: &[u8; 8] = b"NVLCRASH";
+const CAPACITY: usize = (CRASH_SECTORS - 1) * SECTOR_SIZE;
+
+// name of the file a recovered record is saved to
+const CRASH_FILE: &str = "crash.txt";
+
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+static WRITING: AtomicBool = AtomicBool::new(false);
+static mut TEXT: [u8; CAPACITY] = [0; CAPACITY];
+static mut LENGTH: usize = 0;
+
+#[cfg(target_board = "virt")]
+mod disk {
+    use crate::device::common::virtio::block::{BlockOpType, VIRTIO_BLOCK};
+    use crate::lazy::Lazy;
+
+    pub fn sectors() -> Option<usize> {
+        let block = unsafe { Lazy::get(&VIRTIO_BLOCK)? };
+        if block.is_initialized() {
+            Some(block.size())
+        } else {
+            None
+        }
+    }
+
+    pub fn read(sector: usize, buf: &mut [u8]) -> bool {
+        unsafe {
+            VIRTIO_BLOCK.block_op_polled(buf.as_mut_ptr(), sector as u64, BlockOpType::Read) == 0
+        }
+    }
+
+    pub fn write(sector: usize, buf: &[u8]) -> bool {
+        unsafe {
+            VIRTIO_BLOCK.block_op_polled(buf.as_ptr() as *mut u8, sector as u64, BlockOpType::Write)
+                == 0
+        }
+    }
+}
+
+// The SD card driver polls, so it works from the panic handler as well.
+#[cfg(target_board = "raspi3b")]
+mod disk {
+    use crate::device::raspi3b::sd::SDCARD;
+    use crate::lazy::Lazy;
+
+    pub fn sectors() -> Option<usize> {
+        let sd = unsafe { Lazy::get(&SDCARD)? };
+        match sd.sd_blocks() {
+            0 => None,
+            blocks => Some(blocks),
+        }
+    }
+
+    pub fn read(sector: usize, buf: &mut [u8]) -> bool {
+        unsafe { SDCARD.sd_readblock(sector as u32, buf.as_mut_ptr(), 1) != 0 }
+    }
+
+    pub fn write(sector: usize, buf: &[u8]) -> bool {
+        unsafe { SDCARD.sd_writeblock(sector as u32, buf.as_ptr(), 1) != 0 }
+    }
+}
+
+#[cfg(not(any(target_board = "virt", target_board = "raspi3b")))]
+mod disk {
+    pub fn sectors() -> Option<usize> {
+        None
+    }
+
+    pub fn read(_sector: usize, _buf: &mut [u8]) -> bool {
+        false
+    }
+
+    pub fn write(_sector: usize, _buf: &[u8]) -> bool {
+        false
+    }
+}
+
+// The boot disk, through the polled disk functions above.
+struct BootDisk;
+
+impl BlockDevice for BootDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn blocks(&self) -> usize {
+        disk::sectors().unwrap_or(0)
+    }
+
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(block, buf.len())?;
+        for (i, sector) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+            if !disk::read(block + i, sector) {
+                return Err(BlockError::Io);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_range(block, buf.len())?;
+        for (i, sector) in buf.chunks(SECTOR_SIZE).enumerate() {
+            if !disk::write(block + i, sector) {
+                return Err(BlockError::Io);
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Capture;
+
+impl Write for Capture {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        unsafe {
+            let len = s.len().min(CAPACITY - LENGTH);
+            TEXT[LENGTH..LENGTH + len].copy_from_slice(&s.as_bytes()[..len]);
+            LENGTH += len;
+        }
+        Ok(())
+    }
+}
+
+// FNV-1a
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash, b| {
+        (hash ^ *b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+// Called from print!. Keeps the output while a crash is being reported.
+pub fn capture(args: fmt::Arguments) {
+    if CAPTURING.load(Ordering::Relaxed) {
+        let _ = Capture.write_fmt(args);
+    }
+}
+
+// Starts recording the console output for the crash record. Returns false if
+// the record was already started, e.g. by a trap handler that then panicked.
+pub fn begin() -> bool {
+    if CAPTURING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    unsafe {
+        LENGTH = 0;
+    }
+    true
+}
+
+// First sector of the crash area, if the disk is usable.
+fn area(disk: &mut dyn BlockDevice) -> Result<usize, &'static str> {
+    if disk.blocks() == 0 || disk.block_size() != SECTOR_SIZE {
+        return Err("no disk");
+    }
+    let start = disk
+        .blocks()
+        .checked_sub(CRASH_SECTORS)
+        .ok_or("the disk is too small")?;
+    // FAT boot sector: total sector count at 19 (u16) or 32 (u32)
+    let mut boot = [0u8; SECTOR_SIZE];
+    if disk.read_blocks(0, &mut boot).is_err() {
+        return Err("cannot read the boot sector");
+    }
+    if boot[510..512] == [0x55, 0xaa] {
+        let total = match u16::from_le_bytes([boot[19], boot[20]]) {
+            0 => u32::from_le_bytes(boot[32..36].try_into().unwrap()) as usize,
+            total => total as usize,
+        };
+        if total > start {
+            return Err("the file system covers the crash area");
+        }
+    }
+    Ok(start)
+}
+
+// Writes what was captured since begin() to the boot disk.
+pub fn write() {
+    if WRITING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    match write_to(&mut BootDisk) {
+        Ok(start) => println!("crash record written to sector {}", start),
+        Err(reason) => println!("crash record not written: {}", reason),
+    }
+}
+
+// Writes what was captured since begin() to the crash area of `disk` and
+// returns its first sector. The header goes last, so a record that was cut
+// short is not picked up.
+pub fn write_to(disk: &mut dyn BlockDevice) -> Result<usize, &'static str> {
+    CAPTURING.store(false, Ordering::SeqCst);
+    let start = area(disk)?;
+    let text = unsafe { &TEXT[..LENGTH] };
+    let mut sector = [0u8; SECTOR_SIZE];
+    for (i, chunk) in text.chunks(SECTOR_SIZE).enumerate() {
+        sector.fill(0);
+        sector[..chunk.len()].copy_from_slice(chunk);
+        disk.write_blocks(start + 1 + i, &sector)
+            .or(Err("write error"))?;
+    }
+    sector.fill(0);
+    sector[..8].copy_from_slice(MAGIC);
+    sector[8..12].copy_from_slice(&(text.len() as u32).to_le_bytes());
+    sector[12..16].copy_from_slice(&checksum(text).to_le_bytes());
+    disk.write_blocks(start, &sector).or(Err("write error"))?;
+    Ok(start)
+}
+
+// Looks for a record left by the previous boot. It is printed, saved to
+// CRASH_FILE on the FAT volume and then cleared.
+pub fn recover() {
+    let text = match recover_from(&mut BootDisk) {
+        Ok(Some(text)) => text,
+        Ok(None) => return,
+        Err(reason) => {
+            info!("crash record: {}", reason);
+            return;
+        }
+    };
+    println!("--- crash record from the previous boot ---");
+    println!("{}", core::str::from_utf8(&text).unwrap_or("(not UTF-8)"));
+    println!("--- end of crash record ---");
+    if let Err(e) = save(&text) {
+        info!("crash record: cannot save {}: {:?}", CRASH_FILE, e);
+    }
+}
+
+// Takes the record off the crash area of `disk`. Records that cannot be
+// trusted are cleared too, and reported as errors.
+pub fn recover_from(disk: &mut dyn BlockDevice) -> Result<Option<Vec<u8>>, &'static str> {
+    let start = area(disk)?;
+    let mut header = [0u8; SECTOR_SIZE];
+    if disk.read_blocks(start, &mut header).is_err() || &header[..8] != MAGIC {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+    let sum = u32::from_le_bytes(header[12..16].try_into().unwrap());
+    if len > CAPACITY {
+        discard(disk, start);
+        return Err("bad length, discarded");
+    }
+    let mut text = vec![0u8; (len + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE];
+    disk.read_blocks(start + 1, &mut text)
+        .or(Err("read error"))?;
+    text.truncate(len);
+    // a write cut short or a damaged sector; none of it can be trusted
+    if checksum(&text) != sum {
+        discard(disk, start);
+        return Err("bad checksum, discarded");
+    }
+    discard(disk, start);
+    Ok(Some(text))
+}
+
+// Clears the header, so the record is not picked up again.
+fn discard(disk: &mut dyn BlockDevice, start: usize) {
+    let _ = disk.write_blocks(start, &[0u8; SECTOR_SIZE]);
+}
+
+#[cfg(any(target_board = "virt", target_board = "raspi3b"))]
+fn save(text: &[u8]) -> Result<(), crate::error::DiskError> {
+    use fatfs::Write;
+    let root_dir = unsafe { crate::fs::fat32::FILE_SYSTEM.root_dir() };
+    let mut file = root_dir
+        .create_file(CRASH_FILE)
+        .map_err(|_| crate::error::DiskError::Dummy)?;
+    file.truncate()
+        .map_err(|_| crate::error::DiskError::Dummy)?;
+    file.write_all(text)
+        .map_err(|_| crate::error::DiskError::Dummy)?;
+    Ok(())
+}
+
+#[cfg(not(any(target_board = "virt", target_board = "raspi3b")))]
+fn save(_text: &[u8]) -> Result<(), crate::error::DiskError> {
+    Ok(())
+}
+
+// Adds the newest kernel log records that fit to the record, without printing
+// them. The ring is skipped if the panic happened while it was locked.
+fn capture_log() {
+    let ring = match crate::logger::RING.try_lock() {
+        Some(ring) => ring,
+        None => return,
+    };
+    let _ = Capture.write_str("log:\n");
+    unsafe {
+        let (len, _) = ring.read(ring.seq_fitting(CAPACITY - LENGTH), &mut TEXT[LENGTH..]);
+        LENGTH += len;
+    }
+}
+
+// The panic handler's report: the task list, then everything printed since
+// begin() and the kernel log go to disk.
+pub fn finish() {
+    if let Some(task_manager) = unsafe { Lazy::get(&crate::task::TASK_MANAGER) } {
+        println!("tasks:");
+        task_manager.dump_tasks();
+    }
+    capture_log();
+    write();
+}
@@ -1,4 +1,6 @@
+pub mod block;
 pub mod common;
+pub mod ramdisk;
 
 #[cfg(target_arch = "riscv64")]
 #[cfg(target_board = "virt")]
@@ -7,3 +9,6 @@ pub mod virt;
 #[cfg(target_arch = "aarch64")]
 #[cfg(target_board = "raspi3b")]
 pub mod raspi3b;
+
+#[cfg(target_arch = "x86_64")]
+pub mod pc;
@@ -0,0 +1,93 @@
+use crate::error::BlockError;
+use crate::lazy::Lazy;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use log::info;
+
+// A device that reads and writes whole blocks, such as a disk or an SD card.
+pub trait BlockDevice {
+    // bytes per block
+    fn block_size(&self) -> usize;
+    // capacity in blocks
+    fn blocks(&self) -> usize;
+    // Reads buf.len() / block_size() blocks, starting at `block`.
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), BlockError>;
+    // Writes buf.len() / block_size() blocks, starting at `block`.
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), BlockError>;
+
+    // Returns once what was written has reached the medium.
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    // Checks a request of `len` bytes from `block` against the device.
+    fn check_range(&self, block: usize, len: usize) -> Result<(), BlockError> {
+        if len % self.block_size() != 0 {
+            return Err(BlockError::Misaligned);
+        }
+        match block.checked_add(len / self.block_size()) {
+            Some(end) if end <= self.blocks() => Ok(()),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+}
+
+pub static mut BLOCK_DEVICES: Lazy<BlockDevices> =
+    Lazy::<BlockDevices, fn() -> BlockDevices>::new(|| BlockDevices::new());
+
+// Block devices by name, e.g. "vda" for the virtio disk. Drivers register
+// their devices once they are set up.
+pub struct BlockDevices {
+    devices: Vec<(String, &'static mut dyn BlockDevice)>,
+}
+
+impl BlockDevices {
+    pub fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+        }
+    }
+
+    pub fn register(
+        &mut self,
+        name: &str,
+        device: &'static mut dyn BlockDevice,
+    ) -> Result<(), BlockError> {
+        if self
+            .devices
+            .iter()
+            .any(|(registered, _)| registered == name)
+        {
+            return Err(BlockError::AlreadyRegistered);
+        }
+        info!(
+            "block: {}, {} blocks of {} bytes{}",
+            name,
+            device.blocks(),
+            device.block_size(),
+            if device.is_read_only() {
+                ", read-only"
+            } else {
+                ""
+            }
+        );
+        self.devices.push((name.to_string(), device));
+        Ok(())
+    }
+
+    pub fn get(&mut self, name: &str) -> Result<&mut dyn BlockDevice, BlockError> {
+        self.devices
+            .iter_mut()
+            .find(|(registered, _)| registered == name)
+            .map(|(_, device)| &mut **device as &mut dyn BlockDevice)
+            .ok_or(BlockError::NotFound)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.devices.iter().map(|(name, _)| name.as_str())
+    }
+}
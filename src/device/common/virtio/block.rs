@@ -2,9 +2,12 @@
 
 use super::header::*;
 use super::queue::*;
+use crate::allocator::frame::alloc_frames_zeroed;
+use crate::arch::virt_to_phys;
+use crate::device::block::BlockDevice;
+use crate::error::BlockError;
 use crate::lazy::Lazy;
 use crate::KERNEL_LOCK;
-use alloc::alloc::{alloc_zeroed, Layout};
 use alloc::vec::Vec;
 use core::mem;
 use core::sync::atomic::{fence, Ordering};
@@ -62,6 +65,8 @@ pub struct VirtIOBlock<'q> {
     requests: [VirtIOBlockReq; DESC_NUM],
     status: [u8; DESC_NUM],
     complete: [ReadWrite<bool>; DESC_NUM],
+    // the host offered VIRTIO_BLK_F_RO
+    read_only: bool,
 }
 
 unsafe impl Send for VirtIOBlock<'_> {}
@@ -101,6 +106,7 @@ impl VirtIOBlock<'_> {
         {
             use VirtIODeviceFeature::*;
             let mut features = self.header.host_features.read();
+            self.read_only = features & (1 << VIRTIO_BLK_F_RO as usize) != 0;
             features &= !(1 << VIRTIO_BLK_F_RO as usize);
             features &= !(1 << VIRTIO_BLK_F_SCSI as usize);
             features &= !(1 << VIRTIO_BLK_F_CONFIG_WCE as usize);
@@ -134,16 +140,12 @@ impl VirtIOBlock<'_> {
         self.header.queue_num.write(DESC_NUM as u32);
 
         // Allocate queue pages
-        self.pages = {
-            let layout =
-                Layout::from_size_align(crate::arch::PAGE_SIZE * 2, crate::arch::PAGE_SIZE)
-                    .unwrap();
-            alloc_zeroed(layout)
-        };
+        // two contiguous pages
+        self.pages = alloc_frames_zeroed(1).expect("VirtIO Disk: out of frames") as *mut u8;
 
         self.header
             .queue_pfn
-            .write((self.pages as usize >> crate::arch::PAGE_SHIFT) as u32);
+            .write((virt_to_phys(self.pages as usize) >> crate::arch::PAGE_SHIFT) as u32);
         self.desc = (self.pages as *mut [VirtQueueDesc; DESC_NUM])
             .as_mut()
             .unwrap();
@@ -194,6 +196,47 @@ impl VirtIOBlock<'_> {
     }
 
     pub fn block_op(&mut self, buf: *mut u8, sector: u64, op: BlockOpType) {
+        let head = self.submit(buf, sector, op);
+        while !self.complete[head as usize].read() {
+            unsafe {
+                KERNEL_LOCK.wait_interrupt();
+            }
+        }
+
+        self.free_desc(head);
+    }
+
+    // Like block_op, but polls the used ring instead of waiting for the
+    // interrupt. For the panic path, where interrupts cannot be relied on.
+    // Returns the status reported by the device.
+    pub fn block_op_polled(&mut self, buf: *mut u8, sector: u64, op: BlockOpType) -> u8 {
+        let head = self.submit(buf, sector, op);
+        loop {
+            while self.used_idx == unsafe { core::ptr::read_volatile(&self.used.idx) } {
+                core::hint::spin_loop();
+            }
+            fence(Ordering::SeqCst);
+            let id = self.used.ring[self.used_idx as usize % DESC_NUM].id as usize;
+            self.complete[id].write(true);
+            self.used_idx += 1;
+            if id == head as usize {
+                break;
+            }
+        }
+        self.header
+            .interrupt_ack
+            .write(self.header.interrupt_status.read() & 0x3);
+
+        self.free_desc(head);
+        self.status[head as usize]
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        !self.pages.is_null()
+    }
+
+    // Queues a request and returns the index of its first descriptor.
+    fn submit(&mut self, buf: *mut u8, sector: u64, op: BlockOpType) -> u16 {
         let indexes = self.alloc_desc(3);
         assert!(indexes.iter().all(|i| *i < DESC_NUM as u16));
         let mut request = self.requests.get_mut(indexes[0] as usize).unwrap();
@@ -205,14 +248,14 @@ impl VirtIOBlock<'_> {
         request.sector = sector;
 
         self.desc[indexes[0] as usize] = VirtQueueDesc {
-            addr: request as *mut VirtIOBlockReq as u64,
+            addr: virt_to_phys(request as *mut VirtIOBlockReq as usize) as u64,
             len: mem::size_of::<VirtIOBlockReq>() as u32,
             flags: VirtQueueDescFlag::VIRTQ_DESC_F_NEXT as u16,
             next: indexes[1],
         };
 
         self.desc[indexes[1] as usize] = VirtQueueDesc {
-            addr: buf as u64,
+            addr: virt_to_phys(buf as usize) as u64,
             len: BLOCK_SIZE as u32,
             flags: {
                 // TODO: refine
@@ -228,7 +271,7 @@ impl VirtIOBlock<'_> {
 
         self.status[indexes[0] as usize] = 0xff;
         self.desc[indexes[2] as usize] = VirtQueueDesc {
-            addr: (&self.status[indexes[0] as usize]) as *const u8 as u64,
+            addr: virt_to_phys(&self.status[indexes[0] as usize] as *const u8 as usize) as u64,
             len: 1,
             flags: VirtQueueDescFlag::VIRTQ_DESC_F_WRITE as u16,
             next: 0,
@@ -242,13 +285,7 @@ impl VirtIOBlock<'_> {
         fence(Ordering::SeqCst);
 
         self.header.queue_notify.write(0);
-        while !self.complete[indexes[0] as usize].read() {
-            unsafe {
-                KERNEL_LOCK.wait_interrupt();
-            }
-        }
-
-        self.free_desc(indexes[0]);
+        indexes[0]
     }
 
     pub fn size(&self) -> usize {
@@ -273,3 +310,40 @@ impl VirtIOBlock<'_> {
         }
     }
 }
+
+impl BlockDevice for VirtIOBlock<'_> {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn blocks(&self) -> usize {
+        self.size()
+    }
+
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(block, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+            self.block_op(chunk.as_mut_ptr(), (block + i) as u64, BlockOpType::Read);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.check_range(block, buf.len())?;
+        for (i, chunk) in buf.chunks(BLOCK_SIZE).enumerate() {
+            self.block_op(
+                chunk.as_ptr() as *mut u8,
+                (block + i) as u64,
+                BlockOpType::Write,
+            );
+        }
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
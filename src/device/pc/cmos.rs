@@ -0,0 +1,88 @@
+use crate::time::DateTime;
+use ::x86_64::instructions::port::Port;
+use core::time::Duration;
+
+// https://wiki.osdev.org/CMOS
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const CENTURY: u8 = 0x32;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+// status A
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+// status B
+const HOURS_24: u8 = 0x02;
+const BINARY: u8 = 0x04;
+// hours register, in 12-hour mode
+const PM: u8 = 0x80;
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        // bit 7 keeps NMIs enabled
+        Port::<u8>::new(CMOS_ADDRESS).write(register & 0x7f);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+fn read_raw() -> [u8; 7] {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR, CENTURY].map(read_register)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+// Time since the Unix epoch. The RTC is assumed to keep UTC, as QEMU's does
+// by default.
+pub fn read() -> Option<Duration> {
+    // read until two reads agree, so no update happened in between
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let status = read_register(STATUS_B);
+    let decode = |value: u8| {
+        if status & BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+    let [seconds, minutes, hours, day, month, year, century] = raw;
+    let mut hour = decode(hours & !PM) as u32;
+    if status & HOURS_24 == 0 {
+        hour %= 12;
+        if hours & PM != 0 {
+            hour += 12;
+        }
+    }
+    let century = match decode(century) {
+        0 => 20,
+        century => century as u32,
+    };
+    let time = DateTime {
+        year: century * 100 + decode(year) as u32,
+        month: decode(month) as u32,
+        day: decode(day) as u32,
+        hour,
+        minute: decode(minutes) as u32,
+        second: decode(seconds) as u32,
+    };
+    if !(1..=12).contains(&time.month) || !(1..=31).contains(&time.day) {
+        return None;
+    }
+    Some(Duration::from_secs(time.to_unix()))
+}
@@ -0,0 +1,51 @@
+use crate::device::block::BlockDevice;
+use crate::error::BlockError;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub const BLOCK_SIZE: usize = 512;
+
+// A block device in kernel memory.
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    // A zeroed disk of `blocks` blocks.
+    pub fn new(blocks: usize) -> Self {
+        Self {
+            data: vec![0; blocks * BLOCK_SIZE],
+        }
+    }
+
+    // A disk holding a copy of `image`, padded to whole blocks.
+    pub fn from_image(image: &[u8]) -> Self {
+        let mut disk = Self::new((image.len() + BLOCK_SIZE - 1) / BLOCK_SIZE);
+        disk.data[..image.len()].copy_from_slice(image);
+        disk
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn blocks(&self) -> usize {
+        self.data.len() / BLOCK_SIZE
+    }
+
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(block, buf.len())?;
+        let start = block * BLOCK_SIZE;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_range(block, buf.len())?;
+        let start = block * BLOCK_SIZE;
+        self.data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
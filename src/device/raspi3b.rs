@@ -1,5 +1,6 @@
 pub mod base;
 pub mod framebuffer;
+pub mod irq;
 pub mod mailbox;
 pub mod sd;
 pub mod uart;
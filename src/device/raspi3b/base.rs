@@ -1,5 +1,8 @@
 pub const MMIO_BASE: usize = 0x3F000000;
 pub const MMIO_SIZE: usize = 0x01000000;
+// BCM2836 local peripherals: per-core timers, mailboxes and interrupt routing
+pub const LOCAL_BASE: usize = 0x40000000;
+pub const LOCAL_SIZE: usize = 0x1000;
 pub const GPFSEL0: usize = MMIO_BASE + 0x00200000;
 pub const GPFSEL1: usize = MMIO_BASE + 0x00200004;
 pub const GPFSEL2: usize = MMIO_BASE + 0x00200008;
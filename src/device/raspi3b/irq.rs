@@ -0,0 +1,232 @@
+use super::base::*;
+use crate::arch;
+use crate::lazy::Lazy;
+use alloc::vec::Vec;
+use log::warn;
+
+// Interrupts reach a core through the BCM2836 local interrupt controller. Its
+// own sources are the core's timers, mailboxes and PMU; everything else comes
+// from the BCM2835 ARM interrupt controller, routed to one core as the "GPU"
+// source.
+
+// BCM2836 local interrupt controller
+const GPU_INTERRUPT_ROUTING: usize = LOCAL_BASE + 0x0c;
+const CORE_TIMER_CONTROL: usize = LOCAL_BASE + 0x40;
+const CORE_MAILBOX_CONTROL: usize = LOCAL_BASE + 0x50;
+const CORE_IRQ_SOURCE: usize = LOCAL_BASE + 0x60;
+
+// BCM2835 ARM interrupt controller
+const IRQ_BASIC_PENDING: usize = MMIO_BASE + 0xb200;
+const IRQ_PENDING_1: usize = MMIO_BASE + 0xb204;
+const IRQ_PENDING_2: usize = MMIO_BASE + 0xb208;
+const ENABLE_IRQS_1: usize = MMIO_BASE + 0xb210;
+const ENABLE_IRQS_2: usize = MMIO_BASE + 0xb214;
+const ENABLE_BASIC_IRQS: usize = MMIO_BASE + 0xb218;
+const DISABLE_IRQS_1: usize = MMIO_BASE + 0xb21c;
+const DISABLE_IRQS_2: usize = MMIO_BASE + 0xb220;
+const DISABLE_BASIC_IRQS: usize = MMIO_BASE + 0xb224;
+
+// Core sources (CORE_IRQ_SOURCE bits)
+pub const LOCAL_CNTPS: u32 = 0;
+pub const LOCAL_CNTPNS: u32 = 1;
+pub const LOCAL_CNTHP: u32 = 2;
+pub const LOCAL_CNTV: u32 = 3;
+pub const LOCAL_MAILBOX0: u32 = 4;
+pub const LOCAL_GPU: u32 = 8;
+pub const LOCAL_PMU: u32 = 9;
+pub const LOCAL_TIMER: u32 = 11;
+
+// ARM sources (basic pending bits)
+pub const ARM_TIMER: u32 = 0;
+pub const ARM_MAILBOX: u32 = 1;
+
+// GPU sources
+pub const GPU_AUX: u32 = 29;
+pub const GPU_UART: u32 = 57;
+pub const GPU_EMMC: u32 = 62;
+
+const CORES: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Irq {
+    // a source of the local controller, on the core that enables it
+    Local(u32),
+    // one of the ARM controller's own sources, 0-7
+    Arm(u32),
+    // a peripheral, 0-63
+    Gpu(u32),
+}
+
+pub type IrqHandler = unsafe fn();
+
+pub static mut IRQ_MANAGER: Lazy<IrqManager> =
+    Lazy::<IrqManager, fn() -> IrqManager>::new(|| IrqManager::new());
+
+pub struct IrqManager {
+    handlers: Vec<(Irq, IrqHandler)>,
+    // ARM controller sources enabled: basic, then GPU 0-31 and 32-63. The
+    // pending registers are masked with these.
+    enabled: [u32; 3],
+}
+
+unsafe fn read(addr: usize) -> u32 {
+    (addr as *mut u32).read_volatile()
+}
+
+unsafe fn write(addr: usize, value: u32) {
+    (addr as *mut u32).write_volatile(value)
+}
+
+impl IrqManager {
+    pub fn new() -> Self {
+        Self {
+            handlers: Vec::new(),
+            enabled: [0; 3],
+        }
+    }
+
+    // Masks every source and routes the ARM controller to core 0.
+    pub fn init(&mut self) {
+        unsafe {
+            write(DISABLE_BASIC_IRQS, u32::MAX);
+            write(DISABLE_IRQS_1, u32::MAX);
+            write(DISABLE_IRQS_2, u32::MAX);
+            for core in 0..CORES {
+                write(CORE_TIMER_CONTROL + 4 * core, 0);
+                write(CORE_MAILBOX_CONTROL + 4 * core, 0);
+            }
+            write(GPU_INTERRUPT_ROUTING, 0);
+        }
+        self.enabled = [0; 3];
+    }
+
+    // Calls `handler` whenever `irq` is raised, and unmasks it.
+    pub fn register(&mut self, irq: Irq, handler: IrqHandler) {
+        self.handlers.retain(|(registered, _)| *registered != irq);
+        self.handlers.push((irq, handler));
+        self.enable(irq);
+    }
+
+    pub fn enable(&mut self, irq: Irq) {
+        let core = arch::cpu_id();
+        unsafe {
+            match irq {
+                Irq::Local(n @ LOCAL_CNTPS..=LOCAL_CNTV) => {
+                    let control = CORE_TIMER_CONTROL + 4 * core;
+                    write(control, read(control) | 1 << n);
+                }
+                Irq::Local(n @ LOCAL_MAILBOX0..=7) => {
+                    let control = CORE_MAILBOX_CONTROL + 4 * core;
+                    write(control, read(control) | 1 << (n - LOCAL_MAILBOX0));
+                }
+                Irq::Local(n) => warn!("irq: local source {} cannot be enabled", n),
+                Irq::Arm(n) => {
+                    self.enabled[0] |= 1 << n;
+                    write(ENABLE_BASIC_IRQS, 1 << n);
+                }
+                Irq::Gpu(n) => {
+                    self.enabled[1 + n as usize / 32] |= 1 << (n % 32);
+                    let enable = if n < 32 { ENABLE_IRQS_1 } else { ENABLE_IRQS_2 };
+                    write(enable, 1 << (n % 32));
+                }
+            }
+        }
+    }
+
+    pub fn disable(&mut self, irq: Irq) {
+        let core = arch::cpu_id();
+        unsafe {
+            match irq {
+                Irq::Local(n @ LOCAL_CNTPS..=LOCAL_CNTV) => {
+                    let control = CORE_TIMER_CONTROL + 4 * core;
+                    write(control, read(control) & !(1 << n));
+                }
+                Irq::Local(n @ LOCAL_MAILBOX0..=7) => {
+                    let control = CORE_MAILBOX_CONTROL + 4 * core;
+                    write(control, read(control) & !(1 << (n - LOCAL_MAILBOX0)));
+                }
+                Irq::Local(_) => {}
+                Irq::Arm(n) => {
+                    self.enabled[0] &= !(1 << n);
+                    write(DISABLE_BASIC_IRQS, 1 << n);
+                }
+                Irq::Gpu(n) => {
+                    self.enabled[1 + n as usize / 32] &= !(1 << (n % 32));
+                    let disable = if n < 32 {
+                        DISABLE_IRQS_1
+                    } else {
+                        DISABLE_IRQS_2
+                    };
+                    write(disable, 1 << (n % 32));
+                }
+            }
+        }
+    }
+
+    pub fn is_enabled(&self, irq: Irq) -> bool {
+        let core = arch::cpu_id();
+        unsafe {
+            match irq {
+                Irq::Local(n @ LOCAL_CNTPS..=LOCAL_CNTV) => {
+                    read(CORE_TIMER_CONTROL + 4 * core) & 1 << n != 0
+                }
+                Irq::Local(n @ LOCAL_MAILBOX0..=7) => {
+                    read(CORE_MAILBOX_CONTROL + 4 * core) & 1 << (n - LOCAL_MAILBOX0) != 0
+                }
+                Irq::Local(_) => false,
+                Irq::Arm(n) => self.enabled[0] & 1 << n != 0,
+                Irq::Gpu(n) => self.enabled[1 + n as usize / 32] & 1 << (n % 32) != 0,
+            }
+        }
+    }
+
+    // Runs the handler of `irq`. A source nobody handles is masked, or it
+    // would be raised again right away.
+    pub unsafe fn dispatch(&mut self, irq: Irq) {
+        let handler = self
+            .handlers
+            .iter()
+            .find(|(registered, _)| *registered == irq)
+            .map(|(_, handler)| *handler);
+        match handler {
+            Some(handler) => handler(),
+            None => {
+                warn!("irq: unhandled {:?}, masked", irq);
+                self.disable(irq);
+            }
+        }
+    }
+
+    unsafe fn dispatch_gpu(&mut self) {
+        let pending = [
+            read(IRQ_BASIC_PENDING) & 0xff,
+            read(IRQ_PENDING_1),
+            read(IRQ_PENDING_2),
+        ];
+        for (bank, pending) in pending.iter().enumerate() {
+            let mut pending = pending & self.enabled[bank];
+            while pending != 0 {
+                let n = pending.trailing_zeros();
+                pending &= !(1 << n);
+                self.dispatch(match bank {
+                    0 => Irq::Arm(n),
+                    _ => Irq::Gpu(32 * (bank as u32 - 1) + n),
+                });
+            }
+        }
+    }
+
+    // Handles the IRQs pending on this core. Called from the IRQ vector.
+    pub unsafe fn handle_irq(&mut self) {
+        let mut source = read(CORE_IRQ_SOURCE + 4 * arch::cpu_id()) & 0xfff;
+        while source != 0 {
+            let n = source.trailing_zeros();
+            source &= !(1 << n);
+            if n == LOCAL_GPU {
+                self.dispatch_gpu();
+            } else {
+                self.dispatch(Irq::Local(n));
+            }
+        }
+    }
+}
@@ -1,10 +1,12 @@
 #![allow(unused_assignments)]
 
+use crate::device::block::BlockDevice;
 use crate::device::raspi3b::base::*;
 use crate::device::raspi3b::wait::*;
+use crate::error::BlockError;
 use crate::lazy::Lazy;
 use bitflags::bitflags;
-use log::{error, info};
+use log::{debug, error, info};
 
 // https://github.com/bztsrc/raspi3-tutorial/blob/master/0B_readsector/sd.c
 
@@ -14,6 +16,7 @@ pub static mut SDCARD: Lazy<SDCard> = Lazy::new(|| SDCard {
     sd_rca: 0,
     sd_err: 0,
     sd_hv: 0,
+    sd_blocks: 0,
 });
 
 pub const EMMC_ARG2: usize = MMIO_BASE + 0x300000;
@@ -45,10 +48,13 @@ bitflags! {
         const CMD_SEND_REL_ADDR = 0x03020000;
         const CMD_CARD_SELECT = 0x07030000;
         const CMD_SEND_IF_COND = 0x08020000;
+        const CMD_SEND_CSD = 0x09010000;
         const CMD_STOP_TRANS = 0x0C030000;
         const CMD_READ_SINGLE = 0x11220010;
         const CMD_READ_MULTI = 0x12220032;
         const CMD_SET_BLOCKCNT = 0x17020000;
+        const CMD_WRITE_SINGLE = 0x18220000;
+        const CMD_WRITE_MULTI = 0x19220022;
         const CMD_APP_CMD = 0x37000000;
         const CMD_SET_BUS_WIDTH = 0x06020000 | Self::CMD_NEED_APP.bits;
         const CMD_SEND_OP_COND = 0x29020000 | Self::CMD_NEED_APP.bits;
@@ -57,6 +63,7 @@ bitflags! {
 
     struct Status: u32 {
         const SR_READ_AVAILABLE = 0x00000800;
+        const SR_WRITE_AVAILABLE = 0x00000400;
         const SR_DAT_INHIBIT = 0x00000002;
         const SR_CMD_INHIBIT = 0x00000001;
         const SR_APP_CMD = 0x00000020;
@@ -66,6 +73,8 @@ bitflags! {
         const INT_DATA_TIMEOUT = 0x00100000;
         const INT_CMD_TIMEOUT = 0x00010000;
         const INT_READ_RDY = 0x00000020;
+        const INT_WRITE_RDY = 0x00000010;
+        const INT_DATA_DONE = 0x00000002;
         const INT_CMD_DONE = 0x00000001;
         const INT_ERROR_MASK = 0x017E8000;
     }
@@ -116,6 +125,8 @@ pub struct SDCard {
     sd_rca: u64,
     sd_err: u64,
     sd_hv: u64,
+    // capacity in 512-byte blocks, from the CSD
+    sd_blocks: u64,
 }
 
 impl SDCard {
@@ -189,7 +200,7 @@ impl SDCard {
             self.sd_err = SDError::SD_TIMEOUT.bits() as u64;
             return 0;
         }
-        info!("Sending command: {:#x}, arg: {}", code, arg);
+        debug!("Sending command: {:#x}, arg: {}", code, arg);
         Self::write_reg(EMMC_INTERRUPT, Self::read_reg(EMMC_INTERRUPT));
         Self::write_reg(EMMC_ARG1, arg);
         Self::write_reg(EMMC_CMDTM, code);
@@ -239,7 +250,7 @@ impl SDCard {
         if num < 1 {
             num = 1;
         }
-        info!("sd_readblock lba: {}, num: {}", lba, num);
+        debug!("sd_readblock lba: {}, num: {}", lba, num);
         if self.sd_status(Status::SR_DAT_INHIBIT.bits()) != 0 {
             self.sd_err = SDError::SD_TIMEOUT.bits() as u64;
             return 0;
@@ -282,7 +293,7 @@ impl SDCard {
             }
             for d in 0..128 {
                 unsafe {
-                    buf.add(d).write_volatile(Self::read_reg(EMMC_DATA));
+                    buf.add(d).write_unaligned(Self::read_reg(EMMC_DATA));
                 }
             }
             unsafe {
@@ -303,6 +314,105 @@ impl SDCard {
         };
     }
 
+    pub fn sd_writeblock(&mut self, lba: u32, buffer: *const u8, mut num: u32) -> u32 {
+        let mut r = 0;
+        let mut c = 0;
+        if num < 1 {
+            num = 1;
+        }
+        debug!("sd_writeblock lba: {}, num: {}", lba, num);
+        if self.sd_status((Status::SR_DAT_INHIBIT | Status::SR_WRITE_AVAILABLE).bits()) != 0 {
+            self.sd_err = SDError::SD_TIMEOUT.bits() as u64;
+            return 0;
+        }
+        let ccs = self.sd_scr[0] & (ScrFlag::SCR_SUPP_CCS.bits() as u64) != 0;
+        let mut buf: *const u32 = buffer as *const u32;
+        if ccs {
+            if num > 1 && (self.sd_scr[0] & (ScrFlag::SCR_SUPP_SET_BLKCNT.bits() as u64)) != 0 {
+                self.sd_cmd(Command::CMD_SET_BLOCKCNT.bits(), num);
+                if self.sd_err != 0 {
+                    return 0;
+                }
+            }
+            Self::write_reg(EMMC_BLKSIZECNT, (num << 16) | 512);
+            self.sd_cmd(
+                if num == 1 {
+                    Command::CMD_WRITE_SINGLE.bits()
+                } else {
+                    Command::CMD_WRITE_MULTI.bits()
+                },
+                lba,
+            );
+            if self.sd_err != 0 {
+                return 0;
+            }
+        } else {
+            Self::write_reg(EMMC_BLKSIZECNT, (1 << 16) | 512);
+        }
+        while c < num {
+            if !ccs {
+                self.sd_cmd(Command::CMD_WRITE_SINGLE.bits(), (lba + c) * 512);
+                if self.sd_err != 0 {
+                    return 0;
+                }
+            }
+            r = self.sd_int(Interrupt::INT_WRITE_RDY.bits());
+            if r != 0 {
+                error!("Timeout waiting for ready to write");
+                self.sd_err = r as u64;
+                return 0;
+            }
+            for d in 0..128 {
+                unsafe {
+                    Self::write_reg(EMMC_DATA, buf.add(d).read_unaligned());
+                }
+            }
+            unsafe {
+                c += 1;
+                buf = buf.add(128);
+            }
+            // byte-addressed cards take one block per command
+            if !ccs || c == num {
+                r = self.sd_int(Interrupt::INT_DATA_DONE.bits());
+                if r != 0 {
+                    error!("Timeout waiting for data done");
+                    self.sd_err = r as u64;
+                    return 0;
+                }
+            }
+        }
+        if num > 1 && (self.sd_scr[0] & (ScrFlag::SCR_SUPP_SET_BLKCNT.bits() as u64)) == 0 && ccs {
+            self.sd_cmd(Command::CMD_STOP_TRANS.bits(), 0);
+        }
+        return if self.sd_err != (SDError::SD_OK.bits() as u64) || c != num {
+            0
+        } else {
+            num * 512
+        };
+    }
+
+    // Capacity in 512-byte blocks.
+    pub fn sd_blocks(&self) -> usize {
+        self.sd_blocks as usize
+    }
+
+    // Reads the capacity off the CSD register, as returned by CMD9. The
+    // controller drops the CRC, so CSD bit n is bit n - 8 of the response.
+    pub fn csd_blocks(resp: [u32; 4]) -> u64 {
+        let bits = |from: usize, len: usize| -> u64 {
+            let n = from - 8;
+            let low = resp[n / 32] as u64;
+            let high = resp.get(n / 32 + 1).copied().unwrap_or(0) as u64;
+            ((low | high << 32) >> (n % 32)) & ((1 << len) - 1)
+        };
+        match bits(126, 2) {
+            // CSD 2.0: C_SIZE counts 512 KiB
+            1 => (bits(48, 22) + 1) * 1024,
+            // CSD 1.0: (C_SIZE + 1) << (C_SIZE_MULT + 2) blocks of 2^READ_BL_LEN bytes
+            _ => ((bits(62, 12) + 1) << (bits(47, 3) + 2) << bits(80, 4)) / 512,
+        }
+    }
+
     pub fn sd_clk(&mut self, f: u32) -> u32 {
         let mut d: u32 = 0;
         let c: u32 = 41666666 / f;
@@ -523,6 +633,18 @@ impl SDCard {
             return self.sd_err as u32;
         }
 
+        self.sd_cmd(Command::CMD_SEND_CSD.bits(), self.sd_rca as u32);
+        if self.sd_err != 0 {
+            return self.sd_err as u32;
+        }
+        self.sd_blocks = Self::csd_blocks([
+            Self::read_reg(EMMC_RESP0),
+            Self::read_reg(EMMC_RESP1),
+            Self::read_reg(EMMC_RESP2),
+            Self::read_reg(EMMC_RESP3),
+        ]);
+        info!("EMMC: {} blocks", self.sd_blocks);
+
         r = self.sd_clk(2500_0000) as i64;
         if r != 0 {
             return r as u32;
@@ -582,3 +704,37 @@ impl SDCard {
         return SDError::SD_OK.bits();
     }
 }
+
+impl BlockDevice for SDCard {
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn blocks(&self) -> usize {
+        self.sd_blocks()
+    }
+
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(block, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let num = (buf.len() / 512) as u32;
+        match self.sd_readblock(block as u32, buf.as_mut_ptr(), num) {
+            0 => Err(BlockError::Io),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_range(block, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let num = (buf.len() / 512) as u32;
+        match self.sd_writeblock(block as u32, buf.as_ptr(), num) {
+            0 => Err(BlockError::Io),
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,15 +1,14 @@
+use crate::timer::TICK_HZ;
 use core::arch::asm;
+use core::time::Duration;
 
+// Waits of a jiffy or more let other tasks run. Shorter ones spin, since a
+// sleep takes at least a jiffy.
 pub fn wait_msec(n: u64) {
-    let (mut f, mut t, mut r): (u64, u64, u64);
-    unsafe {
-        asm!("mrs {}, cntfrq_el0", out(reg)f);
-        asm!("mrs {}, cntpct_el0", out(reg)t);
-        t += ((f / 1000).wrapping_mul(n)) / 1000;
-        asm!("mrs {}, cntpct_el0", out(reg)r);
-        while r < t {
-            asm!("mrs {}, cntpct_el0", out(reg)r);
-        }
+    if n * TICK_HZ >= 1000 {
+        crate::task::sleep(Duration::from_millis(n));
+    } else {
+        crate::time::delay(Duration::from_millis(n));
     }
 }
 
@@ -1 +1 @@
-
+pub mod rtc;
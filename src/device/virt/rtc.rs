@@ -0,0 +1,19 @@
+use crate::arch;
+use crate::arch::riscv64::address::GOLDFISH_RTC;
+use core::time::Duration;
+
+// https://android.googlesource.com/platform/external/qemu/+/master/docs/GOLDFISH-VIRTUAL-HARDWARE.TXT
+// Reading TIME_LOW latches TIME_HIGH.
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+// Time since the Unix epoch.
+pub fn read() -> Duration {
+    let base = arch::phys_to_virt(GOLDFISH_RTC);
+    let nanos = unsafe {
+        let low = ((base + TIME_LOW) as *const u32).read_volatile() as u64;
+        let high = ((base + TIME_HIGH) as *const u32).read_volatile() as u64;
+        (high << 32) | low
+    };
+    Duration::from_nanos(nanos)
+}
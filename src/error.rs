@@ -5,6 +5,7 @@ use alloc::string::*;
 pub enum VMError {
     Misaligned,
     NotFound,
+    OutOfRange,
 }
 
 #[derive(Debug)]
@@ -13,10 +14,24 @@ pub enum TaskError {
     DiskError(fatfs::Error<DiskError>),
     ExecParseError(goblin::error::Error),
     TaskNotFound(task::TaskId),
+    TaskRunning(task::TaskId),
     MapError(VMError),
+    LimitExceeded(task::Resource),
+    OutOfMemory,
 }
 
 #[derive(Debug)]
 pub enum DiskError {
     Dummy,
+    Device(BlockError),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    Io,
+    OutOfRange,
+    ReadOnly,
+    Misaligned,
+    NotFound,
+    AlreadyRegistered,
 }
@@ -1,5 +1,6 @@
 pub mod buffer;
 pub mod fat32;
+pub mod initrd;
 
 pub trait Size {
     fn size(&self) -> usize;
@@ -169,6 +169,7 @@ impl<R: Read + Write + Seek + IoBase> Write for Buffer<R> {
 
     fn flush(&mut self) -> Result<(), Self::Error> {
         self.cache.flush(&mut self.inner)?;
+        self.inner.flush()?;
         Ok(())
     }
 }
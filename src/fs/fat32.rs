@@ -1,26 +1,45 @@
-use crate::device::common::virtio::block::{BlockOpType, VirtIOBlock, VIRTIO_BLOCK};
+use crate::device::block::{BlockDevice, BLOCK_DEVICES};
 use crate::error::DiskError;
 use crate::fs::buffer::Buffer;
+use crate::fs::initrd;
 use crate::fs::Size;
 use crate::lazy::Lazy;
-use core::ops::DerefMut;
+use crate::time::FatTimeProvider;
+use alloc::vec;
 use fatfs::{IoBase, IoError, Read, Seek, Write};
 
 pub const BLOCK_SIZE: usize = crate::device::common::virtio::block::BLOCK_SIZE;
 
+// The board's disk. On raspi3b, init must have set up the SD card before the
+// volume is used.
+#[cfg(target_board = "raspi3b")]
+pub const DISK_DEVICE: &str = "mmcblk0";
+#[cfg(not(target_board = "raspi3b"))]
+pub const DISK_DEVICE: &str = "vda";
+
+// Block device the volume is on: the RAM disk with the boot image if there
+// is one, or else the board's disk.
+pub fn root_device() -> &'static str {
+    if unsafe { BLOCK_DEVICES.get(initrd::DEVICE).is_ok() } {
+        initrd::DEVICE
+    } else {
+        DISK_DEVICE
+    }
+}
+
 pub static mut FILE_SYSTEM: Lazy<
-    fatfs::FileSystem<Buffer<Disk>, fatfs::NullTimeProvider, fatfs::LossyOemCpConverter>,
+    fatfs::FileSystem<Buffer<Disk>, FatTimeProvider, fatfs::LossyOemCpConverter>,
 > = Lazy::<
-    fatfs::FileSystem<Buffer<Disk>, fatfs::NullTimeProvider, fatfs::LossyOemCpConverter>,
-    fn() -> fatfs::FileSystem<
-        Buffer<Disk<'static>>,
-        fatfs::NullTimeProvider,
-        fatfs::LossyOemCpConverter,
-    >,
+    fatfs::FileSystem<Buffer<Disk>, FatTimeProvider, fatfs::LossyOemCpConverter>,
+    fn() -> fatfs::FileSystem<Buffer<Disk<'static>>, FatTimeProvider, fatfs::LossyOemCpConverter>,
 >::new(|| unsafe {
+    let name = root_device();
+    let device = BLOCK_DEVICES
+        .get(name)
+        .unwrap_or_else(|e| panic!("fat32: {}: {:?}", name, e));
     fatfs::FileSystem::new(
-        Buffer::new(Disk::new(VIRTIO_BLOCK.deref_mut())),
-        fatfs::FsOptions::new(),
+        Buffer::new(Disk::new(device)),
+        fatfs::FsOptions::new().time_provider(FatTimeProvider::new()),
     )
     .unwrap()
 });
@@ -39,13 +58,14 @@ impl IoError for DiskError {
     }
 }
 
+// Byte-addressed view of a block device.
 pub struct Disk<'r> {
-    raw: &'r mut VirtIOBlock<'r>,
+    raw: &'r mut dyn BlockDevice,
     pos: usize,
 }
 
 impl<'r> Disk<'r> {
-    pub fn new(raw: &'r mut VirtIOBlock<'r>) -> Self {
+    pub fn new(raw: &'r mut dyn BlockDevice) -> Self {
         Self { raw, pos: 0 }
     }
 }
@@ -55,28 +75,30 @@ impl<'r> IoBase for Disk<'r> {
 }
 
 impl<'r> Read for Disk<'r> {
-    // TODO: refine
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        let len = buf.len() / BLOCK_SIZE + (if buf.len() % BLOCK_SIZE != 0 { 1 } else { 0 });
-        let sector = self.pos / BLOCK_SIZE;
-        let max_size = self.raw.size() * BLOCK_SIZE;
+        let block_size = self.raw.block_size();
+        let len = usize::min(buf.len(), self.size().saturating_sub(self.pos));
         let mut read_count: usize = 0;
-        let mut tmp_buf: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
-        for i in sector..(sector + len) {
-            if self.pos >= max_size {
-                break;
-            }
-            self.raw
-                .block_op(tmp_buf.as_mut_ptr(), i as u64, BlockOpType::Read);
-            let start = self.pos % BLOCK_SIZE;
-            let copy_amount = usize::min(buf.len() - read_count, BLOCK_SIZE - start);
-            unsafe {
-                core::ptr::copy_nonoverlapping(
-                    tmp_buf.as_ptr().add(start),
-                    buf.as_mut_ptr().add(read_count),
-                    copy_amount,
-                );
-            }
+        while read_count < len {
+            let block = self.pos / block_size;
+            let start = self.pos % block_size;
+            let copy_amount = if start == 0 && len - read_count >= block_size {
+                // whole blocks go straight into buf
+                let amount = (len - read_count) / block_size * block_size;
+                self.raw
+                    .read_blocks(block, &mut buf[read_count..read_count + amount])
+                    .map_err(DiskError::Device)?;
+                amount
+            } else {
+                let mut tmp_buf = vec![0; block_size];
+                self.raw
+                    .read_blocks(block, &mut tmp_buf)
+                    .map_err(DiskError::Device)?;
+                let amount = usize::min(len - read_count, block_size - start);
+                buf[read_count..read_count + amount]
+                    .copy_from_slice(&tmp_buf[start..start + amount]);
+                amount
+            };
             self.pos += copy_amount;
             read_count += copy_amount;
         }
@@ -87,28 +109,32 @@ impl<'r> Read for Disk<'r> {
 
 impl<'r> Write for Disk<'r> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        let len = buf.len() / BLOCK_SIZE + (if buf.len() % BLOCK_SIZE != 0 { 1 } else { 0 });
-        let sector = self.pos / BLOCK_SIZE;
-        let max_size = self.raw.size() * BLOCK_SIZE;
+        let block_size = self.raw.block_size();
+        let len = usize::min(buf.len(), self.size().saturating_sub(self.pos));
         let mut write_count = 0;
-        for i in sector..(sector + len) {
-            if self.pos >= max_size {
-                break;
-            }
-            let start = self.pos % BLOCK_SIZE;
-            let copy_amount = usize::min(buf.len() - write_count, BLOCK_SIZE - start);
-            let mut tmp_buf: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
-            self.raw
-                .block_op(tmp_buf.as_mut_ptr(), i as u64, BlockOpType::Read);
-            unsafe {
-                core::ptr::copy_nonoverlapping(
-                    buf.as_ptr().add(write_count),
-                    tmp_buf.as_mut_ptr().add(start),
-                    copy_amount,
-                );
-            }
-            self.raw
-                .block_op(tmp_buf.as_mut_ptr(), i as u64, BlockOpType::Write);
+        while write_count < len {
+            let block = self.pos / block_size;
+            let start = self.pos % block_size;
+            let copy_amount = if start == 0 && len - write_count >= block_size {
+                let amount = (len - write_count) / block_size * block_size;
+                self.raw
+                    .write_blocks(block, &buf[write_count..write_count + amount])
+                    .map_err(DiskError::Device)?;
+                amount
+            } else {
+                // the rest of the block is kept
+                let mut tmp_buf = vec![0; block_size];
+                self.raw
+                    .read_blocks(block, &mut tmp_buf)
+                    .map_err(DiskError::Device)?;
+                let amount = usize::min(len - write_count, block_size - start);
+                tmp_buf[start..start + amount]
+                    .copy_from_slice(&buf[write_count..write_count + amount]);
+                self.raw
+                    .write_blocks(block, &tmp_buf)
+                    .map_err(DiskError::Device)?;
+                amount
+            };
             self.pos += copy_amount;
             write_count += copy_amount;
         }
@@ -117,7 +143,7 @@ impl<'r> Write for Disk<'r> {
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
-        Ok(())
+        self.raw.flush().map_err(DiskError::Device)
     }
 }
 
@@ -139,6 +165,6 @@ impl<'r> Seek for Disk<'r> {
 
 impl<'r> Size for Disk<'r> {
     fn size(&self) -> usize {
-        self.raw.size() * BLOCK_SIZE
+        self.raw.blocks() * self.raw.block_size()
     }
 }
@@ -0,0 +1,146 @@
+use crate::device::block::BLOCK_DEVICES;
+use crate::device::ramdisk::{RamDisk, BLOCK_SIZE};
+use crate::fs::fat32::Disk;
+use crate::time::FatTimeProvider;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use fatfs::Write;
+use log::{info, warn};
+
+// Boot image built into the kernel, empty if there is none: a FAT image, or a
+// cpio archive in the newc format that is unpacked onto a new FAT volume.
+static IMAGE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd"));
+
+// the RAM disk the image is put on
+pub const DEVICE: &str = "ram0";
+
+const CPIO_MAGIC: &[u8; 6] = b"070701";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+// Room on the unpacked volume besides the file data: FAT structures, plus
+// the partly used last cluster of every entry.
+const UNPACK_SLACK: usize = 1 << 20;
+const UNPACK_SLACK_PER_ENTRY: usize = 4096;
+
+pub struct CpioEntry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+// Field `index` of a newc header: 8 hex digits after the magic.
+fn hex_field(header: &[u8], index: usize) -> Result<usize, &'static str> {
+    let field = &header[6 + 8 * index..14 + 8 * index];
+    core::str::from_utf8(field)
+        .ok()
+        .and_then(|field| usize::from_str_radix(field, 16).ok())
+        .ok_or("bad header field")
+}
+
+// Lists the entries of a newc archive, up to the trailer. Names are relative
+// to the root, without a leading "./".
+pub fn cpio_entries(archive: &[u8]) -> Result<Vec<CpioEntry>, &'static str> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = archive
+            .get(offset..offset + CPIO_HEADER_SIZE)
+            .ok_or("truncated header")?;
+        if &header[..6] != CPIO_MAGIC {
+            return Err("bad magic");
+        }
+        let mode = hex_field(header, 1)? as u32;
+        let file_size = hex_field(header, 6)?;
+        let name_size = hex_field(header, 11)?;
+        // the name ends with a NUL
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name = archive
+            .get(name_start..name_start + name_size.saturating_sub(1))
+            .ok_or("truncated name")?;
+        let name = core::str::from_utf8(name).map_err(|_| "name is not UTF-8")?;
+        let data_start = align4(name_start + name_size);
+        let data = archive
+            .get(data_start..data_start + file_size)
+            .ok_or("truncated data")?;
+        if name == CPIO_TRAILER {
+            return Ok(entries);
+        }
+        let name = name.trim_start_matches("./");
+        if !name.is_empty() && name != "." {
+            entries.push(CpioEntry { name, mode, data });
+        }
+        offset = align4(data_start + file_size);
+    }
+}
+
+// Formats a RAM disk that fits the archive and copies its directories and
+// regular files onto it.
+fn unpack(archive: &[u8]) -> Result<RamDisk, &'static str> {
+    let entries = cpio_entries(archive)?;
+    let data: usize = entries.iter().map(|entry| entry.data.len()).sum();
+    let size = data + entries.len() * UNPACK_SLACK_PER_ENTRY + UNPACK_SLACK;
+    let mut disk = RamDisk::new((size + BLOCK_SIZE - 1) / BLOCK_SIZE);
+    fatfs::format_volume(&mut Disk::new(&mut disk), fatfs::FormatVolumeOptions::new())
+        .map_err(|_| "cannot format the RAM disk")?;
+
+    let fs = fatfs::FileSystem::new(
+        Disk::new(&mut disk),
+        fatfs::FsOptions::new().time_provider(FatTimeProvider::new()),
+    )
+    .map_err(|_| "cannot mount the RAM disk")?;
+    let root_dir = fs.root_dir();
+    for entry in entries.iter() {
+        match entry.mode & S_IFMT {
+            S_IFDIR => {
+                root_dir
+                    .create_dir(entry.name)
+                    .map_err(|_| "cannot create a directory")?;
+            }
+            S_IFREG => {
+                let mut file = root_dir
+                    .create_file(entry.name)
+                    .map_err(|_| "cannot create a file")?;
+                file.truncate().map_err(|_| "cannot create a file")?;
+                file.write_all(entry.data)
+                    .map_err(|_| "cannot write a file")?;
+            }
+            _ => warn!("initrd: {}: skipped, not a file or directory", entry.name),
+        }
+    }
+    drop(root_dir);
+    fs.unmount().map_err(|_| "cannot unmount the RAM disk")?;
+    Ok(disk)
+}
+
+// Puts the boot image, if there is one, on the RAM disk DEVICE, which then
+// holds the root volume.
+pub fn init() {
+    if IMAGE.is_empty() {
+        return;
+    }
+    let disk = if IMAGE.starts_with(CPIO_MAGIC) {
+        match unpack(IMAGE) {
+            Ok(disk) => disk,
+            Err(reason) => {
+                warn!("initrd: {}", reason);
+                return;
+            }
+        }
+    } else {
+        RamDisk::from_image(IMAGE)
+    };
+    info!("initrd: {} bytes", IMAGE.len());
+    unsafe {
+        BLOCK_DEVICES
+            .register(DEVICE, Box::leak(Box::new(disk)))
+            .unwrap();
+    }
+}
@@ -27,7 +27,7 @@ impl Backup for DummyBackup {
 #[cfg(target_arch = "riscv64")]
 pub type ArchInterruptFlag = InterruptFlag<crate::arch::riscv64::riscv::InterruptFlag>;
 #[cfg(target_arch = "aarch64")]
-pub type ArchInterruptFlag = InterruptFlag<DummyBackup>;
+pub type ArchInterruptFlag = InterruptFlag<crate::arch::aarch64::arm::InterruptFlag>;
 #[cfg(target_arch = "x86_64")]
 pub type ArchInterruptFlag = InterruptFlag<DummyBackup>;
 
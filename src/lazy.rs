@@ -82,6 +82,12 @@ impl<T, F> Lazy<T, F> {
             init: Cell::new(Some(init)),
         }
     }
+
+    // The value, if it has been initialized. Not a method, so that it does
+    // not hide methods of T.
+    pub fn get(this: &Lazy<T, F>) -> Option<&T> {
+        this.cell.get()
+    }
 }
 
 impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
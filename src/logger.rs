@@ -1,31 +1,328 @@
+pub mod ring;
+
 use crate::*;
-use log::{Level, LevelFilter, Metadata, Record};
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use log::{LevelFilter, Metadata, Record};
+use ring::LogRing;
 
 static LOGGER: KernelLogger = KernelLogger {};
 
+pub const LOG_BUFFER_SIZE: usize = 0x10000;
+// longer lines are cut
+const LINE_SIZE: usize = 256;
+const MAX_TARGETS: usize = 8;
+const TARGET_SIZE: usize = 48;
+
+// Level spec from kernel.toml's `log`, or from LOG when building, e.g.
+// `info,neverland::task=debug,fatfs=warn`.
+const BOOT_LEVELS: &str = env!("KERNEL_LOG");
+// FAT file the log is mirrored to; empty for none.
+const BOOT_LOG_FILE: &str = env!("KERNEL_LOG_FILE");
+
+pub static RING: spin::Mutex<LogRing<LOG_BUFFER_SIZE>> = spin::Mutex::new(LogRing::new());
+
+// Runs `f` with interrupts off. Interrupt handlers log too, and would spin
+// forever on a lock held by the code they interrupted.
+fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = arch::is_interrupt_on();
+    if enabled {
+        arch::interrupt_off();
+    }
+    let result = f();
+    if enabled {
+        arch::interrupt_on();
+    }
+    result
+}
+
+// Runs `f` with the ring locked and interrupts off.
+pub fn with_ring<R>(f: impl FnOnce(&mut LogRing<LOG_BUFFER_SIZE>) -> R) -> R {
+    without_interrupts(|| f(&mut RING.lock()))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogError {
+    InvalidLevel,
+    TooManyTargets,
+    TargetTooLong,
+}
+
+#[derive(Copy, Clone)]
+struct TargetLevel {
+    target: [u8; TARGET_SIZE],
+    len: usize,
+    level: LevelFilter,
+}
+
+impl TargetLevel {
+    const EMPTY: TargetLevel = TargetLevel {
+        target: [0; TARGET_SIZE],
+        len: 0,
+        level: LevelFilter::Off,
+    };
+
+    fn target(&self) -> &str {
+        core::str::from_utf8(&self.target[..self.len]).unwrap_or("")
+    }
+
+    // `target` itself or one of its submodules
+    fn matches(&self, target: &str) -> bool {
+        let prefix = self.target();
+        target.starts_with(prefix)
+            && (target.len() == prefix.len() || target[prefix.len()..].starts_with("::"))
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Levels {
+    default: LevelFilter,
+    targets: [TargetLevel; MAX_TARGETS],
+    count: usize,
+}
+
+impl Levels {
+    const fn new() -> Self {
+        Self {
+            default: LevelFilter::Info,
+            targets: [TargetLevel::EMPTY; MAX_TARGETS],
+            count: 0,
+        }
+    }
+
+    // The most specific target setting wins.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets[..self.count]
+            .iter()
+            .filter(|t| t.matches(target))
+            .max_by_key(|t| t.len)
+            .map_or(self.default, |t| t.level)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.targets[..self.count]
+            .iter()
+            .map(|t| t.level)
+            .fold(self.default, LevelFilter::max)
+    }
+
+    fn set(&mut self, target: &str, level: LevelFilter) -> Result<(), LogError> {
+        if target.len() > TARGET_SIZE {
+            return Err(LogError::TargetTooLong);
+        }
+        let index = match self.targets[..self.count]
+            .iter()
+            .position(|t| t.target() == target)
+        {
+            Some(index) => index,
+            None if self.count < MAX_TARGETS => {
+                self.count += 1;
+                self.count - 1
+            }
+            None => return Err(LogError::TooManyTargets),
+        };
+        let entry = &mut self.targets[index];
+        entry.target[..target.len()].copy_from_slice(target.as_bytes());
+        entry.len = target.len();
+        entry.level = level;
+        Ok(())
+    }
+}
+
+static LEVELS: spin::Mutex<Levels> = spin::Mutex::new(Levels::new());
+
+// Like with_ring(), for the levels.
+fn with_levels<R>(f: impl FnOnce(&mut Levels) -> R) -> R {
+    without_interrupts(|| f(&mut LEVELS.lock()))
+}
+
+// the log file and the first record not yet written to it
+static LOG_FILE: spin::Mutex<Option<(&'static str, u64)>> = spin::Mutex::new(None);
+
+// Formats into a fixed buffer, cutting what does not fit.
+struct Line {
+    buf: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(LINE_SIZE - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
 pub struct KernelLogger {}
 
 impl log::Log for KernelLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Info
+        metadata.level() <= with_levels(|levels| levels.level(metadata.target()))
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            println!(
-                "[{}] {} - {}",
-                record.target(),
-                record.level(),
-                record.args()
-            );
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let micros = time::monotonic().as_micros() as u64;
+        let mut line = Line {
+            buf: [0; LINE_SIZE],
+            len: 0,
+        };
+        let _ = write!(
+            line,
+            "[{:>5}.{:06}] {} {:<5} {}: {}",
+            micros / 1_000_000,
+            micros % 1_000_000,
+            arch::cpu_id(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        // always end with a newline, even if the line was cut
+        line.len = line.len.min(LINE_SIZE - 1);
+        line.buf[line.len] = b'\n';
+        line.len += 1;
+
+        let text = &line.buf[..line.len];
+        println!(
+            "{}",
+            core::str::from_utf8(&text[..text.len() - 1]).unwrap_or("?")
+        );
+        with_ring(|ring| ring.push(text));
+    }
+
+    fn flush(&self) {
+        flush_log_file();
+    }
+}
+
+// Sets levels from a spec such as `info,neverland::task=debug`: a bare level
+// is the default, `target=level` applies to a target and its submodules.
+pub fn set_levels(spec: &str) -> Result<(), LogError> {
+    let mut levels = with_levels(|levels| *levels);
+    for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        match directive.split_once('=') {
+            Some((target, level)) => {
+                let level = LevelFilter::from_str(level.trim()).or(Err(LogError::InvalidLevel))?;
+                levels.set(target.trim(), level)?;
+            }
+            None => {
+                levels.default =
+                    LevelFilter::from_str(directive).or(Err(LogError::InvalidLevel))?;
+            }
+        }
+    }
+    with_levels(|current| *current = levels);
+    log::set_max_level(levels.max());
+    Ok(())
+}
+
+pub fn set_level(level: LevelFilter) {
+    with_levels(|levels| {
+        levels.default = level;
+        log::set_max_level(levels.max());
+    });
+}
+
+pub fn level() -> LevelFilter {
+    with_levels(|levels| levels.default)
+}
+
+pub fn dump_levels() {
+    let levels = with_levels(|levels| *levels);
+    println!("log level: {}", levels.default);
+    for target in levels.targets[..levels.count].iter() {
+        println!("  {}={}", target.target(), target.level);
+    }
+}
+
+// Calls `f` with the text held in the ring, starting at record `from`.
+// Returns the number of the next record.
+pub fn read_log(from: u64, mut f: impl FnMut(&[u8])) -> u64 {
+    let mut buf = [0u8; LINE_SIZE * 4];
+    let mut seq = from;
+    loop {
+        let (len, next) = with_ring(|ring| ring.read(seq, &mut buf));
+        if len == 0 {
+            return next;
         }
+        f(&buf[..len]);
+        seq = next;
     }
+}
+
+// Prints the ring, like dmesg.
+pub fn dump_log() {
+    read_log(0, |text| {
+        print!("{}", core::str::from_utf8(text).unwrap_or("?"));
+    });
+}
 
-    fn flush(&self) {}
+// Mirrors the log to `name` on the FAT volume from now on, or stops with
+// None. Records are appended by flush_log_file().
+pub fn set_log_file(name: Option<&'static str>) {
+    let first = with_ring(|ring| ring.first_seq());
+    *LOG_FILE.lock() = name.map(|name| (name, first));
 }
 
+#[cfg(any(target_board = "virt", target_board = "raspi3b"))]
+pub fn flush_log_file() {
+    use fatfs::{Seek, SeekFrom};
+
+    let (name, from) = match *LOG_FILE.lock() {
+        Some(file) => file,
+        None => return,
+    };
+    if from == with_ring(|ring| ring.next_seq()) {
+        return;
+    }
+    // records logged while writing are written by the next call
+    let root_dir = unsafe { fs::fat32::FILE_SYSTEM.root_dir() };
+    let mut file = match root_dir.create_file(name) {
+        Ok(file) => file,
+        Err(_) => return,
+    };
+    if file.seek(SeekFrom::End(0)).is_err() {
+        return;
+    }
+    let mut failed = false;
+    let next = read_log(from, |text| {
+        failed = failed || fatfs::Write::write_all(&mut file, text).is_err();
+    });
+    if let Some(file) = LOG_FILE.lock().as_mut() {
+        file.1 = next;
+    }
+    if failed {
+        *LOG_FILE.lock() = None;
+        println!("log file {}: write error, mirroring stopped", name);
+    }
+}
+
+#[cfg(not(any(target_board = "virt", target_board = "raspi3b")))]
+pub fn flush_log_file() {}
+
 pub fn init_logger() {
-    log::set_logger(&LOGGER)
-        .map(|()| log::set_max_level(LevelFilter::Info))
-        .unwrap();
+    log::set_logger(&LOGGER).unwrap();
+    if set_levels(BOOT_LEVELS).is_err() {
+        set_level(LevelFilter::Info);
+        println!("invalid log levels: {}", BOOT_LEVELS);
+    }
+    if !BOOT_LOG_FILE.is_empty() {
+        set_log_file(Some(BOOT_LOG_FILE));
+    }
+}
+
+// Log levels as numbered by syslog(2), where 0 is the most severe.
+pub fn level_from_syslog(level: usize) -> Option<LevelFilter> {
+    match level {
+        0 => Some(LevelFilter::Off),
+        1..=3 => Some(LevelFilter::Error),
+        4 => Some(LevelFilter::Warn),
+        5 | 6 => Some(LevelFilter::Info),
+        7 => Some(LevelFilter::Debug),
+        8 => Some(LevelFilter::Trace),
+        _ => None,
+    }
 }
@@ -0,0 +1,123 @@
+// Byte ring of log lines. Each record is its length (u16, little-endian)
+// followed by the text. Records are numbered from 0; when the ring is full the
+// oldest ones are dropped.
+pub struct LogRing<const N: usize> {
+    buf: [u8; N],
+    // offset of the oldest record
+    start: usize,
+    len: usize,
+    // sequence number of the oldest record
+    first: u64,
+    // sequence number of the next record
+    next: u64,
+}
+
+const LENGTH_SIZE: usize = 2;
+
+impl<const N: usize> LogRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            start: 0,
+            len: 0,
+            first: 0,
+            next: 0,
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    // bytes of text held
+    pub fn text_len(&self) -> usize {
+        self.len - (self.next - self.first) as usize * LENGTH_SIZE
+    }
+
+    pub fn first_seq(&self) -> u64 {
+        self.first
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next
+    }
+
+    fn byte(&self, offset: usize) -> u8 {
+        self.buf[(self.start + offset) % N]
+    }
+
+    fn record_len(&self, offset: usize) -> usize {
+        u16::from_le_bytes([self.byte(offset), self.byte(offset + 1)]) as usize
+    }
+
+    fn drop_oldest(&mut self) {
+        let size = LENGTH_SIZE + self.record_len(0);
+        self.start = (self.start + size) % N;
+        self.len -= size;
+        self.first += 1;
+    }
+
+    // Appends a record. Text that does not fit the ring is cut.
+    pub fn push(&mut self, text: &[u8]) {
+        let text = &text[..text.len().min(N - LENGTH_SIZE).min(u16::MAX as usize)];
+        let size = LENGTH_SIZE + text.len();
+        while self.len + size > N {
+            self.drop_oldest();
+        }
+        let length = (text.len() as u16).to_le_bytes();
+        for (i, b) in length.iter().chain(text.iter()).enumerate() {
+            self.buf[(self.start + self.len + i) % N] = *b;
+        }
+        self.len += size;
+        self.next += 1;
+    }
+
+    // Offset of record `seq`, which must be held.
+    fn offset_of(&self, seq: u64) -> usize {
+        let mut offset = 0;
+        for _ in self.first..seq {
+            offset += LENGTH_SIZE + self.record_len(offset);
+        }
+        offset
+    }
+
+    // The oldest record from which the text of all newer records fits in
+    // `size` bytes.
+    pub fn seq_fitting(&self, size: usize) -> u64 {
+        let mut text = self.text_len();
+        let mut offset = 0;
+        let mut seq = self.first;
+        while text > size {
+            let len = self.record_len(offset);
+            text -= len;
+            offset += LENGTH_SIZE + len;
+            seq += 1;
+        }
+        seq
+    }
+
+    // Copies the text of whole records, starting at record `from` (or the
+    // oldest one held), into `out`. Returns the bytes copied and the number of
+    // the first record not copied.
+    pub fn read(&self, from: u64, out: &mut [u8]) -> (usize, u64) {
+        let mut seq = from.max(self.first);
+        if seq >= self.next {
+            return (0, self.next);
+        }
+        let mut offset = self.offset_of(seq);
+        let mut copied = 0;
+        while seq < self.next {
+            let len = self.record_len(offset);
+            if copied + len > out.len() {
+                break;
+            }
+            for i in 0..len {
+                out[copied + i] = self.byte(offset + LENGTH_SIZE + i);
+            }
+            copied += len;
+            offset += LENGTH_SIZE + len;
+            seq += 1;
+        }
+        (copied, seq)
+    }
+}
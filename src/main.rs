@@ -17,6 +17,8 @@ extern crate alloc;
 
 pub mod allocator;
 pub mod arch;
+pub mod backtrace;
+pub mod crashdump;
 pub mod device;
 pub mod error;
 pub mod fs;
@@ -27,8 +29,11 @@ pub mod panic;
 pub mod print;
 pub mod sandbox;
 pub mod sync;
+pub mod syscall;
 pub mod task;
 pub mod test;
+pub mod time;
+pub mod timer;
 
 use core::arch::asm;
 use log::info;
@@ -78,20 +83,22 @@ pub unsafe extern "C" fn main() -> ! {
     logger::init_logger();
     allocator::init_allocator();
 
-    #[cfg(test)]
-    test_main();
-
     println!("PRESENT DAY\n  PRESENT TIME");
 
     info!("Arch: RISC-V");
     info!("Core: {}", riscv64::riscv::STATE.cpuid());
+    time::init();
 
     riscv64::vm::VM_MANAGER.init();
 
     task::TASK_MANAGER.init().unwrap();
 
+    // tests get address spaces and tasks to work with
+    #[cfg(test)]
+    test_main();
+
     let id = task::TASK_MANAGER
-        .create_task("init", init as usize)
+        .create_task(task::INIT_TASK, init as usize)
         .unwrap();
     task::TASK_MANAGER.ready_task(id);
     task::TASK_MANAGER.schedule();
@@ -109,22 +116,35 @@ pub unsafe extern "C" fn main() -> ! {
     logger::init_logger();
     allocator::init_allocator();
 
-    #[cfg(test)]
-    test_main();
-
     println!("PRESENT DAY\n  PRESENT TIME");
 
     info!("Arch: AArch64");
     info!("Core: {}", crate::arch::aarch64::arm::STATE.cpuid());
+    time::init();
 
     aarch64::vm::VM_MANAGER.init();
+    device::raspi3b::irq::IRQ_MANAGER.init();
 
     task::TASK_MANAGER.init().unwrap();
 
+    // tests get address spaces and tasks to work with
+    #[cfg(test)]
+    test_main();
+
+    device::raspi3b::irq::IRQ_MANAGER.register(
+        device::raspi3b::irq::Irq::Local(device::raspi3b::irq::LOCAL_CNTPNS),
+        aarch64::arm::timer_interrupt,
+    );
+    aarch64::arm::timer_init();
+    aarch64::smp::start_secondary_cores();
+
     let id = task::TASK_MANAGER
-        .create_task("init", init as usize)
+        .create_task(task::INIT_TASK, init as usize)
         .unwrap();
     task::TASK_MANAGER.ready_task(id);
+    // This context is not switched back to, so no core spins in the loop
+    // below holding the kernel lock.
+    task::TASK_MANAGER.block_current();
     task::TASK_MANAGER.schedule();
 
     loop {}
@@ -144,6 +164,7 @@ pub unsafe extern "C" fn main(
         }
     }
     println!("Hello, world");
+    time::init();
     loop {}
 }
 
@@ -157,6 +178,11 @@ pub unsafe extern "C" fn init() {
 
     riscv64::plic::PLIC_MANAGER.init_irq(riscv64::plic::PlicIRQ::VirtIO0);
     virtio::block::VIRTIO_BLOCK.init(riscv64::address::_virtio_start as usize);
+    device::block::BLOCK_DEVICES
+        .register("vda", &mut *virtio::block::VIRTIO_BLOCK)
+        .unwrap();
+    fs::initrd::init();
+    crashdump::recover();
 
     let root_dir = fs::fat32::FILE_SYSTEM.root_dir();
     root_dir.create_file("bbb.txt").unwrap();
@@ -165,6 +191,7 @@ pub unsafe extern "C" fn init() {
     }
 
     loop {
+        logger::flush_log_file();
         task::TASK_MANAGER.schedule();
     }
 }
@@ -174,7 +201,6 @@ pub unsafe extern "C" fn init() {
     use crate::device::raspi3b::framebuffer::*;
     use crate::device::raspi3b::mailbox::*;
     use crate::device::raspi3b::*;
-    use alloc::alloc::{alloc_zeroed, Layout};
 
     info!("init");
 
@@ -206,25 +232,22 @@ pub unsafe extern "C" fn init() {
     if sd::SDCARD.sd_init() != sd::SDError::SD_OK.bits() {
         panic!("SDError");
     }
-    let buf = {
-        let layout = Layout::from_size_align(512, 512).unwrap();
-        alloc_zeroed(layout)
-    };
-
-    sd::SDCARD.sd_readblock(0, buf, 1);
+    device::block::BLOCK_DEVICES
+        .register("mmcblk0", &mut *sd::SDCARD)
+        .unwrap();
+    fs::initrd::init();
+    crashdump::recover();
 
-    for i in 0..512 {
-        if i % 8 == 0 {
-            println!();
-        }
-        let ch = buf.add(i).read();
-        print!("{:02x} ", ch);
+    let root_dir = fs::fat32::FILE_SYSTEM.root_dir();
+    for e in root_dir.iter().map(|e| e.unwrap()) {
+        println!("{}", e.file_name());
     }
-    println!();
 
     sandbox::dwc::dwc();
 
+    // sleeping leaves the kernel lock to the other cores
     loop {
-        task::TASK_MANAGER.schedule();
+        logger::flush_log_file();
+        task::sleep(core::time::Duration::from_secs(1));
     }
 }
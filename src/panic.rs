@@ -1,8 +1,18 @@
 use crate::*;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
 
 #[panic_handler]
 #[allow(unreachable_code)]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    // a panic while reporting one only prints its message
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        println!("Panic while panicking: {}", info);
+        loop {}
+    }
+    let started = crashdump::begin();
+
     print!("Panic: ");
     if let Some(location) = info.location() {
         println!(
@@ -14,6 +24,11 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     } else {
         println!("No information available");
     }
+    // traps print the registers of their frame before they panic
+    if started {
+        arch::dump_current_registers();
+    }
+    backtrace::print_backtrace();
 
     #[cfg(test)]
     {
@@ -21,5 +36,7 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
         test::exit_failure();
     }
 
+    crashdump::finish();
+
     loop {}
 }
@@ -1,21 +1,7 @@
 #[macro_export]
 macro_rules! print {
     ($($args:tt)+) => {{
-		#[cfg(any(target_arch = "riscv64", target_arch = "x86_64"))]
-		#[cfg(target_board = "virt")]
-		#[allow(unused_unsafe)]
-        {
-            use core::fmt::Write;
-            let _ = unsafe { write!(crate::device::common::uart::UART, $($args)+) };
-        }
-
-		#[cfg(target_arch = "aarch64")]
-		#[cfg(target_board = "raspi3b")]
-		#[allow(unused_unsafe)]
-        {
-            use core::fmt::Write;
-            let _ = unsafe { write!(crate::device::raspi3b::uart::UART, $($args)+) };
-        }
+        $crate::print::_print(format_args!($($args)+))
     }};
 }
 
@@ -32,3 +18,22 @@ macro_rules! println
 		print!(concat!($fmt, "\r\n"), $($args)+)
 	});
 }
+
+#[allow(unused_variables)]
+pub fn _print(args: core::fmt::Arguments) {
+    #[cfg(any(target_arch = "riscv64", target_arch = "x86_64"))]
+    #[cfg(target_board = "virt")]
+    {
+        use core::fmt::Write;
+        let _ = unsafe { crate::device::common::uart::UART.write_fmt(args) };
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[cfg(target_board = "raspi3b")]
+    {
+        use core::fmt::Write;
+        let _ = unsafe { crate::device::raspi3b::uart::UART.write_fmt(args) };
+    }
+
+    crate::crashdump::capture(args);
+}
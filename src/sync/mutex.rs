@@ -4,11 +4,12 @@ use const_default::ConstDefault;
 use core::cell::UnsafeCell;
 use core::convert::{AsMut, AsRef};
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 pub struct KernelLock {
     locked: AtomicBool,
-    // cpu_id: UnsafeCell<Option<CpuId>>,
+    // core holding the lock
+    owner: AtomicUsize,
     intr: AtomicBool,
     // intr_flag: UnsafeCell<ArchInterruptFlag>,
 }
@@ -20,6 +21,7 @@ impl KernelLock {
     pub const fn new() -> Self {
         Self {
             locked: AtomicBool::new(false),
+            owner: AtomicUsize::new(usize::MAX),
             intr: AtomicBool::new(false),
         }
     }
@@ -31,6 +33,7 @@ impl KernelLock {
                 self.locked
                     .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
             {
+                self.owner.store(arch::cpu_id(), Ordering::Relaxed);
                 // *self.intr_flag.get() = ArchInterruptFlag::save_and_off();
                 arch::interrupt_off();
                 break;
@@ -40,11 +43,24 @@ impl KernelLock {
 
     #[allow(unused_variables)]
     pub unsafe fn unlock(&self) {
+        self.owner.store(usize::MAX, Ordering::Relaxed);
         self.locked.store(false, Ordering::Release);
         // self.intr_flag.get().as_ref().unwrap().restore();
         arch::interrupt_on();
     }
 
+    // Whether this core holds the lock.
+    pub fn is_held(&self) -> bool {
+        self.locked.load(Ordering::Acquire) && self.owner.load(Ordering::Relaxed) == arch::cpu_id()
+    }
+
+    // Lets go of the lock but leaves interrupts alone, for leaving the kernel
+    // through eret, which restores them from the saved state.
+    pub unsafe fn release(&self) {
+        self.owner.store(usize::MAX, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+    }
+
     pub unsafe fn complete_intr(&self) {
         self.intr.store(true, Ordering::SeqCst);
         // self.intr.get().write(true);
@@ -0,0 +1,269 @@
+use crate::error::{TaskError, VMError};
+use crate::logger;
+use crate::task::{self, Resource, TaskId, TaskManager, TASK_MANAGER};
+use alloc::vec;
+use core::time::Duration;
+use log::info;
+
+// Linux's generic system call numbers, which riscv64 and aarch64 share.
+pub const SYS_NANOSLEEP: usize = 101;
+pub const SYS_GETITIMER: usize = 102;
+pub const SYS_SETITIMER: usize = 103;
+pub const SYS_SYSLOG: usize = 116;
+pub const SYS_BRK: usize = 214;
+pub const SYS_MUNMAP: usize = 215;
+pub const SYS_MMAP: usize = 222;
+pub const SYS_MPROTECT: usize = 226;
+
+pub const EPERM: isize = 1;
+pub const ENOMEM: isize = 12;
+pub const EFAULT: isize = 14;
+pub const ENODEV: isize = 19;
+pub const EINVAL: isize = 22;
+pub const ENOSYS: isize = 38;
+
+const PROT_READ: usize = 0x1;
+const PROT_WRITE: usize = 0x2;
+const PROT_EXEC: usize = 0x4;
+
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+
+// syslog(2) actions
+const SYSLOG_ACTION_READ_ALL: usize = 3;
+const SYSLOG_ACTION_CONSOLE_LEVEL: usize = 8;
+const SYSLOG_ACTION_SIZE_UNREAD: usize = 9;
+const SYSLOG_ACTION_SIZE_BUFFER: usize = 10;
+// ours: set levels from a spec such as `info,neverland::task=debug` in buf,
+// and print the levels or the whole ring to the console
+const SYSLOG_ACTION_SET_LEVELS: usize = 100;
+const SYSLOG_ACTION_PRINT_LEVELS: usize = 101;
+const SYSLOG_ACTION_PRINT_ALL: usize = 102;
+// with the debug allocator: check every redzone, then print the allocations
+// made after mark `len` and return how many there are
+#[cfg(allocator = "Debug")]
+const SYSLOG_ACTION_CHECK_HEAP: usize = 103;
+// longest level spec SET_LEVELS accepts
+const LEVELS_SPEC_SIZE: usize = 256;
+
+const ITIMER_REAL: usize = 0;
+
+pub fn errno(error: &TaskError) -> isize {
+    match error {
+        TaskError::OutOfMemory
+        | TaskError::LimitExceeded(Resource::Memory)
+        | TaskError::MapError(VMError::NotFound)
+        | TaskError::MapError(VMError::OutOfRange) => ENOMEM,
+        _ => EINVAL,
+    }
+}
+
+fn prot(prot: usize) -> (bool, bool, bool) {
+    task::page_access(
+        prot & PROT_READ != 0,
+        prot & PROT_WRITE != 0,
+        prot & PROT_EXEC != 0,
+    )
+}
+
+// Only anonymous mappings are supported. Without fork, shared and private
+// mappings behave the same.
+fn mmap(task_manager: &mut TaskManager, id: TaskId, args: [usize; 6]) -> Result<usize, isize> {
+    let [addr, len, prot_bits, flags, _fd, _offset] = args;
+    if flags & MAP_ANONYMOUS == 0 {
+        return Err(ENODEV);
+    }
+    if len == 0 {
+        return Err(EINVAL);
+    }
+    let (r, w, x) = prot(prot_bits);
+    if flags & MAP_FIXED != 0 {
+        task_manager.mmap_fixed(id, addr, len, r, w, x)
+    } else {
+        task_manager.mmap(id, addr, len, r, w, x)
+    }
+    .map_err(|e| errno(&e))
+}
+
+fn munmap(task_manager: &mut TaskManager, id: TaskId, args: [usize; 6]) -> Result<usize, isize> {
+    let [addr, len, ..] = args;
+    if len == 0 {
+        return Err(EINVAL);
+    }
+    task_manager
+        .munmap(id, addr, len)
+        .map(|_| 0)
+        .map_err(|e| errno(&e))
+}
+
+// struct timespec and struct timeval are two 64-bit fields: seconds, then
+// nanoseconds or microseconds.
+fn read_time(
+    task_manager: &TaskManager,
+    id: TaskId,
+    addr: usize,
+    unit: u32,
+) -> Result<Duration, isize> {
+    let mut buf = [0u8; 16];
+    task_manager
+        .copy_from_user(id, addr, &mut buf)
+        .or(Err(EFAULT))?;
+    let secs = i64::from_le_bytes(buf[..8].try_into().unwrap());
+    let fraction = i64::from_le_bytes(buf[8..].try_into().unwrap());
+    if secs < 0 || fraction < 0 || fraction >= 1_000_000_000 / unit as i64 {
+        return Err(EINVAL);
+    }
+    Ok(Duration::new(secs as u64, fraction as u32 * unit))
+}
+
+fn write_time(
+    task_manager: &mut TaskManager,
+    id: TaskId,
+    addr: usize,
+    time: Duration,
+    unit: u32,
+) -> Result<(), isize> {
+    let mut buf = [0u8; 16];
+    buf[..8].copy_from_slice(&(time.as_secs() as i64).to_le_bytes());
+    buf[8..].copy_from_slice(&((time.subsec_nanos() / unit) as i64).to_le_bytes());
+    task_manager.copy_to_user(id, addr, &buf).or(Err(EFAULT))
+}
+
+// Sleeps are not interrupted, so the remaining time is always zero.
+fn nanosleep(task_manager: &mut TaskManager, id: TaskId, args: [usize; 6]) -> Result<usize, isize> {
+    let [req, rem, ..] = args;
+    let duration = read_time(task_manager, id, req, 1)?;
+    task::sleep(duration);
+    if rem != 0 {
+        write_time(task_manager, id, rem, Duration::ZERO, 1)?;
+    }
+    Ok(0)
+}
+
+// Only ITIMER_REAL is supported. The timer kills the task when it expires,
+// which is what SIGALRM does by default, so intervals never come into play
+// and are reported as zero.
+fn itimer(
+    task_manager: &mut TaskManager,
+    id: TaskId,
+    number: usize,
+    args: [usize; 6],
+) -> Result<usize, isize> {
+    let [which, new, old, ..] = args;
+    if which != ITIMER_REAL {
+        return Err(EINVAL);
+    }
+    let (value_addr, left) = if number == SYS_GETITIMER {
+        (new, task_manager.alarm(id).map_err(|e| errno(&e))?)
+    } else {
+        // it_value follows it_interval
+        let value_addr = new.checked_add(16).ok_or(EFAULT)?;
+        let value = read_time(task_manager, id, value_addr, 1000)?;
+        let delay = (!value.is_zero()).then_some(value);
+        let left = task_manager.set_alarm(id, delay).map_err(|e| errno(&e))?;
+        (old, left)
+    };
+    if value_addr != 0 {
+        write_time(task_manager, id, value_addr, Duration::ZERO, 1000)?;
+        write_time(
+            task_manager,
+            id,
+            value_addr.checked_add(16).ok_or(EFAULT)?,
+            left.unwrap_or(Duration::ZERO),
+            1000,
+        )?;
+    }
+    Ok(0)
+}
+
+// Reads the kernel log ring and sets the log levels. READ_ALL copies the newest
+// records that fit into the buffer; records are never consumed.
+fn syslog(task_manager: &mut TaskManager, id: TaskId, args: [usize; 6]) -> Result<usize, isize> {
+    let [action, buf, len, ..] = args;
+    match action {
+        SYSLOG_ACTION_READ_ALL => {
+            let len = len.min(logger::LOG_BUFFER_SIZE);
+            let mut text = vec![0u8; len];
+            let (copied, _) = logger::with_ring(|ring| ring.read(ring.seq_fitting(len), &mut text));
+            task_manager
+                .copy_to_user(id, buf, &text[..copied])
+                .or(Err(EFAULT))?;
+            Ok(copied)
+        }
+        SYSLOG_ACTION_CONSOLE_LEVEL => {
+            // the level applies to the whole kernel
+            if !task_manager.is_init(id) {
+                return Err(EPERM);
+            }
+            logger::set_level(logger::level_from_syslog(len).ok_or(EINVAL)?);
+            Ok(0)
+        }
+        SYSLOG_ACTION_SIZE_UNREAD => Ok(logger::with_ring(|ring| ring.text_len())),
+        SYSLOG_ACTION_SIZE_BUFFER => Ok(logger::LOG_BUFFER_SIZE),
+        SYSLOG_ACTION_SET_LEVELS => {
+            if !task_manager.is_init(id) {
+                return Err(EPERM);
+            }
+            if len > LEVELS_SPEC_SIZE {
+                return Err(EINVAL);
+            }
+            let mut spec = [0u8; LEVELS_SPEC_SIZE];
+            task_manager
+                .copy_from_user(id, buf, &mut spec[..len])
+                .or(Err(EFAULT))?;
+            let spec = core::str::from_utf8(&spec[..len]).or(Err(EINVAL))?;
+            logger::set_levels(spec).or(Err(EINVAL))?;
+            Ok(0)
+        }
+        SYSLOG_ACTION_PRINT_LEVELS => {
+            logger::dump_levels();
+            Ok(0)
+        }
+        SYSLOG_ACTION_PRINT_ALL => {
+            if !task_manager.is_init(id) {
+                return Err(EPERM);
+            }
+            logger::dump_log();
+            Ok(0)
+        }
+        #[cfg(allocator = "Debug")]
+        SYSLOG_ACTION_CHECK_HEAP => {
+            if !task_manager.is_init(id) {
+                return Err(EPERM);
+            }
+            crate::allocator::debug::check_heap();
+            Ok(crate::allocator::debug::dump_leaks(len))
+        }
+        _ => Err(EINVAL),
+    }
+}
+
+// Runs system call `number` for the current task. Returns the value for the
+// user's return register: the result, or a negated errno.
+pub fn syscall(number: usize, args: [usize; 6]) -> isize {
+    let task_manager = unsafe { &mut TASK_MANAGER };
+    let id = task_manager.current();
+    let result = match number {
+        SYS_NANOSLEEP => nanosleep(task_manager, id, args),
+        SYS_GETITIMER | SYS_SETITIMER => itimer(task_manager, id, number, args),
+        SYS_SYSLOG => syslog(task_manager, id, args),
+        SYS_BRK => task_manager.brk(id, args[0]).map_err(|e| errno(&e)),
+        SYS_MMAP => mmap(task_manager, id, args),
+        SYS_MUNMAP => munmap(task_manager, id, args),
+        SYS_MPROTECT => {
+            let (r, w, x) = prot(args[2]);
+            task_manager
+                .mprotect(id, args[0], args[1], r, w, x)
+                .map(|_| 0)
+                .map_err(|e| errno(&e))
+        }
+        _ => {
+            info!("task {}: unknown system call {}", id, number);
+            Err(ENOSYS)
+        }
+    };
+    match result {
+        Ok(value) => value as isize,
+        Err(errno) => -errno,
+    }
+}
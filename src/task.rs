@@ -1,17 +1,23 @@
+pub mod stack;
+
+use crate::allocator::frame::{self, alloc_frames_zeroed, free_frames};
+use crate::allocator::slab::KmemCache;
 use crate::arch::PAGE_SIZE;
-use crate::error::TaskError;
+use crate::error::{TaskError, VMError};
 use crate::fs::fat32;
 use crate::lazy::Lazy;
+use crate::timer::{self, TimerId};
 use crate::*;
-use alloc::alloc::{alloc_zeroed, Layout};
 use alloc::collections::VecDeque;
 use alloc::string::{String, ToString};
 use alloc::vec;
 use alloc::vec::*;
+use core::time::Duration;
 use fatfs::{Read, Seek, SeekFrom};
 use goblin::elf;
 use hashbrown::HashMap;
 use log::info;
+use stack::KernelStack;
 
 use crate::arch::*;
 
@@ -33,6 +39,9 @@ macro_rules! arch_task_manager {
 pub static mut TASK_MANAGER: Lazy<TaskManager> =
     Lazy::<TaskManager, fn() -> TaskManager>::new(|| TaskManager::new());
 
+static mut TASK_CACHE: Lazy<KmemCache<Task>> =
+    Lazy::<KmemCache<Task>, fn() -> KmemCache<Task>>::new(|| KmemCache::new());
+
 pub trait ArchTaskManager {
     unsafe fn context_switch(&mut self, from: TaskId, to: TaskId);
     unsafe fn user_switch(&mut self, current: TaskId) -> !;
@@ -45,26 +54,188 @@ pub trait ArchTaskManager {
         w: bool,
         x: bool,
     ) -> Result<(), TaskError>;
-    fn create_arch_task(&mut self, id: TaskId, name: String);
+    fn unmap(&mut self, id: TaskId, vaddr: usize, size: usize) -> Result<(), TaskError>;
+    fn protect(
+        &mut self,
+        id: TaskId,
+        vaddr: usize,
+        size: usize,
+        r: bool,
+        w: bool,
+        x: bool,
+    ) -> Result<(), TaskError>;
+    fn translate(&self, id: TaskId, vaddr: usize) -> Option<usize>;
+    fn create_arch_task(&mut self, id: TaskId, name: String) -> Result<(), TaskError>;
+    // Frees the address space, kernel stack and everything else the
+    // architecture keeps for the task.
+    fn remove_arch_task(&mut self, id: TaskId);
     fn init_start(&mut self, id: TaskId, start_address: usize) -> Result<(), TaskError>;
     fn init_user_entry(&mut self, id: TaskId, entry: usize) -> Result<(), TaskError>;
+    fn kernel_stack(&self, id: TaskId) -> Option<&KernelStack>;
 }
 
 pub type TaskId = usize;
 
+// The first task the kernel starts. It may change settings that apply to the
+// whole kernel.
+pub const INIT_TASK: &str = "init";
+
+// mmap places mappings in [MMAP_BASE, MMAP_TOP) unless told otherwise. The
+// heap grows up to MMAP_BASE at most, so the two never run into each other.
+pub const MMAP_BASE: usize = 0x10_0000_0000;
+pub const MMAP_TOP: usize = 0x20_0000_0000;
+
+fn page_round_up(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+// Checks that [vaddr, vaddr + size) is page aligned and in user space, and
+// returns `size` rounded up to whole pages.
+fn user_range(vaddr: usize, size: usize) -> Result<usize, TaskError> {
+    if vaddr % PAGE_SIZE != 0 {
+        return Err(TaskError::MapError(VMError::Misaligned));
+    }
+    match vaddr.checked_add(size) {
+        Some(end) if vaddr >= arch::user_start() && end <= arch::user_end() => {
+            Ok(page_round_up(size))
+        }
+        _ => Err(TaskError::MapError(VMError::OutOfRange)),
+    }
+}
+
+// Page tables cannot express write-only or execute-only memory, so such
+// memory is readable too. Memory with no access at all is kept track of but
+// left unmapped.
+pub fn page_access(r: bool, w: bool, x: bool) -> (bool, bool, bool) {
+    (r || w || x, w, x)
+}
+
 pub struct MemoryRegion {
-    paddr: usize,
+    // kernel address of the backing memory
+    kaddr: usize,
     vaddr: Option<usize>,
     size: usize,
+    // order of the frame block at kaddr, which may be larger than size
+    order: usize,
     r: bool,
     w: bool,
     x: bool,
 }
 
+impl MemoryRegion {
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.vaddr
+            .map_or(false, |vaddr| vaddr < end && start < vaddr + self.size)
+    }
+
+    fn is_inside(&self, start: usize, end: usize) -> bool {
+        self.vaddr
+            .map_or(false, |vaddr| start <= vaddr && vaddr + self.size <= end)
+    }
+
+    // PROT_NONE memory is not in the page tables.
+    fn is_accessible(&self) -> bool {
+        self.r || self.w || self.x
+    }
+
+    fn map(&self, id: TaskId) -> Result<(), TaskError> {
+        let arch_tm = unsafe { arch_task_manager!() };
+        match self.vaddr {
+            Some(vaddr) if self.is_accessible() => {
+                for i in (0..self.size).step_by(PAGE_SIZE) {
+                    arch_tm.map(
+                        id,
+                        arch::virt_to_phys(self.kaddr + i),
+                        vaddr + i,
+                        self.r,
+                        self.w,
+                        self.x,
+                    )?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Splits the region along the halves of its frame block. The upper half
+    // is freed right away if the region does not reach into it.
+    fn split(self) -> (MemoryRegion, Option<MemoryRegion>) {
+        let region = core::mem::ManuallyDrop::new(self);
+        let order = region.order;
+        frame::split_frames(region.kaddr, order);
+        let half = PAGE_SIZE << (order - 1);
+        let lower = MemoryRegion {
+            kaddr: region.kaddr,
+            vaddr: region.vaddr,
+            size: region.size.min(half),
+            order: order - 1,
+            r: region.r,
+            w: region.w,
+            x: region.x,
+        };
+        if region.size <= half {
+            free_frames(region.kaddr + half, order - 1);
+            return (lower, None);
+        }
+        let upper = MemoryRegion {
+            kaddr: region.kaddr + half,
+            vaddr: region.vaddr.map(|vaddr| vaddr + half),
+            size: region.size - half,
+            order: order - 1,
+            r: region.r,
+            w: region.w,
+            x: region.x,
+        };
+        (lower, Some(upper))
+    }
+}
+
+impl Drop for MemoryRegion {
+    fn drop(&mut self) {
+        free_frames(self.kaddr, self.order);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum TaskState {
     Running,
     Ready,
     Stop,
+    // waiting for wake_task()
+    Blocked,
+    Killed,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Resource {
+    CpuTime,
+    Memory,
+    OpenFiles,
+    Children,
+}
+
+// Per-task limits. `None` means unlimited. New tasks inherit the limits of
+// the task that created them.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ResourceLimits {
+    // user + system time in microseconds
+    pub cpu_time: Option<u64>,
+    // pages owned through `MemoryRegion`s
+    pub memory: Option<usize>,
+    pub open_files: Option<usize>,
+    pub children: Option<usize>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TaskUsage {
+    // microseconds
+    pub user_time: u64,
+    pub system_time: u64,
+    // pages
+    pub memory: usize,
+    pub open_files: usize,
+    pub children: usize,
 }
 
 #[allow(dead_code)]
@@ -73,6 +244,17 @@ pub struct Task {
     name: String,
     state: TaskState,
     memory: Vec<MemoryRegion>,
+    parent: Option<TaskId>,
+    limits: ResourceLimits,
+    user_ticks: u64,
+    system_ticks: u64,
+    open_files: usize,
+    // The heap grows from brk_start, the end of the loaded program, up to
+    // brk.
+    brk_start: usize,
+    brk: usize,
+    // kills the task when it fires, like SIGALRM
+    alarm: Option<TimerId>,
 }
 
 impl Task {
@@ -82,19 +264,270 @@ impl Task {
             name: name.to_string(),
             state: TaskState::Stop,
             memory: Vec::new(),
+            parent: None,
+            limits: ResourceLimits::default(),
+            user_ticks: 0,
+            system_ticks: 0,
+            open_files: 0,
+            brk_start: arch::user_start(),
+            brk: arch::user_start(),
+            alarm: None,
         }
     }
 
     pub fn update_state(&mut self, state: TaskState) {
         self.state = state;
     }
+
+    pub fn memory_pages(&self) -> usize {
+        self.memory
+            .iter()
+            .map(|region| region.size / PAGE_SIZE)
+            .sum()
+    }
+
+    pub fn cpu_time(&self) -> u64 {
+        arch::ticks_to_micros(self.user_ticks + self.system_ticks)
+    }
+
+    pub fn cpu_limit_exceeded(&self) -> bool {
+        self.limits
+            .cpu_time
+            .map_or(false, |limit| self.cpu_time() > limit)
+    }
+
+    // Calls `f` with the kernel address, offset into the range and length of
+    // each piece of [vaddr, vaddr + len) in the task's memory.
+    fn user_memory(
+        &self,
+        vaddr: usize,
+        len: usize,
+        write: bool,
+        mut f: impl FnMut(usize, usize, usize),
+    ) -> Result<(), TaskError> {
+        let end = vaddr
+            .checked_add(len)
+            .ok_or(TaskError::MapError(VMError::OutOfRange))?;
+        let mut addr = vaddr;
+        while addr < end {
+            let region = self
+                .memory
+                .iter()
+                .find(|region| {
+                    region.is_accessible()
+                        && (region.w || !write)
+                        && region.overlaps(addr, addr + 1)
+                })
+                .ok_or(TaskError::MapError(VMError::NotFound))?;
+            let start = region.vaddr.unwrap();
+            let len = (start + region.size).min(end) - addr;
+            f(region.kaddr + addr - start, addr - vaddr, len);
+            addr += len;
+        }
+        Ok(())
+    }
+
+    fn check_memory_limit(&self, pages: usize) -> Result<(), TaskError> {
+        if let Some(limit) = self.limits.memory {
+            if self.memory_pages() + pages > limit {
+                return Err(TaskError::LimitExceeded(Resource::Memory));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_free(&self, start: usize, end: usize) -> bool {
+        !self.memory.iter().any(|region| region.overlaps(start, end))
+    }
+
+    // Highest free range of `size` bytes in [MMAP_BASE, MMAP_TOP).
+    fn find_free(&self, size: usize) -> Result<usize, TaskError> {
+        let mut end = MMAP_TOP;
+        loop {
+            let start = end
+                .checked_sub(size)
+                .filter(|start| *start >= MMAP_BASE)
+                .ok_or(TaskError::OutOfMemory)?;
+            match self
+                .memory
+                .iter()
+                .filter(|region| region.overlaps(start, end))
+                .filter_map(|region| region.vaddr)
+                .min()
+            {
+                Some(vaddr) => end = vaddr,
+                None => return Ok(start),
+            }
+        }
+    }
+
+    // Splits regions until none of them straddles a bound of [start, end).
+    fn isolate(&mut self, start: usize, end: usize) {
+        while let Some(i) = self
+            .memory
+            .iter()
+            .position(|region| region.overlaps(start, end) && !region.is_inside(start, end))
+        {
+            let (lower, upper) = self.memory.swap_remove(i).split();
+            self.memory.push(lower);
+            if let Some(upper) = upper {
+                self.memory.push(upper);
+            }
+        }
+    }
+
+    // Maps zeroed memory at [vaddr, vaddr + size), which must be free. The
+    // range is backed by the largest frame blocks that fit and can be had.
+    fn map_anonymous(
+        &mut self,
+        vaddr: usize,
+        size: usize,
+        r: bool,
+        w: bool,
+        x: bool,
+    ) -> Result<(), TaskError> {
+        self.check_memory_limit(size / PAGE_SIZE)?;
+        let mut offset = 0;
+        while offset < size {
+            let pages = (size - offset) / PAGE_SIZE;
+            let mut order = (pages.ilog2() as usize).min(frame::MAX_ORDER - 1);
+            let mut kaddr = alloc_frames_zeroed(order);
+            // fragmented memory may still have smaller blocks
+            while kaddr.is_none() && order > 0 {
+                order -= 1;
+                kaddr = alloc_frames_zeroed(order);
+            }
+            let result = kaddr.ok_or(TaskError::OutOfMemory).and_then(|kaddr| {
+                self.memory.push(MemoryRegion {
+                    kaddr,
+                    vaddr: Some(vaddr + offset),
+                    size: PAGE_SIZE << order,
+                    order,
+                    r,
+                    w,
+                    x,
+                });
+                self.memory.last().unwrap().map(self.id)
+            });
+            if let Err(e) = result {
+                self.unmap(vaddr, offset + (PAGE_SIZE << order))?;
+                return Err(e);
+            }
+            offset += PAGE_SIZE << order;
+        }
+        Ok(())
+    }
+
+    // Unmaps [vaddr, vaddr + size) and frees the memory behind it. Holes in
+    // the range are fine.
+    fn unmap(&mut self, vaddr: usize, size: usize) -> Result<(), TaskError> {
+        let end = vaddr + size;
+        self.isolate(vaddr, end);
+        let arch_tm = unsafe { arch_task_manager!() };
+        arch_tm.unmap(self.id, vaddr, size)?;
+        self.memory.retain(|region| !region.is_inside(vaddr, end));
+        Ok(())
+    }
+
+    // Every page of the range has to be mapped, PROT_NONE memory included.
+    fn protect(
+        &mut self,
+        vaddr: usize,
+        size: usize,
+        r: bool,
+        w: bool,
+        x: bool,
+    ) -> Result<(), TaskError> {
+        let end = vaddr + size;
+        self.isolate(vaddr, end);
+        let mapped: usize = self
+            .memory
+            .iter()
+            .filter(|region| region.is_inside(vaddr, end))
+            .map(|region| region.size)
+            .sum();
+        if mapped != size {
+            return Err(TaskError::MapError(VMError::NotFound));
+        }
+        let arch_tm = unsafe { arch_task_manager!() };
+        for region in self
+            .memory
+            .iter_mut()
+            .filter(|region| region.is_inside(vaddr, end))
+        {
+            let was_accessible = region.is_accessible();
+            region.r = r;
+            region.w = w;
+            region.x = x;
+            let start = region.vaddr.unwrap();
+            match (was_accessible, region.is_accessible()) {
+                (true, true) => arch_tm.protect(self.id, start, region.size, r, w, x)?,
+                (true, false) => arch_tm.unmap(self.id, start, region.size)?,
+                (false, true) => region.map(self.id)?,
+                (false, false) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Jiffies a task may run while others are ready. It is preempted when it
+// next returns to user mode after that.
+pub const QUANTUM: u64 = 10;
+
+// What one core is running.
+#[derive(Copy, Clone)]
+struct Cpu {
+    // whether the core has joined scheduling
+    online: bool,
+    running: TaskId,
+    // timer count at the last accounting point
+    last_tick: u64,
+    // whether the running task is executing in user mode
+    user_mode: bool,
+    // jiffy the running task was switched to
+    slice_start: u64,
+    // set by tick() when the running task used up its quantum
+    need_resched: bool,
+}
+
+impl Cpu {
+    const OFFLINE: Cpu = Cpu {
+        online: false,
+        running: 0,
+        last_tick: 0,
+        user_mode: false,
+        slice_start: 0,
+        need_resched: false,
+    };
+}
+
+fn alloc_task(task: Task) -> Result<&'static mut Task, TaskError> {
+    unsafe {
+        let object = TASK_CACHE.alloc().ok_or(TaskError::OutOfMemory)?;
+        object.write(task);
+        Ok(&mut *object)
+    }
+}
+
+fn free_task(task: &'static mut Task) {
+    let object = task as *mut Task;
+    unsafe {
+        core::ptr::drop_in_place(object);
+        TASK_CACHE.free(object);
+    }
+}
+
+pub fn dump_cache_stats() {
+    unsafe { TASK_CACHE.dump_stats() };
 }
 
 pub struct TaskManager {
-    tasks: HashMap<TaskId, Task>,
+    // objects of TASK_CACHE
+    tasks: HashMap<TaskId, &'static mut Task>,
     ready_queue: VecDeque<TaskId>,
     task_id: TaskId,
-    running: TaskId,
+    cpus: [Cpu; arch::MAX_CPUS],
 }
 
 impl TaskManager {
@@ -103,12 +536,50 @@ impl TaskManager {
             tasks: HashMap::new(),
             ready_queue: VecDeque::new(),
             task_id: 0,
-            running: 0,
+            cpus: [Cpu::OFFLINE; arch::MAX_CPUS],
         }
     }
 
+    fn cpu(&self) -> &Cpu {
+        &self.cpus[arch::cpu_id()]
+    }
+
+    fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpus[arch::cpu_id()]
+    }
+
     pub fn current(&self) -> TaskId {
-        self.running
+        self.cpu().running
+    }
+
+    // Whether a task is running on some core.
+    fn is_running(&self, id: TaskId) -> bool {
+        self.cpus.iter().any(|cpu| cpu.online && cpu.running == id)
+    }
+
+    // Whether a task is running on a core other than this one. Such a task may
+    // be woken while its core idles, and is not switched to before the core
+    // has switched away from it.
+    fn is_running_elsewhere(&self, id: TaskId) -> bool {
+        let this = arch::cpu_id();
+        self.cpus
+            .iter()
+            .enumerate()
+            .any(|(n, cpu)| n != this && cpu.online && cpu.running == id)
+    }
+
+    fn has_ready(&self) -> bool {
+        self.ready_queue
+            .iter()
+            .any(|id| !self.is_running_elsewhere(*id))
+    }
+
+    fn next_ready(&mut self) -> Option<TaskId> {
+        let index = self
+            .ready_queue
+            .iter()
+            .position(|id| !self.is_running_elsewhere(*id))?;
+        self.ready_queue.remove(index)
     }
 
     pub fn next_task_id(&mut self) -> TaskId {
@@ -120,22 +591,108 @@ impl TaskManager {
     pub fn init(&mut self) -> Result<(), TaskError> {
         info!("Initialize Task Manager");
         self.task_id = 0;
-        self.running = self.create_task("kernel", 0)?;
+        self.start_cpu("kernel")?;
+        Ok(())
+    }
+
+    // Makes what runs on this core a task named `name`, so that the core can
+    // take part in scheduling.
+    pub fn start_cpu(&mut self, name: &str) -> Result<TaskId, TaskError> {
+        let id = self.create_task(name, 0)?;
         self.tasks
-            .get_mut(&self.running)
-            .ok_or(TaskError::TaskNotFound(self.running))?
+            .get_mut(&id)
+            .ok_or(TaskError::TaskNotFound(id))?
             .update_state(TaskState::Running);
-        Ok(())
+        *self.cpu_mut() = Cpu {
+            online: true,
+            running: id,
+            last_tick: arch::timer_count(),
+            slice_start: timer::jiffies(),
+            ..Cpu::OFFLINE
+        };
+        Ok(id)
+    }
+
+    // Charges the time since the last accounting point to the running task.
+    fn account(&mut self) {
+        let now = arch::timer_count();
+        let cpu = &mut self.cpus[arch::cpu_id()];
+        let elapsed = now.wrapping_sub(cpu.last_tick);
+        cpu.last_tick = now;
+        if let Some(task) = self.tasks.get_mut(&cpu.running) {
+            if cpu.user_mode {
+                task.user_ticks += elapsed;
+            } else {
+                task.system_ticks += elapsed;
+            }
+        }
+    }
+
+    // Called right before the running task returns to user mode.
+    pub fn enter_user(&mut self) {
+        self.account();
+        self.cpu_mut().user_mode = true;
+    }
+
+    // Called when the running task traps into the kernel.
+    pub fn enter_kernel(&mut self) {
+        self.account();
+        self.cpu_mut().user_mode = false;
+    }
+
+    // Called from the timer interrupt. A task over its CPU time limit is
+    // rescheduled, and so killed, even if it is the only one that is ready.
+    pub fn tick(&mut self) {
+        let over_limit = self
+            .tasks
+            .get(&self.current())
+            .map_or(false, |task| task.cpu_limit_exceeded());
+        if over_limit
+            || (timer::jiffies().wrapping_sub(self.cpu().slice_start) >= QUANTUM
+                && self.has_ready())
+        {
+            self.cpu_mut().need_resched = true;
+        }
+    }
+
+    // Whether the running task is to be switched away from at the next
+    // chance.
+    pub fn need_resched(&self) -> bool {
+        self.cpu().need_resched
+    }
+
+    // Switches to the next ready task if the running one used up its quantum.
+    pub unsafe fn preempt(&mut self) {
+        if self.need_resched() {
+            self.schedule();
+        }
     }
 
     // Round robin scheduling
     pub unsafe fn schedule(&mut self) {
-        if self.ready_queue.len() == 0 {
-            return;
+        self.account();
+        let current_running = self.current();
+        if self
+            .tasks
+            .get(&current_running)
+            .unwrap()
+            .cpu_limit_exceeded()
+        {
+            info!("task {}: CPU time limit exceeded", current_running);
+            self.kill_task(current_running);
         }
-        assert!(1 <= self.ready_queue.len());
-        let next_running = self.ready_queue.pop_front().unwrap();
-        let current_running = self.running;
+
+        if !self.has_ready() {
+            if self.tasks.get(&current_running).unwrap().state == TaskState::Running {
+                // with nothing else to run, the task starts a new quantum
+                let cpu = self.cpu_mut();
+                cpu.slice_start = timer::jiffies();
+                cpu.need_resched = false;
+                return;
+            }
+            self.idle();
+        }
+        let next_running = self.next_ready().unwrap();
         assert!(self.tasks.contains_key(&next_running));
         assert!(self.tasks.contains_key(&current_running));
 
@@ -143,19 +700,83 @@ impl TaskManager {
             .get_mut(&next_running)
             .unwrap()
             .update_state(TaskState::Running);
-        self.tasks
-            .get_mut(&current_running)
-            .unwrap()
-            .update_state(TaskState::Ready);
-
-        self.ready_queue.push_back(current_running);
-        self.running = next_running;
+        let cpu = self.cpu_mut();
+        cpu.slice_start = timer::jiffies();
+        cpu.need_resched = false;
+        // woken up while idling
+        if next_running == current_running {
+            return;
+        }
+        let current = self.tasks.get_mut(&current_running).unwrap();
+        if current.state == TaskState::Running {
+            current.update_state(TaskState::Ready);
+            self.ready_queue.push_back(current_running);
+        }
+        self.cpu_mut().running = next_running;
         // Do context switch
         #[cfg(target_arch = "riscv64")]
         riscv64::task::ARCH_TASK_MANAGER.context_switch(current_running, next_running);
 
         #[cfg(target_arch = "aarch64")]
         aarch64::task::ARCH_TASK_MANAGER.context_switch(current_running, next_running);
+
+        // Back on this task's stack, so no killed task is running anymore.
+        self.reap_killed();
+    }
+
+    // Waits for interrupts until a task is ready. Timers also run from here,
+    // for architectures without a timer interrupt.
+    fn idle(&mut self) {
+        while !self.has_ready() {
+            // the other cores get on with their tasks meanwhile
+            #[cfg(target_arch = "aarch64")]
+            unsafe {
+                crate::KERNEL_LOCK.release();
+            }
+            arch::wait_for_interrupt();
+            #[cfg(target_arch = "aarch64")]
+            unsafe {
+                crate::KERNEL_LOCK.lock();
+            }
+            timer::poll();
+        }
+        // idle time is nobody's
+        self.cpu_mut().last_tick = arch::timer_count();
+    }
+
+    fn reap_killed(&mut self) {
+        let killed: Vec<TaskId> = self
+            .tasks
+            .values()
+            .filter(|task| task.state == TaskState::Killed && !self.is_running(task.id))
+            .map(|task| task.id)
+            .collect();
+        for id in killed {
+            self.remove_task(id).unwrap();
+        }
+    }
+
+    // Tears a task down: frees its memory regions, page tables and kernel
+    // stack. A running task is left alone.
+    pub fn remove_task(&mut self, id: TaskId) -> Result<(), TaskError> {
+        if self.is_running(id) {
+            return Err(TaskError::TaskRunning(id));
+        }
+        let task = self.tasks.remove(&id).ok_or(TaskError::TaskNotFound(id))?;
+        if let Some(alarm) = task.alarm {
+            timer::cancel_timer(alarm);
+        }
+        // the address space goes first, the task object last
+        let arch_tm = unsafe { arch_task_manager!() };
+        arch_tm.remove_arch_task(id);
+        free_task(task);
+        self.ready_queue.retain(|ready| *ready != id);
+        for task in self.tasks.values_mut() {
+            if task.parent == Some(id) {
+                task.parent = None;
+            }
+        }
+        Ok(())
     }
 
     pub fn ready_task(&mut self, id: TaskId) {
@@ -170,86 +791,446 @@ impl TaskManager {
         self.ready_queue.push_back(id);
     }
 
+    // Takes the running task off the CPU at its next schedule() until
+    // wake_task() is called.
+    pub fn block_current(&mut self) {
+        let id = self.current();
+        let task = self.tasks.get_mut(&id).unwrap();
+        if task.state == TaskState::Running {
+            task.update_state(TaskState::Blocked);
+        }
+    }
+
+    // Makes a blocked task ready. Other tasks are left alone.
+    pub fn wake_task(&mut self, id: TaskId) {
+        if let Some(task) = self.tasks.get_mut(&id) {
+            if task.state == TaskState::Blocked {
+                task.update_state(TaskState::Ready);
+                self.ready_queue.push_back(id);
+            }
+        }
+    }
+
     pub fn create_task(&mut self, name: &str, func: usize) -> Result<TaskId, TaskError> {
+        let parent = if self.cpu().online && self.tasks.contains_key(&self.current()) {
+            Some(self.current())
+        } else {
+            None
+        };
+        let mut limits = ResourceLimits::default();
+        if let Some(parent) = parent {
+            limits = self.tasks.get(&parent).unwrap().limits;
+            if let Some(limit) = limits.children {
+                if self.children(parent) >= limit {
+                    return Err(TaskError::LimitExceeded(Resource::Children));
+                }
+            }
+        }
+
         let task_id = self.next_task_id();
-        let task = Task::new(name, task_id);
-        self.tasks.insert(task_id, task);
+        let mut task = Task::new(name, task_id);
+        task.parent = parent;
+        task.limits = limits;
+        self.tasks.insert(task_id, alloc_task(task)?);
         assert!(self.tasks.contains_key(&task_id));
 
         unsafe {
             let arch_tm = arch_task_manager!();
 
-            arch_tm.create_arch_task(task_id, name.to_string());
+            if let Err(e) = arch_tm.create_arch_task(task_id, name.to_string()) {
+                free_task(self.tasks.remove(&task_id).unwrap());
+                return Err(e);
+            }
             arch_tm.init_start(task_id, func)?;
         }
 
         Ok(task_id)
     }
 
+    // Marks a task as killed and takes it off the ready queue. It is never
+    // scheduled again.
+    pub fn kill_task(&mut self, id: TaskId) {
+        if let Some(task) = self.tasks.get_mut(&id) {
+            task.update_state(TaskState::Killed);
+            self.ready_queue.retain(|ready| *ready != id);
+        }
+    }
+
+    fn children(&self, id: TaskId) -> usize {
+        self.tasks
+            .values()
+            .filter(|task| task.parent == Some(id) && task.state != TaskState::Killed)
+            .count()
+    }
+
+    pub fn set_limits(&mut self, id: TaskId, limits: ResourceLimits) -> Result<(), TaskError> {
+        self.tasks
+            .get_mut(&id)
+            .ok_or(TaskError::TaskNotFound(id))?
+            .limits = limits;
+        Ok(())
+    }
+
+    pub fn is_init(&self, id: TaskId) -> bool {
+        self.tasks
+            .get(&id)
+            .map_or(false, |task| task.name == INIT_TASK)
+    }
+
+    pub fn limits(&self, id: TaskId) -> Result<ResourceLimits, TaskError> {
+        Ok(self
+            .tasks
+            .get(&id)
+            .ok_or(TaskError::TaskNotFound(id))?
+            .limits)
+    }
+
+    pub fn usage(&self, id: TaskId) -> Result<TaskUsage, TaskError> {
+        let task = self.tasks.get(&id).ok_or(TaskError::TaskNotFound(id))?;
+        Ok(TaskUsage {
+            user_time: arch::ticks_to_micros(task.user_ticks),
+            system_time: arch::ticks_to_micros(task.system_ticks),
+            memory: task.memory_pages(),
+            open_files: task.open_files,
+            children: self.children(id),
+        })
+    }
+
+    // Accounts a newly opened file against the task's limit.
+    pub fn open_file(&mut self, id: TaskId) -> Result<(), TaskError> {
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        if let Some(limit) = task.limits.open_files {
+            if task.open_files >= limit {
+                return Err(TaskError::LimitExceeded(Resource::OpenFiles));
+            }
+        }
+        task.open_files += 1;
+        Ok(())
+    }
+
+    pub fn close_file(&mut self, id: TaskId) -> Result<(), TaskError> {
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        assert!(task.open_files > 0);
+        task.open_files -= 1;
+        Ok(())
+    }
+
+    pub fn stack_high_water_mark(&self, id: TaskId) -> Result<usize, TaskError> {
+        let arch_tm = unsafe { arch_task_manager!() };
+        arch_tm
+            .kernel_stack(id)
+            .map(|stack| stack.high_water_mark())
+            .ok_or(TaskError::TaskNotFound(id))
+    }
+
+    pub fn dump_stack_usage(&self) {
+        let arch_tm = unsafe { arch_task_manager!() };
+        for (id, task) in self.tasks.iter() {
+            if let Some(stack) = arch_tm.kernel_stack(*id) {
+                println!(
+                    "{}.{}: {:#x} / {:#x} bytes",
+                    task.name,
+                    id,
+                    stack.high_water_mark(),
+                    stack.size()
+                );
+            }
+        }
+    }
+
+    pub fn dump_tasks(&self) {
+        for (id, task) in self.tasks.iter() {
+            println!(
+                "{}.{}: {:?}{}, parent {:?}, {} pages, {} us",
+                task.name,
+                id,
+                task.state,
+                if self.is_running(*id) {
+                    " (current)"
+                } else {
+                    ""
+                },
+                task.parent,
+                task.memory_pages(),
+                task.cpu_time()
+            );
+        }
+    }
+
+    // Returns the task whose kernel stack guard page contains `addr`.
+    pub fn stack_overflow_owner(&self, addr: usize) -> Option<TaskId> {
+        let arch_tm = unsafe { arch_task_manager!() };
+        self.tasks.keys().copied().find(|id| {
+            arch_tm
+                .kernel_stack(*id)
+                .map_or(false, |stack| stack.is_guard(addr))
+        })
+    }
+
     pub fn exec(&mut self, id: TaskId, path: &str) -> Result<(), TaskError> {
+        // the program file counts as open while it is read
+        self.open_file(id)?;
+        let buf = read_file(path);
+        self.close_file(id)?;
+        let buf = buf?;
         let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
-        let root_dir = unsafe { fat32::FILE_SYSTEM.root_dir() };
-        let mut file = root_dir
-            .open_file(path)
-            .map_err(|e| TaskError::DiskError(e))?;
-        let file_size = file
-            .seek(SeekFrom::End(0))
-            .map_err(|e| TaskError::DiskError(e))? as usize;
-        file.seek(SeekFrom::Start(0))
-            .map_err(|e| TaskError::DiskError(e))?;
-        let mut buf: Vec<u8> = vec![0; file_size];
-        file.read(&mut buf).map_err(|e| TaskError::DiskError(e))?;
         let elf_exe = elf::Elf::parse(&buf).map_err(|e| TaskError::ExecParseError(e))?;
         for ph in elf_exe.program_headers.iter() {
             let page_offset = ph.vm_range().start % PAGE_SIZE;
             let mut size = page_offset + ph.p_memsz as usize;
             size = size + (PAGE_SIZE - size % PAGE_SIZE); // Round up
             assert!(size % PAGE_SIZE == 0);
+            task.check_memory_limit(size / PAGE_SIZE)?;
 
             let offset = ph.p_offset as usize;
             let file_size = ph.p_filesz as usize;
             let _mem_size = ph.p_memsz as usize;
-            let program: *mut u8 = unsafe {
-                let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
-                alloc_zeroed(layout)
-            };
+            let program = alloc_frames_zeroed(frame::order_for(size))
+                .ok_or(TaskError::OutOfMemory)? as *mut u8;
             let program_slice = unsafe { core::slice::from_raw_parts_mut(program, size) };
             program_slice[..file_size]
                 .copy_from_slice(&buf.as_slice()[offset..(offset + file_size)]);
             program_slice[file_size..].fill(0);
 
+            let vaddr = ph.vm_range().start - page_offset;
+            task.brk_start = task.brk_start.max(vaddr + size);
+            let (r, w, x) = page_access(ph.is_read(), ph.is_write(), ph.is_executable());
             let mem_region = MemoryRegion {
-                paddr: program as usize,
-                vaddr: Some(ph.vm_range().start - page_offset),
+                kaddr: program as usize,
+                vaddr: Some(vaddr),
                 size,
-                r: ph.is_read(),
-                w: ph.is_write(),
-                x: ph.is_executable(),
+                order: frame::order_for(size),
+                r,
+                w,
+                x,
             };
             task.memory.push(mem_region);
         }
-        let arch_tm = unsafe { arch_task_manager!() };
+        task.brk = task.brk_start;
         for region in task.memory.iter() {
-            if let Some(vaddr) = region.vaddr {
-                for i in (0..region.size).step_by(PAGE_SIZE) {
-                    arch_tm.map(
-                        id,
-                        region.paddr + i,
-                        vaddr + i,
-                        region.r,
-                        region.w,
-                        region.x,
-                    )?;
-                }
-            }
+            region.map(id)?;
         }
+        let arch_tm = unsafe { arch_task_manager!() };
         arch_tm.init_user_entry(id, elf_exe.entry as usize)?;
         Ok(())
     }
+
+    // Moves the end of the heap to `addr` and returns the new end. Like
+    // Linux's brk, a request that cannot be met leaves the heap alone and
+    // returns the current end, so 0 queries it. The heap ends below
+    // MMAP_BASE.
+    pub fn brk(&mut self, id: TaskId, addr: usize) -> Result<usize, TaskError> {
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        if addr < task.brk_start || addr > MMAP_BASE.min(arch::user_end()) {
+            return Ok(task.brk);
+        }
+        let old_end = page_round_up(task.brk);
+        let new_end = page_round_up(addr);
+        let result = if new_end > old_end {
+            if task.is_free(old_end, new_end) {
+                task.map_anonymous(old_end, new_end - old_end, true, true, false)
+            } else {
+                Err(TaskError::OutOfMemory)
+            }
+        } else {
+            task.unmap(new_end, old_end - new_end)
+        };
+        if result.is_ok() {
+            task.brk = addr;
+        }
+        Ok(task.brk)
+    }
+
+    // Maps zeroed memory and returns its address. `hint` is used if the range
+    // there is free.
+    pub fn mmap(
+        &mut self,
+        id: TaskId,
+        hint: usize,
+        size: usize,
+        r: bool,
+        w: bool,
+        x: bool,
+    ) -> Result<usize, TaskError> {
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        if size > MMAP_TOP - MMAP_BASE {
+            return Err(TaskError::OutOfMemory);
+        }
+        let size = page_round_up(size);
+        let vaddr =
+            if hint != 0 && user_range(hint, size).is_ok() && task.is_free(hint, hint + size) {
+                hint
+            } else {
+                task.find_free(size)?
+            };
+        task.map_anonymous(vaddr, size, r, w, x)?;
+        Ok(vaddr)
+    }
+
+    // Like mmap, but at exactly `vaddr`, replacing whatever is mapped there.
+    pub fn mmap_fixed(
+        &mut self,
+        id: TaskId,
+        vaddr: usize,
+        size: usize,
+        r: bool,
+        w: bool,
+        x: bool,
+    ) -> Result<usize, TaskError> {
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        let size = user_range(vaddr, size)?;
+        task.unmap(vaddr, size)?;
+        task.map_anonymous(vaddr, size, r, w, x)?;
+        Ok(vaddr)
+    }
+
+    pub fn munmap(&mut self, id: TaskId, vaddr: usize, size: usize) -> Result<(), TaskError> {
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        let size = user_range(vaddr, size)?;
+        task.unmap(vaddr, size)
+    }
+
+    pub fn mprotect(
+        &mut self,
+        id: TaskId,
+        vaddr: usize,
+        size: usize,
+        r: bool,
+        w: bool,
+        x: bool,
+    ) -> Result<(), TaskError> {
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        let size = user_range(vaddr, size)?;
+        task.protect(vaddr, size, r, w, x)
+    }
+
+    // Copies `data` to `vaddr` in the task's memory, which must be mapped
+    // writable.
+    pub fn copy_to_user(&mut self, id: TaskId, vaddr: usize, data: &[u8]) -> Result<(), TaskError> {
+        let task = self.tasks.get(&id).ok_or(TaskError::TaskNotFound(id))?;
+        task.user_memory(vaddr, data.len(), true, |kaddr, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), kaddr as *mut u8, len);
+        })
+    }
+
+    // Fills `data` from `vaddr` in the task's memory, which must be mapped
+    // readable.
+    pub fn copy_from_user(
+        &self,
+        id: TaskId,
+        vaddr: usize,
+        data: &mut [u8],
+    ) -> Result<(), TaskError> {
+        let task = self.tasks.get(&id).ok_or(TaskError::TaskNotFound(id))?;
+        task.user_memory(vaddr, data.len(), false, |kaddr, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(kaddr as *const u8, data[offset..].as_mut_ptr(), len);
+        })
+    }
+
+    // Starts (or with None, stops) a timer that kills the task after `delay`.
+    // Returns the time that was left on the previous alarm.
+    pub fn set_alarm(
+        &mut self,
+        id: TaskId,
+        delay: Option<Duration>,
+    ) -> Result<Option<Duration>, TaskError> {
+        let task = self.tasks.get_mut(&id).ok_or(TaskError::TaskNotFound(id))?;
+        let left = task.alarm.take().and_then(|alarm| {
+            let left = timer::remaining(alarm);
+            timer::cancel_timer(alarm);
+            left
+        });
+        task.alarm = delay.map(|delay| {
+            timer::add_timer(delay, move || unsafe {
+                if let Some(task) = TASK_MANAGER.tasks.get_mut(&id) {
+                    task.alarm = None;
+                    println!("task {}.{} killed: alarm clock", task.name, id);
+                }
+                TASK_MANAGER.kill_task(id);
+            })
+        });
+        Ok(left)
+    }
+
+    pub fn alarm(&self, id: TaskId) -> Result<Option<Duration>, TaskError> {
+        let task = self.tasks.get(&id).ok_or(TaskError::TaskNotFound(id))?;
+        Ok(task.alarm.and_then(timer::remaining))
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, TaskError> {
+    let root_dir = unsafe { fat32::FILE_SYSTEM.root_dir() };
+    let mut file = root_dir
+        .open_file(path)
+        .map_err(|e| TaskError::DiskError(e))?;
+    let file_size = file
+        .seek(SeekFrom::End(0))
+        .map_err(|e| TaskError::DiskError(e))? as usize;
+    file.seek(SeekFrom::Start(0))
+        .map_err(|e| TaskError::DiskError(e))?;
+    let mut buf: Vec<u8> = vec![0; file_size];
+    file.read(&mut buf).map_err(|e| TaskError::DiskError(e))?;
+    Ok(buf)
+}
+
+// Blocks the running task for at least `duration`. Before there are tasks to
+// switch to, it busy-waits instead.
+pub fn sleep(duration: Duration) {
+    let task_manager = match unsafe { Lazy::get(&TASK_MANAGER) } {
+        Some(task_manager) if task_manager.cpu().online => unsafe { &mut TASK_MANAGER },
+        _ => {
+            time::delay(duration);
+            return;
+        }
+    };
+    let id = task_manager.current();
+    timer::add_timer(duration, move || unsafe { TASK_MANAGER.wake_task(id) });
+    task_manager.block_current();
+    unsafe {
+        task_manager.schedule();
+    }
+}
+
+// Called on a kernel page fault. Panics if `addr` hit the guard page of a task's
+// kernel stack.
+pub fn check_stack_overflow(addr: usize) {
+    let task_manager = unsafe { &TASK_MANAGER };
+    if let Some(id) = task_manager.stack_overflow_owner(addr) {
+        let task = task_manager.tasks.get(&id).unwrap();
+        panic!(
+            "stack overflow in task {}.{} (fault address: {:#x})",
+            task.name, id, addr
+        );
+    }
+}
+
+// Called when the running task faults in user mode. Reports `reason`, kills
+// the task and switches away from it for good.
+pub unsafe fn kill_current(reason: core::fmt::Arguments) -> ! {
+    let id = TASK_MANAGER.current();
+    let task = TASK_MANAGER.tasks.get(&id).unwrap();
+    println!("task {}.{} killed: {}", task.name, id, reason);
+    TASK_MANAGER.kill_task(id);
+    loop {
+        TASK_MANAGER.schedule();
+    }
+}
+
+// Never returns to a task killed by an interrupt, e.g. an alarm, since it
+// entered the kernel.
+pub unsafe fn exit_if_killed() {
+    if TASK_MANAGER.tasks[&TASK_MANAGER.current()].state == TaskState::Killed {
+        loop {
+            TASK_MANAGER.schedule();
+        }
+    }
 }
 
 pub unsafe fn user_entry() -> ! {
-    let task = TASK_MANAGER.tasks.get(&TASK_MANAGER.running).unwrap();
+    exit_if_killed();
+    TASK_MANAGER.preempt();
+    TASK_MANAGER.enter_user();
+    let task = TASK_MANAGER.tasks.get(&TASK_MANAGER.current()).unwrap();
     let arch_tm = arch_task_manager!();
     arch_tm.user_switch(task.id);
 }
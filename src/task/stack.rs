@@ -0,0 +1,97 @@
+use crate::arch::{self, PAGE_SIZE};
+use crate::error::VMError;
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::mem::size_of;
+use log::warn;
+
+// Fresh kernel stacks are filled with this pattern. The deepest word that no
+// longer holds it marks how much of the stack has ever been used.
+pub const STACK_PAINT: usize = 0x5a5a_5a5a_5a5a_5a5a;
+
+// Kernel stack with an unmapped guard page right below it.
+//
+// +-----------------+ <- top()
+// |      stack      |
+// +-----------------+ <- bottom()
+// |   guard page    |
+// +-----------------+ <- guard()
+pub struct KernelStack {
+    base: *mut u8,
+    size: usize,
+}
+
+impl KernelStack {
+    pub fn new(size: usize) -> Result<Self, VMError> {
+        assert!(size % PAGE_SIZE == 0);
+        let base = unsafe { alloc(Self::layout(size)) };
+        assert!(!base.is_null());
+
+        if let Err(e) = arch::protect_guard_page(base as usize) {
+            unsafe { dealloc(base, Self::layout(size)) };
+            return Err(e);
+        }
+        let stack = Self { base, size };
+        stack.paint();
+        Ok(stack)
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size + PAGE_SIZE, PAGE_SIZE).unwrap()
+    }
+
+    fn paint(&self) {
+        let words = unsafe {
+            core::slice::from_raw_parts_mut(
+                self.bottom() as *mut usize,
+                self.size / size_of::<usize>(),
+            )
+        };
+        words.fill(STACK_PAINT);
+    }
+
+    pub fn guard(&self) -> usize {
+        self.base as usize
+    }
+
+    pub fn bottom(&self) -> usize {
+        self.guard() + PAGE_SIZE
+    }
+
+    pub fn top(&self) -> usize {
+        self.bottom() + self.size
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_guard(&self, addr: usize) -> bool {
+        self.guard() <= addr && addr < self.bottom()
+    }
+
+    // The deepest the stack has ever grown, in bytes.
+    pub fn high_water_mark(&self) -> usize {
+        let words = unsafe {
+            core::slice::from_raw_parts(
+                self.bottom() as *const usize,
+                self.size / size_of::<usize>(),
+            )
+        };
+        let untouched = words.iter().take_while(|w| **w == STACK_PAINT).count();
+        self.size - untouched * size_of::<usize>()
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        // The guard page goes back to the heap, so it has to be accessible again.
+        // If it cannot be mapped, the memory is leaked instead.
+        if let Err(e) = arch::release_guard_page(self.guard()) {
+            warn!("stack: guard page {:#x}: {:?}", self.guard(), e);
+            return;
+        }
+        unsafe {
+            dealloc(self.base, Self::layout(self.size));
+        }
+    }
+}
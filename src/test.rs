@@ -17,9 +17,21 @@ where
 #[cfg(test)]
 pub fn test_runner(tests: &[&dyn Testable]) {
     println!("Running {} tests", tests.len());
+    #[cfg(allocator = "Debug")]
+    let mark = crate::allocator::debug::mark();
     for test in tests {
         test.run();
     }
+    crate::task::dump_cache_stats();
+    #[cfg(allocator = "Slab")]
+    crate::allocator::dump_stats();
+
+    #[cfg(allocator = "Debug")]
+    {
+        crate::allocator::debug::check_heap();
+        let leaks = crate::allocator::debug::dump_leaks(mark);
+        println!("{} allocations leaked by tests", leaks);
+    }
 
     println!("test result: ok.");
 
@@ -29,7 +41,9 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 #[cfg(test)]
 pub fn exit_success() -> ! {
     #[cfg(target_arch = "riscv64")]
-    let qemu_exit_handle = qemu_exit::RISCV64::new(0x100000);
+    let qemu_exit_handle = qemu_exit::RISCV64::new(crate::arch::phys_to_virt(
+        crate::arch::riscv64::address::SIFIVE_TEST,
+    ) as u64);
     #[cfg(target_arch = "aarch64")]
     let qemu_exit_handle = qemu_exit::AArch64::new();
 
@@ -39,7 +53,9 @@ pub fn exit_success() -> ! {
 #[cfg(test)]
 pub fn exit_failure() -> ! {
     #[cfg(target_arch = "riscv64")]
-    let qemu_exit_handle = qemu_exit::RISCV64::new(0x100000);
+    let qemu_exit_handle = qemu_exit::RISCV64::new(crate::arch::phys_to_virt(
+        crate::arch::riscv64::address::SIFIVE_TEST,
+    ) as u64);
     #[cfg(target_arch = "aarch64")]
     let qemu_exit_handle = qemu_exit::AArch64::new();
 
@@ -73,3 +89,1054 @@ fn test3() {
     assert_eq!(a[0], 2);
     assert_eq!(a[1], 3);
 }
+
+#[test_case]
+fn test_buddy_allocator() {
+    use crate::allocator::frame::*;
+    let before = frame_stats();
+    let a = alloc_frames_zeroed(0).unwrap();
+    let b = alloc_frames(3).unwrap();
+    assert_eq!(b % crate::arch::PAGE_SIZE, 0);
+    assert_eq!(frame_stats().free, before.free - 9);
+    free_frames(a, 0);
+    free_frames(b, 3);
+    let after = frame_stats();
+    assert_eq!(after.free, before.free);
+    assert_eq!(after.free_blocks, before.free_blocks);
+}
+
+#[test_case]
+fn test_split_frames() {
+    use crate::allocator::frame::*;
+    let before = frame_stats();
+    let a = alloc_frames(2).unwrap();
+    split_frames(a, 2);
+    split_frames(a, 1);
+    free_frames(a + crate::arch::PAGE_SIZE * 2, 1);
+    free_frames(a, 0);
+    free_frames(a + crate::arch::PAGE_SIZE, 0);
+    assert_eq!(frame_stats().free, before.free);
+    assert_eq!(frame_stats().free_blocks, before.free_blocks);
+}
+
+#[test_case]
+fn test_free_checks() {
+    use crate::allocator::frame::*;
+    use crate::arch::PAGE_SIZE;
+    let a = alloc_frames(1).unwrap();
+    assert!(frames_allocated(a, 1));
+    // neither the wrong order nor a frame inside the block may be freed
+    assert!(!frames_allocated(a, 0));
+    assert!(!frames_allocated(a + PAGE_SIZE, 0));
+    let b = alloc_frames(0).unwrap();
+    free_frames(b, 0);
+    // a double free, even after the block merged with its buddy
+    assert!(!frames_allocated(b, 0));
+    split_frames(a, 1);
+    assert!(frames_allocated(a + PAGE_SIZE, 0));
+    free_frames(a, 0);
+    free_frames(a + PAGE_SIZE, 0);
+    assert!(!frames_allocated(a, 0));
+    assert!(!frames_allocated(a, 1));
+}
+
+#[test_case]
+#[cfg(allocator = "Dlmalloc")]
+fn test_heap_stats() {
+    use crate::allocator::dlmalloc::heap_stats;
+    use alloc::boxed::Box;
+    let before = heap_stats();
+    let b = Box::new([0u8; 128]);
+    let during = heap_stats();
+    assert_eq!(during.current, before.current + 128);
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.peak >= during.current);
+    assert!(during.heap_used <= during.heap_size);
+    drop(b);
+    assert_eq!(heap_stats().current, before.current);
+}
+
+#[test_case]
+#[cfg(allocator = "Debug")]
+fn test_debug_allocator() {
+    use crate::allocator::debug::*;
+    use alloc::boxed::Box;
+    let mark = mark();
+    let block = Box::into_raw(Box::new([0u8; 24]));
+    let user = block as *mut u8;
+    assert_eq!(find_corruption(), None);
+    assert_eq!(dump_leaks(mark), 1);
+    // one byte past the end, then one before the start
+    unsafe { user.add(24).write(0) };
+    assert_eq!(find_corruption(), Some((user, 24)));
+    unsafe { user.add(24).write(REDZONE_BYTE) };
+    unsafe { user.sub(1).write(0) };
+    assert_eq!(find_corruption(), Some((user, -1)));
+    unsafe { user.sub(1).write(REDZONE_BYTE) };
+    assert_eq!(find_corruption(), None);
+
+    drop(unsafe { Box::from_raw(block) });
+    assert_eq!(dump_leaks(mark), 0);
+    // a use after free reads the poison
+    let stale = unsafe { (user.add(8) as *const u64).read_volatile() };
+    assert_eq!(stale, u64::from_ne_bytes([POISON_BYTE; 8]));
+}
+
+#[test_case]
+fn test_kmem_cache() {
+    use crate::allocator::slab::KmemCache;
+    struct Object {
+        magic: usize,
+        data: [u8; 100],
+    }
+    fn ctor(object: *mut Object) {
+        unsafe {
+            (*object).magic = 0xcafe;
+            (*object).data = [0; 100];
+        }
+    }
+    let mut cache = KmemCache::<Object>::new().with_ctor(ctor);
+    let a = cache.alloc().unwrap();
+    let b = cache.alloc().unwrap();
+    assert_ne!(a, b);
+    unsafe {
+        assert_eq!((*a).magic, 0xcafe);
+        assert_eq!((*b).data[99], 0);
+    }
+    assert_eq!(cache.stats().in_use, 2);
+    cache.free(a);
+    cache.free(b);
+    let stats = cache.stats();
+    assert_eq!(stats.in_use, 0);
+    assert_eq!(stats.allocations, 2);
+    assert_eq!(stats.slabs, 1);
+}
+
+#[test_case]
+#[cfg(allocator = "Slab")]
+fn test_slab_large() {
+    use crate::allocator::frame::MAX_ORDER;
+    use crate::arch::PAGE_SIZE;
+    use alloc::alloc::{alloc, dealloc, Layout};
+    let layout = Layout::from_size_align(PAGE_SIZE << MAX_ORDER, PAGE_SIZE).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    assert!(ptr.is_null());
+
+    let layout = Layout::from_size_align(4 * PAGE_SIZE, PAGE_SIZE).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    assert!(!ptr.is_null());
+    assert_eq!(ptr as usize % (4 * PAGE_SIZE), 0);
+    unsafe { dealloc(ptr, layout) };
+}
+
+#[test_case]
+fn test_log_ring() {
+    use crate::logger::ring::LogRing;
+    let mut ring = LogRing::<16>::new();
+    ring.push(b"abcd");
+    ring.push(b"efgh");
+    ring.push(b"ijkl");
+    // the first record was dropped to make room
+    assert_eq!(ring.first_seq(), 1);
+    assert_eq!(ring.next_seq(), 3);
+    let mut out = [0u8; 16];
+    let (len, next) = ring.read(0, &mut out);
+    assert_eq!(&out[..len], b"efghijkl");
+    assert_eq!(next, 3);
+    assert_eq!(ring.seq_fitting(4), 2);
+    let (len, _) = ring.read(ring.seq_fitting(4), &mut out);
+    assert_eq!(&out[..len], b"ijkl");
+}
+
+#[test_case]
+fn test_log_levels() {
+    use crate::logger::{self, LogError};
+    use log::{info, log_enabled, Level};
+    const TARGET: &str = "neverland::test::levels";
+    let default = logger::level();
+    logger::set_levels("neverland::test::levels=debug").unwrap();
+    assert!(log_enabled!(target: TARGET, Level::Debug));
+    assert!(log_enabled!(target: "neverland::test::levels::inner", Level::Debug));
+    assert_eq!(
+        logger::set_levels("neverland::test::levels=loud"),
+        Err(LogError::InvalidLevel)
+    );
+    assert!(log_enabled!(target: TARGET, Level::Debug));
+
+    let next = logger::with_ring(|ring| ring.next_seq());
+    info!(target: TARGET, "logged");
+    assert_eq!(logger::with_ring(|ring| ring.next_seq()), next + 1);
+    logger::set_levels("neverland::test::levels=off").unwrap();
+    assert!(!log_enabled!(target: TARGET, Level::Error));
+    info!(target: TARGET, "dropped");
+    assert_eq!(logger::with_ring(|ring| ring.next_seq()), next + 1);
+    assert_eq!(logger::level(), default);
+}
+
+#[test_case]
+fn test_symbolize() {
+    use crate::backtrace::{lookup, print_backtrace, symbolize};
+    use alloc::vec::Vec;
+    let start = print_backtrace as usize;
+    // a table in the format tools/ksyms writes, with one function before it
+    let mut table = Vec::new();
+    table.extend_from_slice(b"KSYM");
+    for word in [2u32, 16 + 2 * 24, 0] {
+        table.extend_from_slice(&word.to_le_bytes());
+    }
+    for (addr, size, name, len) in [(start - 0x40, 0x40, 0, 4), (start, 0x20, 4, 15)] {
+        table.extend_from_slice(&(addr as u64).to_le_bytes());
+        table.extend_from_slice(&(size as u64).to_le_bytes());
+        table.extend_from_slice(&(name as u32).to_le_bytes());
+        table.extend_from_slice(&(len as u32).to_le_bytes());
+    }
+    table.extend_from_slice(b"headprint_backtrace");
+    assert_eq!(lookup(&table, start + 4), Some(("print_backtrace", 4)));
+    assert_eq!(lookup(&table, start - 8), Some(("head", 0x38)));
+    assert_eq!(lookup(&table, start + 0x20), None);
+    assert_eq!(lookup(&table, start - 0x41), None);
+
+    // a cut or damaged table gives nothing
+    assert_eq!(lookup(&table[..40], start + 4), None);
+    table[16 + 24 + 16] = 0xff;
+    assert_eq!(lookup(&table, start + 4), None);
+
+    // images built by `cargo make` carry the kernel's own table
+    if let Some((name, offset)) = symbolize(start) {
+        assert!(name.ends_with("print_backtrace"));
+        assert_eq!(offset, 0);
+    }
+}
+
+#[test_case]
+fn test_date_time() {
+    use crate::time::DateTime;
+    let time = DateTime::from_unix(951782400);
+    assert_eq!(
+        time,
+        DateTime {
+            year: 2000,
+            month: 2,
+            day: 29,
+            hour: 0,
+            minute: 0,
+            second: 0
+        }
+    );
+    assert_eq!(time.to_unix(), 951782400);
+    assert_eq!(DateTime::from_unix(0).year, 1970);
+    assert_eq!(DateTime::from_unix(4354819199).to_unix(), 4354819199);
+}
+
+#[test_case]
+fn test_timer() {
+    use crate::timer;
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+    use core::time::Duration;
+    let fired = Rc::new(Cell::new(0));
+    let counter = fired.clone();
+    let once = timer::add_timer(Duration::from_millis(10), move || {
+        counter.set(counter.get() + 1)
+    });
+    let cancelled = timer::add_timer(Duration::from_millis(10), || panic!("cancelled timer ran"));
+    assert!(timer::cancel_timer(cancelled));
+    crate::time::delay(Duration::from_millis(50));
+    timer::poll();
+    assert_eq!(fired.get(), 1);
+    assert!(!timer::cancel_timer(once));
+}
+
+#[test_case]
+fn test_ram_disk() {
+    use crate::device::block::BlockDevice;
+    use crate::device::ramdisk::RamDisk;
+    use crate::error::BlockError;
+    let mut disk = RamDisk::from_image(b"abc");
+    assert_eq!(disk.blocks(), 1);
+    let mut block = [0xffu8; 512];
+    disk.read_blocks(0, &mut block).unwrap();
+    assert_eq!(&block[..4], b"abc\0");
+    block[0] = b'x';
+    disk.write_blocks(0, &block).unwrap();
+    disk.read_blocks(0, &mut block).unwrap();
+    assert_eq!(&block[..3], b"xbc");
+    assert_eq!(disk.write_blocks(1, &block), Err(BlockError::OutOfRange));
+    assert_eq!(
+        disk.read_blocks(0, &mut block[..100]),
+        Err(BlockError::Misaligned)
+    );
+}
+
+#[test_case]
+fn test_cpio_entries() {
+    use crate::fs::initrd::cpio_entries;
+    use alloc::format;
+    use alloc::vec::Vec;
+    let mut archive = Vec::new();
+    for (name, mode, data) in [
+        (".", 0o040755, &b""[..]),
+        ("./bin", 0o040755, &b""[..]),
+        ("./bin/hello", 0o100644, &b"hello"[..]),
+        ("TRAILER!!!", 0, &b""[..]),
+    ] {
+        let zeros = "0".repeat(32);
+        let header = format!(
+            "070701{:08x}{:08x}{}{:08x}{}{:08x}{:08x}",
+            0,
+            mode,
+            zeros,
+            data.len(),
+            zeros,
+            name.len() + 1,
+            0
+        );
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize((archive.len() + 3) & !3, 0);
+        archive.extend_from_slice(data);
+        archive.resize((archive.len() + 3) & !3, 0);
+    }
+    let entries = cpio_entries(&archive).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].name, "bin");
+    assert_eq!(entries[1].name, "bin/hello");
+    assert_eq!(entries[1].mode, 0o100644);
+    assert_eq!(entries[1].data, b"hello");
+    assert!(cpio_entries(&archive[..200]).is_err());
+}
+
+// Where `vaddr` is mapped in the address space of task `id`.
+#[cfg(test)]
+fn translate(id: crate::task::TaskId, vaddr: usize) -> Option<usize> {
+    use crate::task::ArchTaskManager;
+    #[cfg(target_arch = "riscv64")]
+    return unsafe { crate::arch::riscv64::task::ARCH_TASK_MANAGER.translate(id, vaddr) };
+    #[cfg(target_arch = "aarch64")]
+    return unsafe { crate::arch::aarch64::task::ARCH_TASK_MANAGER.translate(id, vaddr) };
+    #[cfg(target_arch = "x86_64")]
+    return None;
+}
+
+#[test_case]
+fn test_mmap_ranges() {
+    use crate::arch::{user_end, PAGE_SIZE};
+    use crate::syscall::{syscall, EINVAL, SYS_MUNMAP};
+    use crate::task::{MMAP_BASE, MMAP_TOP, TASK_MANAGER};
+    let task_manager = unsafe { &mut TASK_MANAGER };
+    let id = task_manager.create_task("mmap", 0).unwrap();
+    assert!(task_manager
+        .mmap_fixed(id, 0, PAGE_SIZE, true, true, false)
+        .is_err());
+    assert!(task_manager
+        .mmap_fixed(id, user_end(), PAGE_SIZE, true, true, false)
+        .is_err());
+    assert!(task_manager.munmap(id, MMAP_BASE + 1, PAGE_SIZE).is_err());
+    assert!(task_manager.munmap(id, MMAP_BASE, usize::MAX).is_err());
+    assert!(task_manager
+        .mprotect(id, !(PAGE_SIZE - 1), 2 * PAGE_SIZE, true, false, false)
+        .is_err());
+    assert!(task_manager
+        .mmap(id, 0, usize::MAX, true, true, false)
+        .is_err());
+
+    let addr = task_manager
+        .mmap(id, 0, 3 * PAGE_SIZE, true, true, false)
+        .unwrap();
+    assert!(MMAP_BASE <= addr && addr + 3 * PAGE_SIZE <= MMAP_TOP);
+    task_manager.copy_to_user(id, addr, b"abc").unwrap();
+    task_manager
+        .munmap(id, addr + PAGE_SIZE, PAGE_SIZE)
+        .unwrap();
+    assert!(translate(id, addr + PAGE_SIZE).is_none());
+    assert!(translate(id, addr + 2 * PAGE_SIZE).is_some());
+    assert!(task_manager
+        .copy_to_user(id, addr + PAGE_SIZE, b"abc")
+        .is_err());
+    assert_eq!(task_manager.usage(id).unwrap().memory, 2);
+    task_manager.remove_task(id).unwrap();
+
+    // an empty range is an error, as on Linux
+    let args = [MMAP_BASE, 0, 0, 0, 0, 0];
+    assert_eq!(syscall(SYS_MUNMAP, args), -EINVAL);
+}
+
+#[test_case]
+fn test_prot_none() {
+    use crate::arch::PAGE_SIZE;
+    use crate::task::{page_access, TASK_MANAGER};
+    assert_eq!(page_access(false, true, false), (true, true, false));
+    assert_eq!(page_access(false, false, true), (true, false, true));
+    let task_manager = unsafe { &mut TASK_MANAGER };
+    let id = task_manager.create_task("prot", 0).unwrap();
+    let (r, w, x) = page_access(false, false, false);
+    let addr = task_manager.mmap(id, 0, PAGE_SIZE, r, w, x).unwrap();
+    // kept track of, but not mapped
+    assert_eq!(task_manager.usage(id).unwrap().memory, 1);
+    assert!(translate(id, addr).is_none());
+    let mut buf = [0u8; 4];
+    assert!(task_manager.copy_from_user(id, addr, &mut buf).is_err());
+
+    task_manager
+        .mprotect(id, addr, PAGE_SIZE, true, true, false)
+        .unwrap();
+    assert!(translate(id, addr).is_some());
+    task_manager.copy_to_user(id, addr, b"abcd").unwrap();
+    task_manager.mprotect(id, addr, PAGE_SIZE, r, w, x).unwrap();
+    assert!(translate(id, addr).is_none());
+    task_manager
+        .mprotect(id, addr, PAGE_SIZE, true, false, false)
+        .unwrap();
+    task_manager.copy_from_user(id, addr, &mut buf).unwrap();
+    assert_eq!(&buf, b"abcd");
+    task_manager.remove_task(id).unwrap();
+}
+
+#[test_case]
+fn test_brk() {
+    use crate::arch::{user_start, PAGE_SIZE};
+    use crate::task::{MMAP_BASE, TASK_MANAGER};
+    let task_manager = unsafe { &mut TASK_MANAGER };
+    let id = task_manager.create_task("brk", 0).unwrap();
+    let start = task_manager.brk(id, 0).unwrap();
+    assert_eq!(start, user_start());
+    let end = start + 2 * PAGE_SIZE + 1;
+    assert_eq!(task_manager.brk(id, end).unwrap(), end);
+    assert_eq!(task_manager.usage(id).unwrap().memory, 3);
+    task_manager
+        .copy_to_user(id, start + 2 * PAGE_SIZE, b"x")
+        .unwrap();
+
+    // shrinking frees the pages above the new end
+    let end = start + PAGE_SIZE;
+    assert_eq!(task_manager.brk(id, end).unwrap(), end);
+    assert_eq!(task_manager.usage(id).unwrap().memory, 1);
+    assert!(translate(id, end).is_none());
+    assert!(task_manager.copy_to_user(id, end, b"x").is_err());
+
+    // the heap stays below the mmap area
+    assert_eq!(task_manager.brk(id, MMAP_BASE + PAGE_SIZE).unwrap(), end);
+    assert_eq!(task_manager.brk(id, usize::MAX).unwrap(), end);
+    task_manager.remove_task(id).unwrap();
+}
+
+#[test_case]
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+fn test_asid_rollover() {
+    use crate::arch::asid::AsidAllocator;
+    let mut asids = AsidAllocator::new();
+    asids.set_bits(3);
+    assert!(asids.is_supported());
+    let (running, rollover) = asids.alloc();
+    assert!(!rollover);
+    asids.set_active(1, running);
+    let (idle, _) = asids.alloc();
+    // the rest of the first generation
+    for _ in 0..5 {
+        assert!(!asids.alloc().1);
+    }
+
+    let (asid, rollover) = asids.alloc();
+    assert!(rollover);
+    assert_eq!(asids.rollovers(), 1);
+    assert!(!asids.is_current(&idle));
+    // core 1 still runs with its ASID, so it is neither stale nor handed out
+    assert!(asids.is_current(&running));
+    assert_ne!(asid.id(), running.id());
+    for _ in 0..5 {
+        let (asid, rollover) = asids.alloc();
+        assert!(!rollover);
+        assert_ne!(asid.id(), running.id());
+    }
+    assert!(asids.alloc().1);
+}
+
+#[test_case]
+fn test_cpu_accounting() {
+    use crate::task::{ResourceLimits, TASK_MANAGER};
+    use core::time::Duration;
+    let task_manager = unsafe { &mut TASK_MANAGER };
+    let id = task_manager.current();
+    let before = task_manager.usage(id).unwrap();
+    task_manager.enter_user();
+    crate::time::delay(Duration::from_millis(20));
+    task_manager.enter_kernel();
+    crate::time::delay(Duration::from_millis(20));
+    task_manager.enter_kernel();
+    let after = task_manager.usage(id).unwrap();
+    assert!(after.user_time >= before.user_time + 20_000);
+    assert!(after.system_time >= before.system_time + 20_000);
+
+    // a task over its limit is rescheduled, even with nothing else to run
+    let limits = task_manager.limits(id).unwrap();
+    task_manager
+        .set_limits(
+            id,
+            ResourceLimits {
+                cpu_time: Some(1),
+                ..limits
+            },
+        )
+        .unwrap();
+    task_manager.tick();
+    assert!(task_manager.need_resched());
+    task_manager.set_limits(id, limits).unwrap();
+
+    // the time CSR counts the CLINT's mtime
+    #[cfg(target_arch = "riscv64")]
+    {
+        let count = crate::arch::timer_count();
+        let mtime = crate::time::clocksource().read();
+        assert!(count <= mtime && mtime - count < crate::arch::timer_frequency() / 100);
+    }
+}
+
+#[test_case]
+fn test_resource_limits() {
+    use crate::arch::PAGE_SIZE;
+    use crate::error::TaskError;
+    use crate::task::{Resource, ResourceLimits, TASK_MANAGER};
+    let task_manager = unsafe { &mut TASK_MANAGER };
+    let parent = task_manager.current();
+    let parent_limits = task_manager.limits(parent).unwrap();
+    task_manager
+        .set_limits(
+            parent,
+            ResourceLimits {
+                children: Some(1),
+                ..parent_limits
+            },
+        )
+        .unwrap();
+    let id = task_manager.create_task("limits", 0).unwrap();
+    assert!(matches!(
+        task_manager.create_task("limits", 0),
+        Err(TaskError::LimitExceeded(Resource::Children))
+    ));
+    task_manager.set_limits(parent, parent_limits).unwrap();
+
+    task_manager
+        .set_limits(
+            id,
+            ResourceLimits {
+                memory: Some(2),
+                open_files: Some(1),
+                ..ResourceLimits::default()
+            },
+        )
+        .unwrap();
+    assert!(matches!(
+        task_manager.mmap(id, 0, 3 * PAGE_SIZE, true, true, false),
+        Err(TaskError::LimitExceeded(Resource::Memory))
+    ));
+    task_manager
+        .mmap(id, 0, 2 * PAGE_SIZE, true, true, false)
+        .unwrap();
+    assert_eq!(task_manager.usage(id).unwrap().memory, 2);
+
+    task_manager.open_file(id).unwrap();
+    assert!(matches!(
+        task_manager.open_file(id),
+        Err(TaskError::LimitExceeded(Resource::OpenFiles))
+    ));
+    // exec reads the program through a file of its own
+    assert!(matches!(
+        task_manager.exec(id, "hello"),
+        Err(TaskError::LimitExceeded(Resource::OpenFiles))
+    ));
+    task_manager.close_file(id).unwrap();
+    task_manager.open_file(id).unwrap();
+    task_manager.close_file(id).unwrap();
+    assert_eq!(task_manager.usage(id).unwrap().open_files, 0);
+    task_manager.remove_task(id).unwrap();
+    assert!(matches!(
+        task_manager.remove_task(id),
+        Err(TaskError::TaskNotFound(_))
+    ));
+    assert!(matches!(
+        task_manager.remove_task(parent),
+        Err(TaskError::TaskRunning(_))
+    ));
+}
+
+#[test_case]
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+fn test_stack_usage() {
+    use crate::arch::PAGE_SIZE;
+    use crate::task::stack::{KernelStack, STACK_PAINT};
+    use crate::task::TASK_MANAGER;
+    let stack = KernelStack::new(2 * PAGE_SIZE).unwrap();
+    assert_eq!(stack.high_water_mark(), 0);
+    assert!(stack.is_guard(stack.guard()));
+    assert!(!stack.is_guard(stack.bottom()));
+
+    let top = stack.top() as *mut usize;
+    unsafe { top.sub(4).write(!STACK_PAINT) };
+    assert_eq!(stack.high_water_mark(), 32);
+    unsafe { top.sub(PAGE_SIZE / 8 + 1).write(!STACK_PAINT) };
+    assert_eq!(stack.high_water_mark(), PAGE_SIZE + 8);
+    drop(stack);
+
+    let task_manager = unsafe { &mut TASK_MANAGER };
+    let id = task_manager.create_task("stack", 0).unwrap();
+    let mark = task_manager.stack_high_water_mark(id).unwrap();
+    assert!(mark < PAGE_SIZE);
+    task_manager.dump_stack_usage();
+    task_manager.remove_task(id).unwrap();
+    assert!(task_manager.stack_high_water_mark(id).is_err());
+}
+
+#[test_case]
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+fn test_address_space() {
+    use crate::allocator::frame::*;
+    #[cfg(target_arch = "aarch64")]
+    use crate::arch::aarch64::vm::AddressSpace;
+    #[cfg(target_arch = "riscv64")]
+    use crate::arch::riscv64::vm::AddressSpace;
+    use crate::arch::{user_start, virt_to_phys, PAGE_SIZE};
+    use crate::error::VMError;
+    let before = frame_stats();
+    let frames = alloc_frames_zeroed(1).unwrap();
+    let paddr = virt_to_phys(frames);
+    let vaddr = user_start();
+    let mut space = AddressSpace::new();
+    space
+        .map_range(paddr, vaddr, 2 * PAGE_SIZE, true, true, false, true)
+        .unwrap();
+    assert_eq!(space.translate(vaddr + 8), Some(paddr + 8));
+    assert_eq!(space.translate(vaddr + PAGE_SIZE), Some(paddr + PAGE_SIZE));
+    assert_eq!(space.permissions(vaddr), Some((true, true, false)));
+    assert_eq!(
+        space.map_range(paddr, vaddr + 8, PAGE_SIZE, true, false, false, true),
+        Err(VMError::Misaligned)
+    );
+
+    space
+        .protect_range(vaddr, PAGE_SIZE, true, false, true, true)
+        .unwrap();
+    assert_eq!(space.permissions(vaddr), Some((true, false, true)));
+    assert_eq!(
+        space.permissions(vaddr + PAGE_SIZE),
+        Some((true, true, false))
+    );
+    space.unmap_range(vaddr + PAGE_SIZE, PAGE_SIZE).unwrap();
+    assert!(space.translate(vaddr + PAGE_SIZE).is_none());
+    assert_eq!(space.translate(vaddr), Some(paddr));
+    assert_eq!(
+        space.protect_range(vaddr + PAGE_SIZE, PAGE_SIZE, true, false, false, true),
+        Err(VMError::NotFound)
+    );
+
+    // a gigabyte further, under tables of its own
+    let far = vaddr + (1 << 30);
+    space
+        .map_range(paddr, far, PAGE_SIZE, true, false, false, true)
+        .unwrap();
+    assert_eq!(space.translate(far), Some(paddr));
+    // dropping the address space frees its tables, not the mapped frames
+    drop(space);
+    assert!(frames_allocated(frames, 1));
+    free_frames(frames, 1);
+    assert_eq!(frame_stats().free, before.free);
+}
+
+#[test_case]
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+fn test_asid_tagging() {
+    #[cfg(target_arch = "aarch64")]
+    use crate::arch::aarch64::vm::{AddressSpace, VM_MANAGER};
+    use crate::arch::asid::{ASID_ALLOCATOR, KERNEL_ASID};
+    #[cfg(target_arch = "riscv64")]
+    use crate::arch::riscv64::vm::{AddressSpace, VM_MANAGER};
+    // where satp and TTBR0_EL1 keep the ASID
+    #[cfg(target_arch = "aarch64")]
+    let asid_of = |root: usize| root >> 48;
+    #[cfg(target_arch = "riscv64")]
+    let asid_of = |satp: usize| (satp >> 44) & 0xffff;
+    assert_eq!(
+        unsafe { VM_MANAGER.kernel_space() }.asid(),
+        Some(KERNEL_ASID)
+    );
+    let mut a = AddressSpace::new();
+    let mut b = AddressSpace::new();
+    assert!(a.asid().is_none());
+    let root_a = a.activate();
+    let root_b = b.activate();
+    if unsafe { ASID_ALLOCATOR.is_supported() } {
+        let asid_a = a.asid().unwrap();
+        let asid_b = b.asid().unwrap();
+        assert_ne!(asid_a, KERNEL_ASID);
+        assert_ne!(asid_a, asid_b);
+        assert_eq!(asid_of(root_a), asid_a);
+        assert_eq!(asid_of(root_b), asid_b);
+        // the ASID is kept until the next rollover
+        assert_eq!(a.activate(), root_a);
+    } else {
+        assert_eq!(asid_of(root_a), KERNEL_ASID);
+    }
+}
+
+#[test_case]
+#[cfg(target_arch = "riscv64")]
+fn test_higher_half() {
+    use crate::arch::riscv64::address::{_text_start, PHYS_OFFSET};
+    use crate::arch::riscv64::vm::VM_MANAGER;
+    use crate::arch::{phys_to_virt, user_end, virt_to_phys};
+    use crate::task::TASK_MANAGER;
+    let text = _text_start as usize;
+    assert!(text >= PHYS_OFFSET);
+    assert_eq!(phys_to_virt(virt_to_phys(text)), text);
+    assert!(user_end() <= PHYS_OFFSET);
+    // the kernel runs from the direct map only
+    let kernel = unsafe { VM_MANAGER.kernel_space() };
+    assert_eq!(kernel.translate(text), Some(virt_to_phys(text)));
+    assert!(kernel.translate(virt_to_phys(text)).is_none());
+
+    // and tasks see none of it at either address
+    let task_manager = unsafe { &mut TASK_MANAGER };
+    let id = task_manager.create_task("higher", 0).unwrap();
+    assert!(translate(id, text).is_none());
+    assert!(translate(id, virt_to_phys(text)).is_none());
+    task_manager.remove_task(id).unwrap();
+}
+
+#[test_case]
+#[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+fn test_kernel_permissions() {
+    #[cfg(target_arch = "aarch64")]
+    use crate::arch::aarch64::{address::*, vm::VM_MANAGER};
+    #[cfg(target_arch = "riscv64")]
+    use crate::arch::riscv64::{address::*, vm::VM_MANAGER};
+    let kernel = unsafe { VM_MANAGER.kernel_space() };
+    let rx = Some((true, false, true));
+    let r = Some((true, false, false));
+    let rw = Some((true, true, false));
+    assert_eq!(kernel.permissions(_text_start as usize), rx);
+    assert_eq!(kernel.permissions(_text_end as usize - 1), rx);
+    assert_eq!(kernel.permissions(_rodata_start as usize), r);
+    assert_eq!(kernel.permissions(_rodata_end as usize - 1), r);
+    assert_eq!(kernel.permissions(_data_start as usize), rw);
+    assert_eq!(kernel.permissions(_bss_start as usize), rw);
+    assert_eq!(kernel.permissions(_heap_start as usize), rw);
+    // MMIO is not executable either
+    #[cfg(target_arch = "riscv64")]
+    assert_eq!(
+        kernel.permissions(crate::arch::phys_to_virt(SIFIVE_TEST)),
+        rw
+    );
+    #[cfg(target_board = "raspi3b")]
+    assert_eq!(
+        kernel.permissions(crate::device::raspi3b::base::MMIO_BASE),
+        rw
+    );
+    // and none of it can be run from EL0
+    #[cfg(target_arch = "aarch64")]
+    for vaddr in [
+        _text_start as usize,
+        _rodata_start as usize,
+        _data_start as usize,
+        _bss_start as usize,
+        _heap_start as usize,
+    ] {
+        assert_eq!(kernel.user_executable(vaddr), Some(false));
+    }
+}
+
+#[test_case]
+#[cfg(target_arch = "riscv64")]
+fn test_paging_mode() {
+    use crate::allocator::frame::*;
+    use crate::arch::riscv64::csr::*;
+    use crate::arch::riscv64::vm::{AddressSpace, PagingMode, PAGING_MODE};
+    use crate::arch::{user_end, virt_to_phys, PAGE_SIZE};
+    assert_eq!(PagingMode::Sv39.levels(), 3);
+    assert_eq!(PagingMode::Sv48.levels(), 4);
+    assert_eq!(PagingMode::Sv39.user_end(), 1 << 38);
+    assert_eq!(PagingMode::Sv48.user_end(), 1 << 47);
+    let mode = unsafe { PAGING_MODE };
+    assert_eq!(Csr::Satp.read() >> 60, mode as usize);
+    assert_eq!(user_end(), mode.user_end());
+
+    // the last page of user space, whatever the number of levels
+    let frame = alloc_frames_zeroed(0).unwrap();
+    let paddr = virt_to_phys(frame);
+    let vaddr = user_end() - PAGE_SIZE;
+    let mut space = AddressSpace::new();
+    space
+        .map_range(paddr, vaddr, PAGE_SIZE, true, true, false, true)
+        .unwrap();
+    assert_eq!(space.translate(vaddr + 8), Some(paddr + 8));
+    assert!(space.translate(vaddr - PAGE_SIZE).is_none());
+    drop(space);
+    free_frames(frame, 0);
+}
+
+#[test_case]
+#[cfg(target_arch = "riscv64")]
+fn test_trap_decoding() {
+    use crate::arch::riscv64::trap::*;
+    assert_eq!(cause_name(LOAD_PAGE_FAULT), "load page fault");
+    assert_eq!(cause_name(ECALL_FROM_U), "environment call from U-mode");
+    assert_eq!(
+        cause_name(INTERRUPT | SUPERVISOR_EXTERNAL),
+        "supervisor external interrupt"
+    );
+    assert_eq!(cause_name(14), "unknown exception");
+    // c.li a0, 1 and addi a0, zero, 1
+    let code: [u16; 3] = [0x4505, 0x0513, 0x0010];
+    let read = |addr: usize| code.get(addr / 2).copied();
+    assert_eq!(instruction(0, read), Some(0x4505));
+    assert_eq!(instruction(2, read), Some(0x0010_0513));
+    // the second half of an instruction cannot be read
+    assert_eq!(instruction(4, |addr| (addr == 4).then(|| 0x0513)), None);
+}
+
+#[test_case]
+#[cfg(target_arch = "aarch64")]
+fn test_exception_decoding() {
+    use crate::arch::aarch64::exception::*;
+    assert_eq!(exception_type_name(EXCEPTION_IRQ), "IRQ");
+    assert_eq!(exception_class_name(EC_SVC_AARCH64), "SVC from AArch64");
+    assert_eq!(
+        exception_class_name(EC_DATA_ABORT_LOWER_EL),
+        "data abort from a lower EL"
+    );
+    assert_eq!(exception_class_name(0b111111), "unknown exception class");
+    // DFSC of a level 3 translation fault and a level 2 permission fault
+    assert_eq!(fault_status_name(0b000111), "translation fault");
+    assert_eq!(fault_status_name(0b001110), "permission fault");
+    // only ISS[5:0] is looked at
+    assert_eq!(fault_status_name(1 << 6 | 0b100001), "alignment fault");
+}
+
+#[test_case]
+fn test_preemption() {
+    use crate::task::{QUANTUM, TASK_MANAGER};
+    use crate::timer::TICK_HZ;
+    use core::time::Duration;
+    let task_manager = unsafe { &mut TASK_MANAGER };
+    // alone, the running task keeps the core
+    unsafe { task_manager.schedule() };
+    assert!(!task_manager.need_resched());
+    crate::time::delay(Duration::from_millis(QUANTUM * 1000 / TICK_HZ));
+    task_manager.tick();
+    assert!(!task_manager.need_resched());
+
+    let id = task_manager.create_task("preempt", 0).unwrap();
+    task_manager.ready_task(id);
+    task_manager.tick();
+    assert!(task_manager.need_resched());
+    task_manager.remove_task(id).unwrap();
+    unsafe { task_manager.schedule() };
+    assert!(!task_manager.need_resched());
+    // a new quantum has started
+    let id = task_manager.create_task("preempt", 0).unwrap();
+    task_manager.ready_task(id);
+    task_manager.tick();
+    assert!(!task_manager.need_resched());
+    task_manager.remove_task(id).unwrap();
+}
+
+#[test_case]
+#[cfg(target_arch = "aarch64")]
+fn test_user_task() {
+    use crate::arch::aarch64::address::_text_start;
+    use crate::arch::aarch64::exception::ExceptionFrame;
+    use crate::arch::aarch64::task::ARCH_TASK_MANAGER;
+    use crate::arch::aarch64::vm::USER_START;
+    use crate::arch::PAGE_SIZE;
+    use crate::task::{ArchTaskManager, TASK_MANAGER};
+    use core::mem::size_of;
+    let task_manager = unsafe { &mut TASK_MANAGER };
+    let arch_tm = unsafe { &mut ARCH_TASK_MANAGER };
+    let id = task_manager.create_task("user", 0).unwrap();
+    arch_tm.init_user_entry(id, USER_START).unwrap();
+    let frame = arch_tm.user_context(id).unwrap();
+    // at the top of the kernel stack, where exceptions from EL0 save to
+    assert_eq!(size_of::<ExceptionFrame>() % 16, 0);
+    assert_eq!(
+        frame as usize + size_of::<ExceptionFrame>(),
+        arch_tm.kernel_stack(id).unwrap().top()
+    );
+    unsafe {
+        assert_eq!((*frame).elr, USER_START);
+        // EL0t
+        assert_eq!((*frame).spsr, 0);
+        assert_eq!((*frame).x[0], 0);
+    }
+
+    // the kernel is mapped below user space, which starts out empty
+    let text = _text_start as usize;
+    assert_eq!(translate(id, text), Some(text));
+    assert!(translate(id, USER_START).is_none());
+    task_manager
+        .mmap_fixed(id, USER_START, PAGE_SIZE, true, true, false)
+        .unwrap();
+    assert!(translate(id, USER_START).is_some());
+    task_manager.copy_to_user(id, USER_START, b"svc").unwrap();
+    task_manager.remove_task(id).unwrap();
+}
+
+#[test_case]
+#[cfg(target_board = "raspi3b")]
+fn test_irq_manager() {
+    use crate::device::raspi3b::irq::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    unsafe fn handler() {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+    // a manager of its own; interrupts are still off while tests run
+    let mut irq_manager = IrqManager::new();
+    irq_manager.register(Irq::Gpu(GPU_EMMC), handler);
+    assert!(irq_manager.is_enabled(Irq::Gpu(GPU_EMMC)));
+    assert!(!irq_manager.is_enabled(Irq::Gpu(GPU_EMMC - 32)));
+    unsafe { irq_manager.dispatch(Irq::Gpu(GPU_EMMC)) };
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    // registering again replaces the handler
+    irq_manager.register(Irq::Gpu(GPU_EMMC), handler);
+    unsafe { irq_manager.dispatch(Irq::Gpu(GPU_EMMC)) };
+    assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+
+    // a source nobody handles is masked
+    irq_manager.enable(Irq::Arm(ARM_MAILBOX));
+    unsafe { irq_manager.dispatch(Irq::Arm(ARM_MAILBOX)) };
+    assert!(!irq_manager.is_enabled(Irq::Arm(ARM_MAILBOX)));
+    irq_manager.enable(Irq::Local(LOCAL_CNTV));
+    assert!(irq_manager.is_enabled(Irq::Local(LOCAL_CNTV)));
+    unsafe { irq_manager.dispatch(Irq::Local(LOCAL_CNTV)) };
+    assert!(!irq_manager.is_enabled(Irq::Local(LOCAL_CNTV)));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+
+    irq_manager.disable(Irq::Gpu(GPU_EMMC));
+    assert!(!irq_manager.is_enabled(Irq::Gpu(GPU_EMMC)));
+}
+
+#[test_case]
+#[cfg(target_board = "raspi3b")]
+fn test_sd_capacity() {
+    use crate::device::raspi3b::sd::SDCard;
+    // CMD9 responses without the CRC: a 1 GiB CSD 2.0 card, C_SIZE 2047, and
+    // a 512 MiB CSD 1.0 card, C_SIZE 2047, C_SIZE_MULT 7, READ_BL_LEN 9
+    assert_eq!(SDCard::csd_blocks([0, 2047 << 8, 0, 1 << 22]), 2 << 20);
+    assert_eq!(SDCard::csd_blocks([0, 0xffc0_0380, 0x901, 0]), 1 << 20);
+}
+
+#[test_case]
+fn test_block_devices() {
+    use crate::device::block::{BlockDevice, BlockDevices};
+    use crate::device::ramdisk::RamDisk;
+    use crate::error::BlockError;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    let disk = RamDisk::new(4);
+    assert_eq!(disk.check_range(0, 4 * 512), Ok(()));
+    assert_eq!(disk.check_range(4, 0), Ok(()));
+    assert_eq!(disk.check_range(3, 2 * 512), Err(BlockError::OutOfRange));
+    assert_eq!(
+        disk.check_range(usize::MAX, 512),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(disk.check_range(0, 100), Err(BlockError::Misaligned));
+
+    let mut devices = BlockDevices::new();
+    devices.register("ram1", Box::leak(Box::new(disk))).unwrap();
+    assert_eq!(
+        devices.register("ram1", Box::leak(Box::new(RamDisk::new(0)))),
+        Err(BlockError::AlreadyRegistered)
+    );
+    assert_eq!(devices.names().collect::<Vec<_>>(), ["ram1"]);
+    assert_eq!(devices.get("ram1").unwrap().blocks(), 4);
+    assert!(matches!(devices.get("ram2"), Err(BlockError::NotFound)));
+}
+
+#[test_case]
+fn test_fat_on_ram_disk() {
+    use crate::device::ramdisk::RamDisk;
+    use crate::fs::buffer::Buffer;
+    use crate::fs::fat32::Disk;
+    use fatfs::{Read, Write};
+    let mut disk = RamDisk::new(2048);
+    fatfs::format_volume(&mut Disk::new(&mut disk), fatfs::FormatVolumeOptions::new()).unwrap();
+    let fs =
+        fatfs::FileSystem::new(Buffer::new(Disk::new(&mut disk)), fatfs::FsOptions::new()).unwrap();
+    let mut file = fs.root_dir().create_file("hello.txt").unwrap();
+    file.write_all(b"hello").unwrap();
+    drop(file);
+    fs.unmount().unwrap();
+
+    // read back from what reached the disk
+    let fs =
+        fatfs::FileSystem::new(Buffer::new(Disk::new(&mut disk)), fatfs::FsOptions::new()).unwrap();
+    let mut file = fs.root_dir().open_file("hello.txt").unwrap();
+    let mut buf = [0u8; 8];
+    assert_eq!(file.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+}
+
+#[test_case]
+fn test_crash_record() {
+    use crate::crashdump::{self, CRASH_SECTORS};
+    use crate::device::block::BlockDevice;
+    use crate::device::ramdisk::{RamDisk, BLOCK_SIZE};
+    use crate::fs::fat32::Disk;
+    let mut disk = RamDisk::new(2048);
+    let start = 2048 - CRASH_SECTORS;
+    // a FAT volume that ends before the crash area
+    let mut boot = [0u8; BLOCK_SIZE];
+    boot[19..21].copy_from_slice(&(start as u16).to_le_bytes());
+    boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+    disk.write_blocks(0, &boot).unwrap();
+
+    assert!(crashdump::begin());
+    assert!(!crashdump::begin());
+    crashdump::capture(format_args!("crash {}", 42));
+    assert_eq!(crashdump::write_to(&mut disk), Ok(start));
+    let mut header = [0u8; BLOCK_SIZE];
+    disk.read_blocks(start, &mut header).unwrap();
+    assert_eq!(&header[..8], b"NVLCRASH");
+    assert_eq!(&header[8..12], &8u32.to_le_bytes());
+    assert_eq!(
+        crashdump::recover_from(&mut disk),
+        Ok(Some(b"crash 42".to_vec()))
+    );
+    // taken once
+    assert_eq!(crashdump::recover_from(&mut disk), Ok(None));
+
+    // a damaged record is thrown away
+    assert!(crashdump::begin());
+    crashdump::capture(format_args!("crash 43"));
+    crashdump::write_to(&mut disk).unwrap();
+    let mut text = [0u8; BLOCK_SIZE];
+    disk.read_blocks(start + 1, &mut text).unwrap();
+    text[0] ^= 1;
+    disk.write_blocks(start + 1, &text).unwrap();
+    assert!(crashdump::recover_from(&mut disk).is_err());
+    assert_eq!(crashdump::recover_from(&mut disk), Ok(None));
+
+    // nothing is written over a volume that covers the area
+    let mut disk = RamDisk::new(2048);
+    fatfs::format_volume(&mut Disk::new(&mut disk), fatfs::FormatVolumeOptions::new()).unwrap();
+    let mut before = [0u8; BLOCK_SIZE];
+    disk.read_blocks(start, &mut before).unwrap();
+    assert!(crashdump::begin());
+    crashdump::capture(format_args!("crash 44"));
+    assert!(crashdump::write_to(&mut disk).is_err());
+    let mut after = [0u8; BLOCK_SIZE];
+    disk.read_blocks(start, &mut after).unwrap();
+    assert_eq!(before, after);
+    assert!(crashdump::recover_from(&mut disk).is_err());
+}
+
+#[test_case]
+#[cfg(target_arch = "aarch64")]
+fn test_secondary_cores() {
+    use crate::arch::aarch64::smp::{online_cores, start_secondary_cores};
+    use crate::arch::MAX_CPUS;
+    assert_eq!(online_cores(), 1);
+    // they come up, then wait for the kernel lock, which the tests hold
+    unsafe { start_secondary_cores() };
+    assert_eq!(online_cores(), MAX_CPUS);
+}
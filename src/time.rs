@@ -0,0 +1,197 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use log::info;
+
+// A free-running counter the kernel keeps time with.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+    fn read(&self) -> u64;
+    // counts per second
+    fn frequency(&self) -> u64;
+}
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Wall-clock time at monotonic time zero, in nanoseconds since the Unix epoch.
+static BOOT_WALL_CLOCK: AtomicU64 = AtomicU64::new(0);
+static WALL_CLOCK_SET: AtomicBool = AtomicBool::new(false);
+
+#[cfg(target_arch = "riscv64")]
+static CLOCKSOURCE: crate::arch::riscv64::clint::ClintMtime =
+    crate::arch::riscv64::clint::ClintMtime;
+#[cfg(target_arch = "aarch64")]
+static CLOCKSOURCE: crate::arch::aarch64::arm::GenericTimer =
+    crate::arch::aarch64::arm::GenericTimer;
+
+pub fn clocksource() -> &'static dyn ClockSource {
+    #[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
+    return &CLOCKSOURCE;
+    #[cfg(target_arch = "x86_64")]
+    return unsafe { &*crate::arch::x86_64::tsc::TSC };
+}
+
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    (ticks as u128 * NANOS_PER_SEC as u128 / clocksource().frequency() as u128) as u64
+}
+
+pub fn nanos_to_ticks(nanos: u64) -> u64 {
+    (nanos as u128 * clocksource().frequency() as u128 / NANOS_PER_SEC as u128) as u64
+}
+
+// Time since the clocksource started counting, which is about when the
+// machine was reset.
+pub fn monotonic() -> Duration {
+    Duration::from_nanos(ticks_to_nanos(clocksource().read()))
+}
+
+// Time since the Unix epoch, if an RTC or someone else has set it.
+pub fn wall_clock() -> Option<Duration> {
+    if !WALL_CLOCK_SET.load(Ordering::Acquire) {
+        return None;
+    }
+    Some(Duration::from_nanos(BOOT_WALL_CLOCK.load(Ordering::Relaxed)) + monotonic())
+}
+
+pub fn set_wall_clock(now: Duration) {
+    let boot = now.saturating_sub(monotonic());
+    BOOT_WALL_CLOCK.store(boot.as_nanos() as u64, Ordering::Relaxed);
+    WALL_CLOCK_SET.store(true, Ordering::Release);
+}
+
+// Busy-waits for `duration`.
+pub fn delay(duration: Duration) {
+    let clock = clocksource();
+    let start = clock.read();
+    let ticks = nanos_to_ticks(duration.as_nanos() as u64);
+    while clock.read().wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+// Reads the board's RTC, if it has one.
+fn read_rtc() -> Option<Duration> {
+    #[cfg(all(target_arch = "riscv64", target_board = "virt"))]
+    let now = Some(crate::device::virt::rtc::read());
+    #[cfg(target_arch = "x86_64")]
+    let now = crate::device::pc::cmos::read();
+    #[cfg(not(any(
+        all(target_arch = "riscv64", target_board = "virt"),
+        target_arch = "x86_64"
+    )))]
+    let now = None;
+    now
+}
+
+// Seeds the wall clock from the RTC.
+pub fn init() {
+    info!(
+        "clocksource: {} at {} Hz",
+        clocksource().name(),
+        clocksource().frequency()
+    );
+    match read_rtc() {
+        Some(now) => {
+            set_wall_clock(now);
+            info!("wall clock: {}", DateTime::from_unix(now.as_secs()));
+        }
+        None => info!("wall clock: no RTC"),
+    }
+}
+
+// Calendar time in UTC.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / 86400) as i64 + 719468;
+        let seconds = (secs % 86400) as u32;
+        let era = days / 146097;
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+        let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+        let year = (year_of_era + era * 400) as u32 + (month <= 2) as u32;
+        Self {
+            year,
+            month,
+            day,
+            hour: seconds / 3600,
+            minute: seconds / 60 % 60,
+            second: seconds % 60,
+        }
+    }
+
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    pub fn to_unix(&self) -> u64 {
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Timestamps for files on the FAT volume. FAT dates start in 1980, which is
+// also used while the wall clock is not set.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct FatTimeProvider;
+
+impl FatTimeProvider {
+    pub const fn new() -> Self {
+        Self
+    }
+
+    fn now() -> (fatfs::Date, fatfs::Time) {
+        const FAT_EPOCH: u64 = 315532800;
+        const FAT_END: u64 = 4354819199;
+        let now = wall_clock().unwrap_or(Duration::ZERO);
+        let secs = now.as_secs().clamp(FAT_EPOCH, FAT_END);
+        let time = DateTime::from_unix(secs);
+        (
+            fatfs::Date::new(time.year as u16, time.month as u16, time.day as u16),
+            fatfs::Time::new(
+                time.hour as u16,
+                time.minute as u16,
+                time.second as u16,
+                now.subsec_millis() as u16,
+            ),
+        )
+    }
+}
+
+impl fatfs::TimeProvider for FatTimeProvider {
+    fn get_current_date(&self) -> fatfs::Date {
+        Self::now().0
+    }
+
+    fn get_current_date_time(&self) -> fatfs::DateTime {
+        let (date, time) = Self::now();
+        fatfs::DateTime::new(date, time)
+    }
+}
@@ -0,0 +1,161 @@
+use crate::lazy::Lazy;
+use crate::time;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+// Timers are kept in jiffies of 1 / TICK_HZ seconds, counted from the
+// clocksource. The timer interrupt fires once a jiffy; without one, timers run
+// whenever poll() is called.
+pub const TICK_HZ: u64 = 100;
+const WHEEL_SIZE: usize = 256;
+
+pub static mut TIMERS: Lazy<TimerWheel> =
+    Lazy::<TimerWheel, fn() -> TimerWheel>::new(|| TimerWheel::new());
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+struct Timer {
+    id: TimerId,
+    // jiffy the timer fires at
+    expires: u64,
+    // jiffies between runs of a periodic timer
+    period: Option<u64>,
+    callback: Box<dyn FnMut()>,
+}
+
+// Hashed timer wheel: a timer sits in the slot of the jiffy it expires at,
+// and a slot is looked at whenever that jiffy comes around.
+pub struct TimerWheel {
+    slots: Vec<Vec<Timer>>,
+    next_id: u64,
+    // last jiffy whose timers have run
+    processed: u64,
+    // the timer whose callback is running, and whether it was cancelled by it
+    running: Option<(TimerId, bool)>,
+}
+
+pub fn jiffies() -> u64 {
+    let clock = time::clocksource();
+    clock.read() / (clock.frequency() / TICK_HZ).max(1)
+}
+
+// Jiffies to wait so that at least `duration` passes.
+pub fn duration_to_jiffies(duration: Duration) -> u64 {
+    let jiffy = time::NANOS_PER_SEC / TICK_HZ;
+    let nanos = duration.as_nanos().min(u64::MAX as u128) as u64;
+    // the current jiffy is already partly over
+    nanos / jiffy + (nanos % jiffy != 0) as u64 + 1
+}
+
+impl TimerWheel {
+    pub fn new() -> Self {
+        Self {
+            slots: (0..WHEEL_SIZE).map(|_| Vec::new()).collect(),
+            next_id: 0,
+            processed: jiffies(),
+            running: None,
+        }
+    }
+
+    fn insert(&mut self, timer: Timer) {
+        self.slots[timer.expires as usize % WHEEL_SIZE].push(timer);
+    }
+
+    fn add(&mut self, delay: u64, period: Option<u64>, callback: Box<dyn FnMut()>) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        // timers due at or before `processed` would wait for the wheel to
+        // come around
+        self.insert(Timer {
+            id,
+            expires: jiffies().max(self.processed) + delay.max(1),
+            period,
+            callback,
+        });
+        id
+    }
+
+    fn find(&self, id: TimerId) -> Option<&Timer> {
+        self.slots.iter().flatten().find(|timer| timer.id == id)
+    }
+
+    fn cancel(&mut self, id: TimerId) -> bool {
+        if let Some((running, cancelled)) = self.running.as_mut() {
+            if *running == id {
+                *cancelled = true;
+                return true;
+            }
+        }
+        for slot in self.slots.iter_mut() {
+            if let Some(index) = slot.iter().position(|timer| timer.id == id) {
+                slot.swap_remove(index);
+                return true;
+            }
+        }
+        false
+    }
+
+    // Takes the timers that expired up to `now` off the wheel.
+    fn expired(&mut self, now: u64) -> Vec<Timer> {
+        let steps = (now.saturating_sub(self.processed) as usize).min(WHEEL_SIZE);
+        self.processed = self.processed.max(now);
+        let mut expired = Vec::new();
+        for step in 0..steps {
+            let slot = &mut self.slots[(now as usize - step) % WHEEL_SIZE];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].expires <= now {
+                    expired.push(slot.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        expired.sort_by_key(|timer| timer.expires);
+        expired
+    }
+}
+
+// Runs `callback` once, after `delay`. Callbacks run in interrupt context and
+// must not block.
+pub fn add_timer(delay: Duration, callback: impl FnMut() + 'static) -> TimerId {
+    unsafe { TIMERS.add(duration_to_jiffies(delay), None, Box::new(callback)) }
+}
+
+// Runs `callback` every `period` until the timer is cancelled.
+pub fn add_periodic(period: Duration, callback: impl FnMut() + 'static) -> TimerId {
+    let period = (duration_to_jiffies(period) - 1).max(1);
+    unsafe { TIMERS.add(period, Some(period), Box::new(callback)) }
+}
+
+// Returns whether the timer was still pending.
+pub fn cancel_timer(id: TimerId) -> bool {
+    unsafe { TIMERS.cancel(id) }
+}
+
+// Time until the timer fires next.
+pub fn remaining(id: TimerId) -> Option<Duration> {
+    let timer = unsafe { TIMERS.find(id)? };
+    let jiffies = timer.expires.saturating_sub(jiffies());
+    Some(Duration::from_nanos(
+        jiffies.saturating_mul(time::NANOS_PER_SEC / TICK_HZ),
+    ))
+}
+
+// Runs the callbacks of expired timers. Called from the timer interrupt and
+// from loops that wait without one.
+pub fn poll() {
+    let timers = unsafe { &mut TIMERS };
+    for mut timer in timers.expired(jiffies()) {
+        timers.running = Some((timer.id, false));
+        (timer.callback)();
+        let cancelled = matches!(timers.running.take(), Some((_, true)));
+        if let (Some(period), false) = (timer.period, cancelled) {
+            // a timer that fell behind skips the runs it missed
+            timer.expires = (timer.expires + period).max(timers.processed + 1);
+            timers.insert(timer);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+// Usage: ksyms <kernel.elf> [size file]
+//
+// Collects the function symbols of the kernel and writes them into the space
+// the kernel reserves between _ksyms_start and _ksyms_end (see
+// src/backtrace.rs for the format). The file is patched in place, so no
+// address in the image changes.
+//
+// The space the table needs, with some room to grow, is written to the size
+// file when the reserved space is too small or much too large. build.rs
+// reserves that much in the next build. A table that does not fit exits with
+// status 2, and the kernel has to be built again.
+
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::sym::STT_FUNC;
+use goblin::elf::Elf;
+use std::{env, fs, process};
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 16;
+const SYMBOL_SIZE: usize = 24;
+// the size file is rounded up to this
+const SIZE_STEP: usize = 0x10000;
+
+fn fail(message: &str) -> ! {
+    eprintln!("ksyms: {}", message);
+    process::exit(1);
+}
+
+fn main() {
+    let path = env::args()
+        .nth(1)
+        .unwrap_or_else(|| fail("usage: ksyms <kernel.elf> [size file]"));
+    let size_file = env::args().nth(2);
+    let mut image = fs::read(&path).unwrap_or_else(|e| fail(&e.to_string()));
+
+    let (mut symbols, start, end, offset) = {
+        let elf = Elf::parse(&image).unwrap_or_else(|e| fail(&e.to_string()));
+        let find = |name: &str| {
+            elf.syms
+                .iter()
+                .find(|sym| elf.strtab.get_at(sym.st_name) == Some(name))
+                .map(|sym| sym.st_value)
+                .unwrap_or_else(|| fail(&format!("{} not found", name)))
+        };
+        let start = find("_ksyms_start");
+        let end = find("_ksyms_end");
+        let offset = elf
+            .program_headers
+            .iter()
+            .find(|ph| {
+                ph.p_type == PT_LOAD && ph.p_vaddr <= start && end <= ph.p_vaddr + ph.p_filesz
+            })
+            .map(|ph| ph.p_offset + (start - ph.p_vaddr))
+            .unwrap_or_else(|| fail("the symbol table space is not in a loaded segment"));
+
+        let symbols: Vec<(u64, u64, String)> = elf
+            .syms
+            .iter()
+            .filter(|sym| sym.st_type() == STT_FUNC && sym.st_value != 0)
+            .filter_map(|sym| {
+                let name = elf.strtab.get_at(sym.st_name)?;
+                Some((
+                    sym.st_value,
+                    sym.st_size,
+                    format!("{:#}", rustc_demangle::demangle(name)),
+                ))
+            })
+            .collect();
+        (symbols, start, end, offset)
+    };
+    symbols.sort();
+    symbols.dedup_by_key(|sym| sym.0);
+
+    // header, symbols sorted by address, then the names
+    let strings = HEADER_SIZE + symbols.len() * SYMBOL_SIZE;
+    let mut table = Vec::new();
+    table.extend_from_slice(MAGIC);
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&(strings as u32).to_le_bytes());
+    table.extend_from_slice(&0u32.to_le_bytes());
+    let mut names = Vec::new();
+    for (addr, size, name) in symbols.iter() {
+        table.extend_from_slice(&addr.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+
+    let space = (end - start) as usize;
+    let wanted = (table.len() + table.len() / 4 + SIZE_STEP - 1) / SIZE_STEP * SIZE_STEP;
+    if let Some(size_file) = &size_file {
+        if table.len() > space || space > 2 * wanted {
+            fs::write(size_file, wanted.to_string()).unwrap_or_else(|e| fail(&e.to_string()));
+        }
+    }
+    if table.len() > space {
+        eprintln!(
+            "ksyms: the symbol table needs {:#x} bytes, but only {:#x} are reserved",
+            table.len(),
+            space
+        );
+        process::exit(2);
+    }
+    let offset = offset as usize;
+    image[offset..offset + table.len()].copy_from_slice(&table);
+    fs::write(&path, image).unwrap_or_else(|e| fail(&e.to_string()));
+    println!("ksyms: {} symbols, {:#x} bytes", symbols.len(), table.len());
+}